@@ -0,0 +1,23 @@
+// Builds `src/musttail.cpp` for the JIT, with the flags of the LLVM that
+// `llvm-sys` found.
+fn main() {
+    #[cfg(feature = "jit")]
+    {
+        use std::env;
+        use std::process::Command;
+
+        println!("cargo:rerun-if-changed=src/musttail.cpp");
+        let llvm_config = env::var("DEP_LLVM_13_CONFIG_PATH").unwrap();
+        let output = Command::new(&llvm_config)
+            .arg("--cxxflags")
+            .output()
+            .unwrap_or_else(|err| panic!("could not run `{}`: {}", llvm_config, err));
+        let mut build = cc::Build::new();
+        // LLVM's headers set off `-Wextra`, only `-Wall` is asked for
+        build.cpp(true).extra_warnings(false).file("src/musttail.cpp");
+        for flag in String::from_utf8(output.stdout).unwrap().split_whitespace() {
+            build.flag(flag);
+        }
+        build.compile("musttail");
+    }
+}
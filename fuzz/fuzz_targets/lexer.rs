@@ -0,0 +1,24 @@
+//! Any input either lexes to `Eof` or stops at an error, without panicking.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::token::Token;
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+    // with comments, which take the most care to get right
+    let mut lex = Lexer::with_trivia(source);
+    // every token but `Eof` consumes something
+    for _ in 0..=source.len() {
+        match lex.emit_token() {
+            Ok((Token::Eof, _)) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+    panic!("lexer is stuck: more tokens than bytes in the input");
+});
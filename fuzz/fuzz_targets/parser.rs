@@ -0,0 +1,26 @@
+//! Any input parses to items and errors, recovering after each error,
+//! without panicking or getting stuck.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::parser::Parser;
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    // every item or error consumes at least one token
+    let mut items = 0;
+    while par.emit_node().is_some() {
+        items += 1;
+        assert!(
+            items <= source.len(),
+            "parser is stuck: more items than bytes in the input"
+        );
+    }
+});
@@ -0,0 +1,61 @@
+//! Any input goes through parsing, linting, constant folding and type
+//! checking into bytecode without panicking. `CodeGen` checks the same rules
+//! and reports the same errors, but needs LLVM and a JIT for every item, so
+//! the bytecode compiler stands in for it here.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use llvm_tutorial::ast::{Function, Item};
+use llvm_tutorial::bytecode::Compiler;
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::lint::Linter;
+use llvm_tutorial::parser::Parser;
+use llvm_tutorial::vm::Vm;
+
+// so calls to `sqrt` and friends type check like they do in a session
+const PRELUDE: &str = include_str!("../../src/prelude.ks");
+
+// Imports read whatever file they name and library externs load whatever
+// library they name, neither of which a fuzzer should do.
+fn touches_system(item: &Item) -> bool {
+    match item {
+        Item::Import(_) => true,
+        Item::Function(Function {
+            prototype: Some(proto),
+            body: None,
+        }) => proto.library.is_some(),
+        _ => false,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+    let mut vm = Vm::default();
+    let mut lex = Lexer::new(PRELUDE);
+    let mut par = Parser::new(&mut lex);
+    Compiler::new(&mut par, &mut vm)
+        .checking_only()
+        .run_to_end("<prelude>", PRELUDE);
+
+    let mut linter = Linter::default();
+    linter.lint_file("<prelude>", PRELUDE);
+    linter.lint_file("<fuzz>", source);
+
+    let mut lex = Lexer::new(source);
+    let par = Parser::new(&mut lex);
+    let mut items =
+        par.filter(|result| !matches!(result, Ok((item, _, _)) if touches_system(item)));
+    let mut compiler = Compiler::new(&mut items, &mut vm).checking_only();
+    let mut compiled = 0;
+    while compiler.compile_item().is_some() {
+        compiled += 1;
+        assert!(
+            compiled <= source.len(),
+            "compiler is stuck: more items than bytes in the input"
+        );
+    }
+});
@@ -0,0 +1,21 @@
+#!/bin/sh
+# Runs a fuzz target with cargo-fuzz (nightly), seeded from the example
+# scripts, the prelude and the golden tests:
+#
+#   fuzz/run.sh parser [libFuzzer options]
+#
+# New inputs go to fuzz/corpus/<target>. The scripts at the top of the repo
+# aren't in a directory of their own, so they're linked into
+# fuzz/corpus/scripts rather than copied.
+set -e
+cd "$(dirname "$0")/.."
+target=${1:?usage: fuzz/run.sh <lexer|parser|pipeline> [libFuzzer options]}
+shift
+root=$(pwd)
+mkdir -p fuzz/corpus/"$target" fuzz/corpus/scripts
+for script in Fib Input Functional src/prelude.ks; do
+    ln -sf "$root/$script" fuzz/corpus/scripts/
+done
+cd fuzz
+exec cargo fuzz run "$target" "$root/fuzz/corpus/$target" \
+    "$root/fuzz/corpus/scripts" "$root/tests/golden" -- "$@"
@@ -1,24 +1,155 @@
+use crate::diagnostic::Span;
 use crate::operator::Operator;
+use crate::types::{IntType, Type};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr {
-    Number(usize),
-    Variable(Vec<u8>),
+    Number {
+        value: u64,
+        ty: Option<IntType>,
+        // the literal as written, suffix included, which `fmt` prints back
+        text: String,
+    },
+    Float(f64),
+    Variable(String),
     Binary {
         op: Operator,
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
     Call {
-        name: Vec<u8>,
+        name: String,
         args: Vec<Expr>,
     },
+    // without `else` the value is zero, the `if` only runs for its effects
     Condition {
         predicate: Box<Expr>,
         then: Box<Expr>,
-        other: Box<Expr>,
+        other: Option<Box<Expr>>,
+    },
+    // `[a, b, c]`
+    Array(Vec<Expr>),
+    // `target[index]`
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
+    // `Point { x: 1, y: 2 }`
+    Struct {
+        name: String,
+        fields: Vec<(String, Expr)>,
+    },
+    // `target.field`, through a pointer or on a struct value
+    Field {
+        target: Box<Expr>,
+        field: String,
+    },
+    // `&value`
+    Ref(Box<Expr>),
+    // `{ a; b; c }` or a bare `a; b; c`, evaluated in order to the last value
+    Block(Vec<Expr>),
+    // `let name = value in body`, where `name` shadows any outer binding
+    // until the end of `body`
+    Let {
+        name: String,
+        value: Box<Expr>,
+        body: Box<Expr>,
     },
+    // `|x, y: f64| -> f64 body`, which may use variables of its surroundings
+    Lambda {
+        args: Vec<(String, Option<Type>)>,
+        ret: Option<Type>,
+        body: Box<Expr>,
+    },
+}
+
+impl Expr {
+    /// A literal that isn't in the source, written the plain way.
+    pub fn number(value: u64, ty: Option<IntType>) -> Expr {
+        let text = match ty {
+            Some(ty) => format!("{}{}", value, ty),
+            None => value.to_string(),
+        };
+        Expr::Number { value, ty, text }
+    }
+
+    /// Names read by the expression that aren't bound inside it or in
+    /// `bound`, in the order they are first used.
+    pub fn free_variables(&self, bound: &[String]) -> Vec<String> {
+        let mut pass = FreeVariables {
+            bound: bound.to_vec(),
+            free: Vec::new(),
+        };
+        pass.visit_expr(self);
+        pass.free
+    }
+}
+
+struct FreeVariables {
+    // innermost last, a `let` or lambda pops its names again at its end
+    bound: Vec<String>,
+    free: Vec<String>,
+}
+
+impl FreeVariables {
+    fn note(&mut self, name: &str) {
+        if !self.bound.iter().any(|b| b == name) && !self.free.iter().any(|f| f == name) {
+            self.free.push(name.to_string());
+        }
+    }
+}
+
+impl Visitor for FreeVariables {
+    fn visit_variable(&mut self, name: &str) {
+        self.note(name);
+    }
+
+    fn visit_call(&mut self, name: &str, args: &[Expr]) {
+        self.note(name);
+        walk_call(self, name, args);
+    }
+
+    fn visit_let(&mut self, name: &str, value: &Expr, body: &Expr) {
+        self.visit_expr(value);
+        self.bound.push(name.to_string());
+        self.visit_expr(body);
+        self.bound.pop();
+    }
+
+    fn visit_lambda(&mut self, args: &[(String, Option<Type>)], _ret: Option<&Type>, body: &Expr) {
+        let outer = self.bound.len();
+        self.bound.extend(args.iter().map(|(name, _)| name.clone()));
+        self.visit_expr(body);
+        self.bound.truncate(outer);
+    }
+}
+
+#[derive(Debug)]
+pub enum Item {
+    Function(Function),
+    Struct(StructDef),
+    // `import "math.ks"`, relative to the importing file
+    Import(String),
+    // `use math::sqrt`, so `sqrt` can be called without its namespace
+    Use(String),
+}
+/// Where the parts of an item are in its file, which the tree itself
+/// doesn't keep, for diagnostics to point at.
+#[derive(Debug, Clone)]
+pub struct Spans {
+    // from the first to the last token of the item
+    pub item: Span,
+    // every expression in the item, in the order a `Visitor` visits them
+    pub exprs: Vec<Span>,
+    // the name a `def`, `extern` or `struct` declares
+    pub name: Option<Span>,
+    // every parameter, the prototype's first and then each lambda's, in the
+    // order a `Visitor` meets the lambdas
+    pub params: Vec<Span>,
+    // the name of every `let`, in the order a `Visitor` meets them
+    pub bindings: Vec<Span>,
 }
+
 #[derive(Debug)]
 pub struct Function {
     pub prototype: Option<Prototype>,
@@ -27,15 +158,448 @@ pub struct Function {
 
 #[derive(Debug)]
 pub struct Prototype {
-    pub name: Vec<u8>,
-    pub args: Vec<Vec<u8>>,
+    pub name: String,
+    // unannotated arguments and results are `Type::WORD`
+    pub args: Vec<(String, Option<Type>)>,
+    pub ret: Option<Type>,
+    // the shared library in `extern "libm.so.6" cos(x: f64) -> f64`, whose
+    // functions are called with the C calling convention, the only one
+    pub library: Option<String>,
 }
 
 impl Default for Prototype {
     fn default() -> Self {
         Prototype {
-            name: Vec::from("__anon_fn"),
+            name: String::from("__anon_fn"),
             args: Vec::new(),
+            ret: None,
+            library: None,
         }
     }
 }
+
+impl Prototype {
+    /// The argument and result types, with the unannotated ones filled in.
+    pub fn signature(&self) -> (Vec<Type>, Type) {
+        let args = self
+            .args
+            .iter()
+            .map(|(_, ty)| ty.clone().unwrap_or(Type::WORD))
+            .collect();
+        (args, self.ret.clone().unwrap_or(Type::WORD))
+    }
+}
+
+#[derive(Debug)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<(String, Type)>,
+}
+
+/// A pass reading the tree. Every `visit_*` method defaults to the matching
+/// `walk_*` function, which visits the children, so a pass only overrides
+/// the nodes it's interested in and calls `walk_*` from there to carry on
+/// below them.
+pub trait Visitor {
+    fn visit_item(&mut self, item: &Item) {
+        walk_item(self, item);
+    }
+
+    fn visit_function(&mut self, function: &Function) {
+        walk_function(self, function);
+    }
+
+    fn visit_prototype(&mut self, _prototype: &Prototype) {}
+
+    fn visit_struct_def(&mut self, _def: &StructDef) {}
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_number(&mut self, _value: u64, _ty: Option<IntType>) {}
+
+    fn visit_float(&mut self, _value: f64) {}
+
+    fn visit_variable(&mut self, _name: &str) {}
+
+    fn visit_binary(&mut self, op: Operator, lhs: &Expr, rhs: &Expr) {
+        walk_binary(self, op, lhs, rhs);
+    }
+
+    fn visit_call(&mut self, name: &str, args: &[Expr]) {
+        walk_call(self, name, args);
+    }
+
+    fn visit_condition(&mut self, predicate: &Expr, then: &Expr, other: Option<&Expr>) {
+        walk_condition(self, predicate, then, other);
+    }
+
+    fn visit_array(&mut self, elements: &[Expr]) {
+        walk_array(self, elements);
+    }
+
+    fn visit_index(&mut self, target: &Expr, index: &Expr) {
+        walk_index(self, target, index);
+    }
+
+    fn visit_struct(&mut self, name: &str, fields: &[(String, Expr)]) {
+        walk_struct(self, name, fields);
+    }
+
+    fn visit_field(&mut self, target: &Expr, field: &str) {
+        walk_field(self, target, field);
+    }
+
+    fn visit_ref(&mut self, value: &Expr) {
+        walk_ref(self, value);
+    }
+
+    fn visit_block(&mut self, exprs: &[Expr]) {
+        walk_block(self, exprs);
+    }
+
+    fn visit_let(&mut self, name: &str, value: &Expr, body: &Expr) {
+        walk_let(self, name, value, body);
+    }
+
+    fn visit_lambda(&mut self, args: &[(String, Option<Type>)], ret: Option<&Type>, body: &Expr) {
+        walk_lambda(self, args, ret, body);
+    }
+}
+
+pub fn walk_item<V: Visitor + ?Sized>(visitor: &mut V, item: &Item) {
+    match item {
+        Item::Function(function) => visitor.visit_function(function),
+        Item::Struct(def) => visitor.visit_struct_def(def),
+        Item::Import(_) | Item::Use(_) => {}
+    }
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(visitor: &mut V, function: &Function) {
+    if let Some(prototype) = &function.prototype {
+        visitor.visit_prototype(prototype);
+    }
+    if let Some(body) = &function.body {
+        visitor.visit_expr(body);
+    }
+}
+
+/// Hands `expr` to the `visit_*` method for its variant.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Number { value, ty, .. } => visitor.visit_number(*value, *ty),
+        Expr::Float(value) => visitor.visit_float(*value),
+        Expr::Variable(name) => visitor.visit_variable(name),
+        Expr::Binary { op, lhs, rhs } => visitor.visit_binary(*op, lhs, rhs),
+        Expr::Call { name, args } => visitor.visit_call(name, args),
+        Expr::Condition {
+            predicate,
+            then,
+            other,
+        } => visitor.visit_condition(predicate, then, other.as_deref()),
+        Expr::Array(elements) => visitor.visit_array(elements),
+        Expr::Index { target, index } => visitor.visit_index(target, index),
+        Expr::Struct { name, fields } => visitor.visit_struct(name, fields),
+        Expr::Field { target, field } => visitor.visit_field(target, field),
+        Expr::Ref(value) => visitor.visit_ref(value),
+        Expr::Block(exprs) => visitor.visit_block(exprs),
+        Expr::Let { name, value, body } => visitor.visit_let(name, value, body),
+        Expr::Lambda { args, ret, body } => visitor.visit_lambda(args, ret.as_ref(), body),
+    }
+}
+
+pub fn walk_binary<V: Visitor + ?Sized>(visitor: &mut V, _op: Operator, lhs: &Expr, rhs: &Expr) {
+    visitor.visit_expr(lhs);
+    visitor.visit_expr(rhs);
+}
+
+pub fn walk_call<V: Visitor + ?Sized>(visitor: &mut V, _name: &str, args: &[Expr]) {
+    for arg in args {
+        visitor.visit_expr(arg);
+    }
+}
+
+pub fn walk_condition<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    predicate: &Expr,
+    then: &Expr,
+    other: Option<&Expr>,
+) {
+    visitor.visit_expr(predicate);
+    visitor.visit_expr(then);
+    if let Some(other) = other {
+        visitor.visit_expr(other);
+    }
+}
+
+pub fn walk_array<V: Visitor + ?Sized>(visitor: &mut V, elements: &[Expr]) {
+    for element in elements {
+        visitor.visit_expr(element);
+    }
+}
+
+pub fn walk_index<V: Visitor + ?Sized>(visitor: &mut V, target: &Expr, index: &Expr) {
+    visitor.visit_expr(target);
+    visitor.visit_expr(index);
+}
+
+pub fn walk_struct<V: Visitor + ?Sized>(visitor: &mut V, _name: &str, fields: &[(String, Expr)]) {
+    for (_, value) in fields {
+        visitor.visit_expr(value);
+    }
+}
+
+pub fn walk_field<V: Visitor + ?Sized>(visitor: &mut V, target: &Expr, _field: &str) {
+    visitor.visit_expr(target);
+}
+
+pub fn walk_ref<V: Visitor + ?Sized>(visitor: &mut V, value: &Expr) {
+    visitor.visit_expr(value);
+}
+
+pub fn walk_block<V: Visitor + ?Sized>(visitor: &mut V, exprs: &[Expr]) {
+    for expr in exprs {
+        visitor.visit_expr(expr);
+    }
+}
+
+pub fn walk_let<V: Visitor + ?Sized>(visitor: &mut V, _name: &str, value: &Expr, body: &Expr) {
+    visitor.visit_expr(value);
+    visitor.visit_expr(body);
+}
+
+pub fn walk_lambda<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    _args: &[(String, Option<Type>)],
+    _ret: Option<&Type>,
+    body: &Expr,
+) {
+    visitor.visit_expr(body);
+}
+
+/// A pass rebuilding the tree, which it takes apart by value. Like with
+/// `Visitor`, every `fold_*` method defaults to the matching `noop_fold_*`
+/// function, which folds the children and puts the node back together as
+/// it was.
+pub trait Folder {
+    fn fold_item(&mut self, item: Item) -> Item {
+        noop_fold_item(self, item)
+    }
+
+    fn fold_function(&mut self, function: Function) -> Function {
+        noop_fold_function(self, function)
+    }
+
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        noop_fold_expr(self, expr)
+    }
+
+    fn fold_number(&mut self, value: u64, ty: Option<IntType>, text: String) -> Expr {
+        Expr::Number { value, ty, text }
+    }
+
+    fn fold_float(&mut self, value: f64) -> Expr {
+        Expr::Float(value)
+    }
+
+    fn fold_variable(&mut self, name: String) -> Expr {
+        Expr::Variable(name)
+    }
+
+    fn fold_binary(&mut self, op: Operator, lhs: Expr, rhs: Expr) -> Expr {
+        noop_fold_binary(self, op, lhs, rhs)
+    }
+
+    fn fold_call(&mut self, name: String, args: Vec<Expr>) -> Expr {
+        noop_fold_call(self, name, args)
+    }
+
+    fn fold_condition(&mut self, predicate: Expr, then: Expr, other: Option<Expr>) -> Expr {
+        noop_fold_condition(self, predicate, then, other)
+    }
+
+    fn fold_array(&mut self, elements: Vec<Expr>) -> Expr {
+        noop_fold_array(self, elements)
+    }
+
+    fn fold_index(&mut self, target: Expr, index: Expr) -> Expr {
+        noop_fold_index(self, target, index)
+    }
+
+    fn fold_struct(&mut self, name: String, fields: Vec<(String, Expr)>) -> Expr {
+        noop_fold_struct(self, name, fields)
+    }
+
+    fn fold_field(&mut self, target: Expr, field: String) -> Expr {
+        noop_fold_field(self, target, field)
+    }
+
+    fn fold_ref(&mut self, value: Expr) -> Expr {
+        noop_fold_ref(self, value)
+    }
+
+    fn fold_block(&mut self, exprs: Vec<Expr>) -> Expr {
+        noop_fold_block(self, exprs)
+    }
+
+    fn fold_let(&mut self, name: String, value: Expr, body: Expr) -> Expr {
+        noop_fold_let(self, name, value, body)
+    }
+
+    fn fold_lambda(
+        &mut self,
+        args: Vec<(String, Option<Type>)>,
+        ret: Option<Type>,
+        body: Expr,
+    ) -> Expr {
+        noop_fold_lambda(self, args, ret, body)
+    }
+}
+
+pub fn noop_fold_item<F: Folder + ?Sized>(folder: &mut F, item: Item) -> Item {
+    match item {
+        Item::Function(function) => Item::Function(folder.fold_function(function)),
+        item => item,
+    }
+}
+
+pub fn noop_fold_function<F: Folder + ?Sized>(folder: &mut F, function: Function) -> Function {
+    Function {
+        prototype: function.prototype,
+        body: function.body.map(|body| folder.fold_expr(body)),
+    }
+}
+
+/// Hands `expr` to the `fold_*` method for its variant.
+pub fn noop_fold_expr<F: Folder + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    match expr {
+        Expr::Number { value, ty, text } => folder.fold_number(value, ty, text),
+        Expr::Float(value) => folder.fold_float(value),
+        Expr::Variable(name) => folder.fold_variable(name),
+        Expr::Binary { op, lhs, rhs } => folder.fold_binary(op, *lhs, *rhs),
+        Expr::Call { name, args } => folder.fold_call(name, args),
+        Expr::Condition {
+            predicate,
+            then,
+            other,
+        } => folder.fold_condition(*predicate, *then, other.map(|other| *other)),
+        Expr::Array(elements) => folder.fold_array(elements),
+        Expr::Index { target, index } => folder.fold_index(*target, *index),
+        Expr::Struct { name, fields } => folder.fold_struct(name, fields),
+        Expr::Field { target, field } => folder.fold_field(*target, field),
+        Expr::Ref(value) => folder.fold_ref(*value),
+        Expr::Block(exprs) => folder.fold_block(exprs),
+        Expr::Let { name, value, body } => folder.fold_let(name, *value, *body),
+        Expr::Lambda { args, ret, body } => folder.fold_lambda(args, ret, *body),
+    }
+}
+
+pub fn noop_fold_binary<F: Folder + ?Sized>(
+    folder: &mut F,
+    op: Operator,
+    lhs: Expr,
+    rhs: Expr,
+) -> Expr {
+    Expr::Binary {
+        op,
+        lhs: Box::new(folder.fold_expr(lhs)),
+        rhs: Box::new(folder.fold_expr(rhs)),
+    }
+}
+
+pub fn noop_fold_call<F: Folder + ?Sized>(folder: &mut F, name: String, args: Vec<Expr>) -> Expr {
+    Expr::Call {
+        name,
+        args: args.into_iter().map(|arg| folder.fold_expr(arg)).collect(),
+    }
+}
+
+pub fn noop_fold_condition<F: Folder + ?Sized>(
+    folder: &mut F,
+    predicate: Expr,
+    then: Expr,
+    other: Option<Expr>,
+) -> Expr {
+    Expr::Condition {
+        predicate: Box::new(folder.fold_expr(predicate)),
+        then: Box::new(folder.fold_expr(then)),
+        other: other.map(|other| Box::new(folder.fold_expr(other))),
+    }
+}
+
+pub fn noop_fold_array<F: Folder + ?Sized>(folder: &mut F, elements: Vec<Expr>) -> Expr {
+    Expr::Array(
+        elements
+            .into_iter()
+            .map(|element| folder.fold_expr(element))
+            .collect(),
+    )
+}
+
+pub fn noop_fold_index<F: Folder + ?Sized>(folder: &mut F, target: Expr, index: Expr) -> Expr {
+    Expr::Index {
+        target: Box::new(folder.fold_expr(target)),
+        index: Box::new(folder.fold_expr(index)),
+    }
+}
+
+pub fn noop_fold_struct<F: Folder + ?Sized>(
+    folder: &mut F,
+    name: String,
+    fields: Vec<(String, Expr)>,
+) -> Expr {
+    Expr::Struct {
+        name,
+        fields: fields
+            .into_iter()
+            .map(|(field, value)| (field, folder.fold_expr(value)))
+            .collect(),
+    }
+}
+
+pub fn noop_fold_field<F: Folder + ?Sized>(folder: &mut F, target: Expr, field: String) -> Expr {
+    Expr::Field {
+        target: Box::new(folder.fold_expr(target)),
+        field,
+    }
+}
+
+pub fn noop_fold_ref<F: Folder + ?Sized>(folder: &mut F, value: Expr) -> Expr {
+    Expr::Ref(Box::new(folder.fold_expr(value)))
+}
+
+pub fn noop_fold_block<F: Folder + ?Sized>(folder: &mut F, exprs: Vec<Expr>) -> Expr {
+    Expr::Block(
+        exprs
+            .into_iter()
+            .map(|expr| folder.fold_expr(expr))
+            .collect(),
+    )
+}
+
+pub fn noop_fold_let<F: Folder + ?Sized>(
+    folder: &mut F,
+    name: String,
+    value: Expr,
+    body: Expr,
+) -> Expr {
+    Expr::Let {
+        name,
+        value: Box::new(folder.fold_expr(value)),
+        body: Box::new(folder.fold_expr(body)),
+    }
+}
+
+pub fn noop_fold_lambda<F: Folder + ?Sized>(
+    folder: &mut F,
+    args: Vec<(String, Option<Type>)>,
+    ret: Option<Type>,
+    body: Expr,
+) -> Expr {
+    Expr::Lambda {
+        args,
+        ret,
+        body: Box::new(folder.fold_expr(body)),
+    }
+}
@@ -0,0 +1,1079 @@
+use crate::ast::*;
+use crate::const_fold;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::Lexer;
+use crate::operator::Operator;
+use crate::parser::Parser;
+use crate::resolve;
+use crate::runtime;
+use crate::types::{lambda_signature, IntType, Type};
+use crate::vm::{Body, Function, Slot, Vm};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::mem::{replace, take};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The type arithmetic and conversions work on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Num {
+    Int(IntType),
+    F64,
+}
+
+impl Num {
+    fn of(ty: &Type) -> Option<Num> {
+        match ty {
+            Type::Int(int) => Some(Num::Int(*int)),
+            Type::F64 => Some(Num::F64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Num {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Num::Int(int) => write!(f, "{}", int),
+            Num::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+/// One instruction of the stack machine. Operands are popped in the order
+/// they were pushed, and the result is pushed in their place.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    // a placeholder patched once the types around it are known, anything
+    // left over is removed by `finish`
+    Nop,
+    Const(u32),
+    Load(u32),
+    Store(u32),
+    Pop,
+    Add(Num),
+    Sub(Num),
+    Mul(Num),
+    Pow(Num),
+    // a word, 1 if the first operand is the smaller one
+    Less(Num),
+    Cast(Num, Num),
+    Jump(u32),
+    // pops a number and jumps if it's zero
+    JumpUnless(u32),
+    // function and argument count
+    Call(u32, u32),
+    // a call whose result is returned as is, the callee takes over the frame
+    TailCall(u32, u32),
+    // the closure sits below its arguments
+    CallClosure(u32),
+    TailCallClosure(u32),
+    Return,
+    // `[a, b, c]` from that many words
+    Array(u32),
+    Alloc,
+    Len,
+    // array and index
+    Index,
+    // array, index and value
+    SetIndex,
+    // that many fields, in declaration order
+    Struct(u32),
+    Field(u32),
+    Deref,
+    // pointer to a struct and value
+    SetField(u32),
+    // moves the value behind a new pointer
+    Ref,
+    // function and how many captured values it takes along, `&name` is a
+    // closure without any
+    Closure(u32, u32),
+}
+
+/// The code of one function. Its locals are the arguments, then what a
+/// lambda captured, then every `let` and temporary.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub name: String,
+    pub code: Vec<Op>,
+    pub constants: Vec<Slot>,
+    // names of the locals, empty for temporaries
+    pub slots: Vec<String>,
+}
+
+impl Chunk {
+    fn new(name: &str) -> Chunk {
+        Chunk {
+            name: name.to_string(),
+            ..Chunk::default()
+        }
+    }
+
+    pub fn locals(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+// Turns calls whose result is returned as is into tail calls, then drops the
+// placeholders nothing was patched into.
+fn finish(mut chunk: Chunk) -> Chunk {
+    for i in 0..chunk.code.len() {
+        if !matches!(chunk.code[i], Op::Call(..) | Op::CallClosure(_)) {
+            continue;
+        }
+        // every chunk ends in `Return` and jumps only go forward
+        let mut next = i + 1;
+        let returns = loop {
+            match chunk.code[next] {
+                Op::Nop => next += 1,
+                Op::Jump(target) => next = target as usize,
+                op => break op == Op::Return,
+            }
+        };
+        if returns {
+            chunk.code[i] = match chunk.code[i] {
+                Op::Call(function, argc) => Op::TailCall(function, argc),
+                Op::CallClosure(argc) => Op::TailCallClosure(argc),
+                op => op,
+            };
+        }
+    }
+
+    let mut moved = Vec::with_capacity(chunk.code.len() + 1);
+    let mut kept = 0;
+    for op in &chunk.code {
+        moved.push(kept);
+        if *op != Op::Nop {
+            kept += 1;
+        }
+    }
+    moved.push(kept);
+    chunk.code.retain(|op| *op != Op::Nop);
+    for op in &mut chunk.code {
+        if let Op::Jump(target) | Op::JumpUnless(target) = op {
+            *target = moved[*target as usize];
+        }
+    }
+    chunk
+}
+
+/// A listing of `chunk`, with function names and constants spelled out.
+pub fn disassemble(chunk: &Chunk, functions: &[Function]) -> String {
+    let mut out = format!("{}:\n", chunk.name);
+    let name = |function: u32| functions[function as usize].name.as_str();
+    for (i, op) in chunk.code.iter().enumerate() {
+        let text = match *op {
+            Op::Nop => String::from("nop"),
+            Op::Const(index) => match &chunk.constants[index as usize] {
+                Slot::Int(bits) => format!("const {}", bits),
+                Slot::F64(value) => format!("const {:?}", value),
+                slot => format!("const {:?}", slot),
+            },
+            Op::Load(slot) | Op::Store(slot) => {
+                let verb = if let Op::Load(_) = op {
+                    "load"
+                } else {
+                    "store"
+                };
+                match chunk.slots[slot as usize].as_str() {
+                    "" => format!("{} {}", verb, slot),
+                    local => format!("{} {} ({})", verb, slot, local),
+                }
+            }
+            Op::Pop => String::from("pop"),
+            Op::Add(num) => format!("add {}", num),
+            Op::Sub(num) => format!("sub {}", num),
+            Op::Mul(num) => format!("mul {}", num),
+            Op::Pow(num) => format!("pow {}", num),
+            Op::Less(num) => format!("less {}", num),
+            Op::Cast(from, to) => format!("cast {} -> {}", from, to),
+            Op::Jump(target) => format!("jump {}", target),
+            Op::JumpUnless(target) => format!("jump_unless {}", target),
+            Op::Call(function, argc) => format!("call {} {}", name(function), argc),
+            Op::TailCall(function, argc) => format!("tail_call {} {}", name(function), argc),
+            Op::CallClosure(argc) => format!("call_closure {}", argc),
+            Op::TailCallClosure(argc) => format!("tail_call_closure {}", argc),
+            Op::Return => String::from("return"),
+            Op::Array(len) => format!("array {}", len),
+            Op::Alloc => String::from("alloc"),
+            Op::Len => String::from("len"),
+            Op::Index => String::from("index"),
+            Op::SetIndex => String::from("set_index"),
+            Op::Struct(len) => format!("struct {}", len),
+            Op::Field(index) => format!("field {}", index),
+            Op::Deref => String::from("deref"),
+            Op::SetField(index) => format!("set_field {}", index),
+            Op::Ref => String::from("ref"),
+            Op::Closure(function, count) => format!("closure {} {}", name(function), count),
+        };
+        out.push_str(&format!("{:>4}  {}\n", i, text));
+    }
+    out
+}
+
+// what happens to a top-level expression once it's compiled
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Run,
+    // printed like the functions, see `:bytecode`
+    Show,
+    Check,
+}
+
+/// Compiles items to bytecode for a `Vm`, which runs each top-level
+/// expression as soon as it's compiled. Types are checked here, once, just
+/// like `CodeGen` does, so the `Vm` never has to look at them.
+pub struct Compiler<'a> {
+    parser: &'a mut dyn Iterator<Item = Result<(Item, String, Spans), Diagnostic>>,
+    vm: &'a mut Vm,
+    parsed_buffer: String,
+    // the file being compiled, imports are looked up next to it
+    path: PathBuf,
+    // the file's functions are defined as `namespace::name`
+    namespace: Option<String>,
+    // `use math::sqrt` maps `sqrt` to `math::sqrt`
+    aliases: HashMap<String, String>,
+    // the name of the item being compiled, problems with it point there
+    name_span: Option<Span>,
+    mode: Mode,
+    // the function being compiled
+    chunk: Chunk,
+    // bindings in scope with their slot, innermost last
+    locals: Vec<(String, u32, Type)>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(
+        parser: &'a mut dyn Iterator<Item = Result<(Item, String, Spans), Diagnostic>>,
+        vm: &'a mut Vm,
+    ) -> Compiler<'a> {
+        Compiler {
+            parser,
+            vm,
+            parsed_buffer: String::new(),
+            path: PathBuf::new(),
+            namespace: None,
+            aliases: HashMap::new(),
+            name_span: None,
+            mode: Mode::Run,
+            chunk: Chunk::default(),
+            locals: Vec::new(),
+        }
+    }
+
+    /// Compiles the code as the contents of `path`, putting the functions it
+    /// defines under `namespace::` if one is given.
+    pub fn in_file(mut self, path: &Path, namespace: Option<String>) -> Compiler<'a> {
+        self.path = path.to_path_buf();
+        self.namespace = namespace;
+        self
+    }
+
+    /// Prints the bytecode of every function and top-level expression instead
+    /// of running the expressions.
+    pub fn showing_bytecode(mut self) -> Compiler<'a> {
+        self.mode = Mode::Show;
+        self
+    }
+
+    /// Only compiles, reporting the same errors without running or printing
+    /// anything, e.g. for the fuzzer.
+    pub fn checking_only(mut self) -> Compiler<'a> {
+        self.mode = Mode::Check;
+        self
+    }
+
+    /// Compiles every item, reporting problems against `source` as they come up.
+    pub fn run_to_end(&mut self, path: &str, source: &str) {
+        while let Some(result) = self.compile_item() {
+            if let Err(err) = result {
+                eprintln!("{}", err.render(path, source));
+            }
+        }
+    }
+
+    pub fn compile_item(&mut self) -> Option<Result<(), Diagnostic>> {
+        let (item, span) = match self.parser.next()? {
+            Ok((item, buf, spans)) => {
+                self.parsed_buffer = buf;
+                let arity = |name: &str| {
+                    let name = self.resolve_function(name);
+                    self.vm
+                        .types
+                        .functions
+                        .get(&name)
+                        .map(|(args, _)| args.len())
+                };
+                if let Err(err) = resolve::resolve_item(&item, &spans, &arity) {
+                    return Some(Err(err));
+                }
+                self.name_span = spans.name;
+                (const_fold::fold_item(item), spans.item)
+            }
+            Err(err) => return Some(Err(err)),
+        };
+        let result = match item {
+            Item::Struct(def) => self.define_struct(def),
+            Item::Import(target) => self.import(&target),
+            Item::Use(path) => self.use_path(path),
+            Item::Function(fun) => match (fun.prototype, fun.body) {
+                (Some(mut proto), Some(body)) => {
+                    // externs keep their name, it's the symbol they link to
+                    if let Some(namespace) = &self.namespace {
+                        proto.name = format!("{}::{}", namespace, proto.name);
+                    }
+                    self.define(proto, &body)
+                }
+                (None, Some(body)) => self.run_anon(&body),
+                (Some(proto), None) => self.declare_extern(proto),
+                (None, None) => {
+                    panic!("Unsupposed to see a function without nither prototype nor body!")
+                }
+            },
+        };
+        // like the JIT, whatever has no more precise place points at the item
+        Some(result.map_err(|err| match err.span {
+            Some(_) => err,
+            None => err.with_span(span),
+        }))
+    }
+
+    fn run_anon(&mut self, body: &Expr) -> Result<(), Diagnostic> {
+        let (chunk, ty) = self.function("__anon_fn", Vec::new(), None, body)?;
+        if self.mode == Mode::Run {
+            let value = self.vm.run(Rc::new(chunk))?;
+            println!("{} => {}", self.parsed_buffer, self.vm.format(&value, &ty));
+        }
+        Ok(())
+    }
+
+    fn define_struct(&mut self, def: StructDef) -> Result<(), Diagnostic> {
+        if self.vm.types.structs.contains_key(&def.name) {
+            return Err(self.name_error(format!("struct `{}` is already defined", def.name)));
+        }
+        self.vm
+            .types
+            .check_fields(&def.name, &def.fields)
+            .map_err(Diagnostic::error)?;
+        self.vm.types.structs.insert(def.name, def.fields);
+        Ok(())
+    }
+
+    fn import(&mut self, target: &str) -> Result<(), Diagnostic> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let path = dir.join(target);
+        let key = match self.vm.types.import_key(&path).map_err(Diagnostic::error)? {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let source = fs::read_to_string(&path)
+            .map_err(|err| Diagnostic::error(format!("could not import `{}`: {}", target, err)))?;
+        let namespace = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        self.vm.types.imported.insert(key.clone());
+        self.vm.types.importing.push(key);
+        let display = path.to_string_lossy().into_owned();
+        let mut lex = Lexer::new(&source);
+        let mut par = Parser::new(&mut lex);
+        let mut compiler = Compiler::new(&mut par, self.vm).in_file(&path, Some(namespace));
+        compiler.mode = self.mode;
+        compiler.run_to_end(&display, &source);
+        self.vm.types.importing.pop();
+        Ok(())
+    }
+
+    fn use_path(&mut self, path: String) -> Result<(), Diagnostic> {
+        if !self.vm.types.functions.contains_key(&path) {
+            return Err(Diagnostic::error(format!("unresolved import `{}`", path)));
+        }
+        let name = path.rsplit("::").next().unwrap().to_string();
+        self.aliases.insert(name, path);
+        Ok(())
+    }
+
+    // Functions of this file win over `use`d ones, which win over globals.
+    fn resolve_function(&self, name: &str) -> String {
+        if let Some(namespace) = &self.namespace {
+            let local = format!("{}::{}", namespace, name);
+            if self.vm.types.functions.contains_key(&local) {
+                return local;
+            }
+        }
+        match self.aliases.get(name) {
+            Some(path) => path.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    // A problem with the name of the item being compiled, pointing at it.
+    fn name_error(&self, message: String) -> Diagnostic {
+        match self.name_span {
+            Some(span) => Diagnostic::error(message).with_span(span),
+            None => Diagnostic::error(message),
+        }
+    }
+
+    // Makes `prototype` callable by name before its body is compiled, so it
+    // can call itself. A name declared before keeps its function, code
+    // compiled since then already calls it.
+    fn declare(&mut self, prototype: &Prototype) -> Result<usize, Diagnostic> {
+        let (args, ret) = prototype.signature();
+        self.vm
+            .types
+            .check_types(args.iter().chain(Some(&ret)))
+            .map_err(Diagnostic::error)?;
+        self.vm
+            .types
+            .check_signature(&prototype.name, &args, &ret)
+            .map_err(|message| self.name_error(message))?;
+        if let Some(&index) = self.vm.names.get(&prototype.name) {
+            return Ok(index);
+        }
+        self.vm
+            .types
+            .functions
+            .insert(prototype.name.clone(), (args.clone(), ret.clone()));
+        self.vm.functions.push(Function {
+            name: prototype.name.clone(),
+            args,
+            ret,
+            body: Body::Foreign(None),
+        });
+        let index = self.vm.functions.len() - 1;
+        self.vm.names.insert(prototype.name.clone(), index);
+        Ok(index)
+    }
+
+    // A redefinition replaces the body for every caller, older ones included.
+    fn define(&mut self, prototype: Prototype, body: &Expr) -> Result<(), Diagnostic> {
+        let declared = self.vm.names.contains_key(&prototype.name);
+        let index = self.declare(&prototype)?;
+        let function = &self.vm.functions[index];
+        let params = prototype
+            .args
+            .iter()
+            .map(|(name, _)| name.clone())
+            .zip(function.args.clone())
+            .collect();
+        let ret = function.ret.clone();
+        match self.function(&prototype.name, params, Some(ret), body) {
+            Ok((chunk, _)) => {
+                self.vm.functions[index].body = Body::Chunk(Rc::new(chunk));
+                Ok(())
+            }
+            // calls keep going to whatever the name meant before
+            Err(err) => {
+                if !declared {
+                    self.vm.names.remove(&prototype.name);
+                    self.vm.types.functions.remove(&prototype.name);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // `extern "libm.so.6" cos(x: f64) -> f64` is bound when it's declared,
+    // like the JIT does, other externs are looked up when first called.
+    fn declare_extern(&mut self, prototype: Prototype) -> Result<(), Diagnostic> {
+        let address = match &prototype.library {
+            Some(library) => {
+                let types = prototype
+                    .args
+                    .iter()
+                    .filter_map(|(_, ty)| ty.as_ref())
+                    .chain(prototype.ret.as_ref());
+                for ty in types {
+                    if let Type::Struct(_) | Type::Function(..) = ty {
+                        return Err(Diagnostic::error(format!(
+                            "`{}` can't be passed to `{}` in `{}`, pass a pointer instead",
+                            ty, prototype.name, library
+                        )));
+                    }
+                }
+                let address = runtime::load_symbol(library, &prototype.name).map_err(|err| {
+                    Diagnostic::error(format!(
+                        "could not bind `{}` from `{}`: {}",
+                        prototype.name, library, err
+                    ))
+                })?;
+                Some(address)
+            }
+            None => None,
+        };
+        // without a library it's whatever the name already is, if anything
+        let index = self.declare(&prototype)?;
+        if address.is_some() {
+            self.vm.functions[index].body = Body::Foreign(address);
+        }
+        Ok(())
+    }
+
+    // Compiles `body` as a function of `params`, returning a `ret` if it's
+    // given, or whatever type the body has otherwise.
+    fn function(
+        &mut self,
+        name: &str,
+        params: Vec<(String, Type)>,
+        ret: Option<Type>,
+        body: &Expr,
+    ) -> Result<(Chunk, Type), Diagnostic> {
+        let outer = (
+            replace(&mut self.chunk, Chunk::new(name)),
+            take(&mut self.locals),
+        );
+        for (param, ty) in params {
+            let slot = self.slot(&param);
+            self.locals.push((param, slot, ty));
+        }
+        let result = self.expr(body, ret.as_ref()).and_then(|ty| match ret {
+            Some(ret) => self.coerce(&ty, &ret).map(|_| ret),
+            None => Ok(ty),
+        });
+        self.emit(Op::Return);
+        let chunk = replace(&mut self.chunk, outer.0);
+        self.locals = outer.1;
+
+        let ty = result?;
+        let chunk = finish(chunk);
+        if self.mode == Mode::Show {
+            print!("{}", disassemble(&chunk, &self.vm.functions));
+        }
+        Ok((chunk, ty))
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.code.len() - 1
+    }
+
+    fn slot(&mut self, name: &str) -> u32 {
+        self.chunk.slots.push(name.to_string());
+        self.chunk.slots.len() as u32 - 1
+    }
+
+    fn constant(&mut self, value: Slot) -> Op {
+        let same = |c: &Slot| match (c, &value) {
+            (Slot::Int(a), Slot::Int(b)) => a == b,
+            (Slot::F64(a), Slot::F64(b)) => a.to_bits() == b.to_bits(),
+            _ => false,
+        };
+        let index = match self.chunk.constants.iter().position(same) {
+            Some(index) => index,
+            None => {
+                self.chunk.constants.push(value);
+                self.chunk.constants.len() - 1
+            }
+        };
+        Op::Const(index as u32)
+    }
+
+    fn literal(&mut self, value: u64, ty: &Type) -> Op {
+        match ty {
+            Type::F64 => self.constant(Slot::F64(value as f64)),
+            Type::Int(int) => self.constant(Slot::Int(int.truncate(value))),
+            _ => unreachable!("literals are numbers"),
+        }
+    }
+
+    // Points the jump at `at` to the next instruction.
+    fn patch_jump(&mut self, at: usize) {
+        let next = self.chunk.code.len() as u32;
+        if let Op::Jump(target) | Op::JumpUnless(target) = &mut self.chunk.code[at] {
+            *target = next;
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<(u32, Type)> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|(local, ..)| local == name)
+            .map(|(_, slot, ty)| (*slot, ty.clone()))
+    }
+
+    fn field_index(&self, name: &str, field: &str) -> Result<(usize, Type), Diagnostic> {
+        let fields = match self.vm.types.structs.get(name) {
+            Some(fields) => fields,
+            None => return Err(Diagnostic::error(format!("could not find type `{}`", name))),
+        };
+        match fields.iter().position(|(f, _)| f == field) {
+            Some(i) => Ok((i, fields[i].1.clone())),
+            None => Err(Diagnostic::error(format!(
+                "`{}` has no field `{}`",
+                name, field
+            ))),
+        }
+    }
+
+    // The instruction converting a `from` to a `to`: numbers convert to each
+    // other, anything else has to match exactly.
+    fn cast(&self, from: &Type, to: &Type) -> Result<Op, Diagnostic> {
+        if from == to {
+            return Ok(Op::Nop);
+        }
+        match (Num::of(from), Num::of(to)) {
+            (Some(from), Some(to)) => Ok(Op::Cast(from, to)),
+            _ => Err(Diagnostic::error(format!(
+                "expected a value of type `{}`, but found `{}`",
+                to, from
+            ))),
+        }
+    }
+
+    fn coerce(&mut self, from: &Type, to: &Type) -> Result<(), Diagnostic> {
+        let op = self.cast(from, to)?;
+        self.emit(op);
+        Ok(())
+    }
+
+    // Everything array related still works on plain words.
+    fn word(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        let ty = self.expr(expr, Some(&Type::WORD))?;
+        self.coerce(&ty, &Type::WORD)
+    }
+
+    fn truth(&self, ty: &Type) -> Result<(), Diagnostic> {
+        if ty.is_numeric() {
+            return Ok(());
+        }
+        Err(Diagnostic::error(format!(
+            "expected a number as condition, but found `{}`",
+            ty
+        )))
+    }
+
+    fn assign(&mut self, target: &Expr, value: &Expr) -> Result<Type, Diagnostic> {
+        match target {
+            Expr::Index { target, index } => {
+                self.word(target)?;
+                self.word(index)?;
+                self.word(value)?;
+                self.emit(Op::SetIndex);
+                Ok(Type::WORD)
+            }
+            Expr::Field { target, field } => {
+                let pointee = match self.expr(target, None)? {
+                    Type::Pointer(inner) => match *inner {
+                        Type::Struct(name) => name,
+                        ty => {
+                            return Err(Diagnostic::error(format!(
+                                "`*{}` has no field `{}`",
+                                ty, field
+                            )))
+                        }
+                    },
+                    ty @ Type::Struct(_) => {
+                        return Err(Diagnostic::error(format!(
+                            "fields of `{}` can only be changed through a pointer, try `&`",
+                            ty
+                        )))
+                    }
+                    ty => {
+                        return Err(Diagnostic::error(format!(
+                            "`{}` has no field `{}`",
+                            ty, field
+                        )))
+                    }
+                };
+                let (index, ty) = self.field_index(&pointee, field)?;
+                let value_ty = self.expr(value, Some(&ty))?;
+                self.coerce(&value_ty, &ty)?;
+                self.emit(Op::SetField(index as u32));
+                Ok(ty)
+            }
+            _ => Err(Diagnostic::error(
+                "can only assign to an array element or a field",
+            )),
+        }
+    }
+
+    // Fields are evaluated in the order they're written but stored in the
+    // order they're declared, through temporaries if the two differ.
+    fn struct_literal(
+        &mut self,
+        name: &str,
+        fields: &[(String, Expr)],
+    ) -> Result<Type, Diagnostic> {
+        let def = match self.vm.types.structs.get(name) {
+            Some(def) => def.clone(),
+            None => return Err(Diagnostic::error(format!("could not find type `{}`", name))),
+        };
+        let mut order = Vec::with_capacity(fields.len());
+        for (field, _) in fields {
+            let (index, _) = self.field_index(name, field)?;
+            if order.contains(&index) {
+                return Err(Diagnostic::error(format!(
+                    "field `{}` is initialized twice",
+                    field
+                )));
+            }
+            order.push(index);
+        }
+        if let Some(i) = (0..def.len()).find(|i| !order.contains(i)) {
+            return Err(Diagnostic::error(format!(
+                "missing field `{}` in `{}`",
+                def[i].0, name
+            )));
+        }
+
+        let in_order = order.windows(2).all(|pair| pair[0] < pair[1]);
+        let mut temps = vec![0; def.len()];
+        for ((_, value), &index) in fields.iter().zip(&order) {
+            let ty = self.expr(value, Some(&def[index].1))?;
+            self.coerce(&ty, &def[index].1)?;
+            if !in_order {
+                temps[index] = self.slot("");
+                self.emit(Op::Store(temps[index]));
+            }
+        }
+        if !in_order {
+            for temp in temps {
+                self.emit(Op::Load(temp));
+            }
+        }
+        self.emit(Op::Struct(def.len() as u32));
+        Ok(Type::Struct(name.to_string()))
+    }
+
+    fn field(&mut self, target: &Expr, field: &str) -> Result<Type, Diagnostic> {
+        let name = match self.expr(target, None)? {
+            Type::Struct(name) => name,
+            Type::Pointer(inner) => match *inner {
+                Type::Struct(name) => {
+                    self.emit(Op::Deref);
+                    name
+                }
+                ty => {
+                    return Err(Diagnostic::error(format!(
+                        "`*{}` has no field `{}`",
+                        ty, field
+                    )))
+                }
+            },
+            ty => {
+                return Err(Diagnostic::error(format!(
+                    "`{}` has no field `{}`",
+                    ty, field
+                )))
+            }
+        };
+        let (index, ty) = self.field_index(&name, field)?;
+        self.emit(Op::Field(index as u32));
+        Ok(ty)
+    }
+
+    // `&value` puts a copy of the value behind a pointer, `&square` takes a
+    // named function instead.
+    fn reference(&mut self, value: &Expr) -> Result<Type, Diagnostic> {
+        if let Expr::Variable(name) = value {
+            if self.lookup(name).is_none() {
+                let name = self.resolve_function(name);
+                return match self.vm.names.get(&name) {
+                    Some(&index) => {
+                        self.emit(Op::Closure(index as u32, 0));
+                        let (args, ret) = self.vm.types.functions[&name].clone();
+                        Ok(Type::Function(args, Box::new(ret)))
+                    }
+                    None => Err(Diagnostic::error(format!(
+                        "could not find function `{}`",
+                        name
+                    ))),
+                };
+            }
+        }
+        let ty = self.expr(value, None)?;
+        self.emit(Op::Ref);
+        Ok(Type::Pointer(Box::new(ty)))
+    }
+
+    // The body becomes a function of its arguments followed by whatever it
+    // uses from the enclosing scope, which is copied into the closure.
+    fn lambda(
+        &mut self,
+        args: &[(String, Option<Type>)],
+        ret: &Option<Type>,
+        body: &Expr,
+        expected: Option<&Type>,
+    ) -> Result<Type, Diagnostic> {
+        let (arg_types, ret) = lambda_signature(args, ret, expected);
+        self.vm
+            .types
+            .check_types(arg_types.iter().chain(Some(&ret)))
+            .map_err(Diagnostic::error)?;
+        let names: Vec<String> = args.iter().map(|(name, _)| name.clone()).collect();
+        let captures: Vec<(String, u32, Type)> = body
+            .free_variables(&names)
+            .into_iter()
+            .filter_map(|name| {
+                let (slot, ty) = self.lookup(&name)?;
+                Some((name, slot, ty))
+            })
+            .collect();
+
+        self.vm.functions.push(Function {
+            name: String::from("lambda"),
+            args: arg_types.clone(),
+            ret: ret.clone(),
+            body: Body::Foreign(None),
+        });
+        let index = self.vm.functions.len() - 1;
+        let params = names
+            .into_iter()
+            .zip(arg_types.iter().cloned())
+            .chain(
+                captures
+                    .iter()
+                    .map(|(name, _, ty)| (name.clone(), ty.clone())),
+            )
+            .collect();
+        let (chunk, _) = self.function("lambda", params, Some(ret.clone()), body)?;
+        self.vm.functions[index].body = Body::Chunk(Rc::new(chunk));
+
+        for (_, slot, _) in &captures {
+            self.emit(Op::Load(*slot));
+        }
+        self.emit(Op::Closure(index as u32, captures.len() as u32));
+        Ok(Type::Function(arg_types, Box::new(ret)))
+    }
+
+    fn builtin(&mut self, name: &str, args: &[Expr]) -> Result<Type, Diagnostic> {
+        match (name, args) {
+            ("alloc", [len]) => {
+                self.word(len)?;
+                self.emit(Op::Alloc);
+                Ok(Type::WORD)
+            }
+            ("len", [array]) => {
+                self.word(array)?;
+                self.emit(Op::Len);
+                Ok(Type::WORD)
+            }
+            ("alloc", _) | ("len", _) => Err(Diagnostic::error("incorrect # of arguments passed")),
+            _ => Err(Diagnostic::error(format!(
+                "could not find function `{}`",
+                name
+            ))),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: Operator,
+        lhs: &Expr,
+        rhs: &Expr,
+        expected: Option<&Type>,
+    ) -> Result<Type, Diagnostic> {
+        // a comparison's operands don't have to look like its result
+        let expected = match op {
+            Operator::Les => None,
+            _ => expected,
+        };
+        // an unsuffixed literal takes the type of the other operand, so its
+        // constant is only filled in once that one is compiled
+        let (lhs_ty, lhs_at, rhs_ty) = if let Expr::Number {
+            value, ty: None, ..
+        } = lhs
+        {
+            let at = self.emit(Op::Nop);
+            let rhs_ty = self.expr(rhs, expected)?;
+            let lhs_ty = Type::literal(*value, None, Some(&rhs_ty));
+            self.chunk.code[at] = self.literal(*value, &lhs_ty);
+            (lhs_ty, None, rhs_ty)
+        } else {
+            let lhs_ty = self.expr(lhs, expected)?;
+            let at = self.emit(Op::Nop);
+            let rhs_ty = self.expr(rhs, Some(&lhs_ty))?;
+            (lhs_ty, Some(at), rhs_ty)
+        };
+        let ty = match lhs_ty.unify(&rhs_ty) {
+            Some(ty) if ty.is_numeric() => ty,
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "operator `{}` cannot be applied to `{}` and `{}`",
+                    op, lhs_ty, rhs_ty
+                )))
+            }
+        };
+        match lhs_at {
+            Some(at) => self.chunk.code[at] = self.cast(&lhs_ty, &ty)?,
+            // a literal only ever widens to the type it already has
+            None => debug_assert_eq!(lhs_ty, ty),
+        }
+        self.coerce(&rhs_ty, &ty)?;
+        let num = Num::of(&ty).unwrap();
+        self.emit(match op {
+            Operator::Add => Op::Add(num),
+            Operator::Sub => Op::Sub(num),
+            Operator::Mul => Op::Mul(num),
+            Operator::Pow => Op::Pow(num),
+            Operator::Les => Op::Less(num),
+            // handled by `assign` before the operands are compiled
+            Operator::Assign => unreachable!(),
+        });
+        Ok(match op {
+            Operator::Les => Type::WORD,
+            _ => ty,
+        })
+    }
+
+    fn args(&mut self, args: &[Expr], types: &[Type]) -> Result<(), Diagnostic> {
+        if types.len() != args.len() {
+            return Err(Diagnostic::error("incorrect # of arguments passed"));
+        }
+        for (arg, ty) in args.iter().zip(types) {
+            let arg_ty = self.expr(arg, Some(ty))?;
+            self.coerce(&arg_ty, ty)?;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<Type, Diagnostic> {
+        // a variable holding a function shadows the function of that name
+        if let Some((slot, ty)) = self.lookup(name) {
+            let (arg_types, ret) = match ty {
+                Type::Function(args, ret) => (args, *ret),
+                ty => {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` is a `{}`, not a function",
+                        name, ty
+                    )))
+                }
+            };
+            self.emit(Op::Load(slot));
+            self.args(args, &arg_types)?;
+            self.emit(Op::CallClosure(args.len() as u32));
+            return Ok(ret);
+        }
+        let name = self.resolve_function(name);
+        let index = match self.vm.names.get(&name) {
+            Some(&index) => index,
+            None => return self.builtin(&name, args),
+        };
+        let (arg_types, ret) = self.vm.types.functions[&name].clone();
+        self.args(args, &arg_types)?;
+        self.emit(Op::Call(index as u32, args.len() as u32));
+        Ok(ret)
+    }
+
+    fn condition(
+        &mut self,
+        predicate: &Expr,
+        then: &Expr,
+        other: Option<&Expr>,
+        expected: Option<&Type>,
+    ) -> Result<Type, Diagnostic> {
+        let ty = self.expr(predicate, None)?;
+        self.truth(&ty)?;
+        let skip_then = self.emit(Op::JumpUnless(0));
+        let other = match other {
+            Some(other) => other,
+            // without `else` the value is zero, the `if` only runs for its effects
+            None => {
+                self.expr(then, None)?;
+                self.emit(Op::Pop);
+                self.patch_jump(skip_then);
+                let zero = self.constant(Slot::Int(0));
+                self.emit(zero);
+                return Ok(Type::WORD);
+            }
+        };
+
+        let then_ty = self.expr(then, expected)?;
+        let then_cast = self.emit(Op::Nop);
+        let skip_other = self.emit(Op::Jump(0));
+        self.patch_jump(skip_then);
+        let other_ty = self.expr(other, Some(&then_ty))?;
+        let ty = match then_ty.unify(&other_ty) {
+            Some(ty) => ty,
+            None => {
+                return Err(Diagnostic::error(format!(
+                    "branches of `if` have different types `{}` and `{}`",
+                    then_ty, other_ty
+                )))
+            }
+        };
+        self.coerce(&other_ty, &ty)?;
+        self.chunk.code[then_cast] = self.cast(&then_ty, &ty)?;
+        self.patch_jump(skip_other);
+        Ok(ty)
+    }
+
+    // Leaves the value of `expr` on the stack and returns its type.
+    fn expr(&mut self, expr: &Expr, expected: Option<&Type>) -> Result<Type, Diagnostic> {
+        match expr {
+            Expr::Number { value, ty, .. } => {
+                let ty = Type::literal(*value, *ty, expected);
+                let op = self.literal(*value, &ty);
+                self.emit(op);
+                Ok(ty)
+            }
+            Expr::Float(value) => {
+                let op = self.constant(Slot::F64(*value));
+                self.emit(op);
+                Ok(Type::F64)
+            }
+            Expr::Variable(name) => match self.lookup(name) {
+                Some((slot, ty)) => {
+                    self.emit(Op::Load(slot));
+                    Ok(ty)
+                }
+                None => Err(Diagnostic::error(format!(
+                    "could not find variable `{}`",
+                    name
+                ))),
+            },
+            Expr::Binary {
+                op: Operator::Assign,
+                lhs,
+                rhs,
+            } => self.assign(lhs, rhs),
+            Expr::Binary { op, lhs, rhs } => self.binary(*op, lhs, rhs, expected),
+            Expr::Call { name, args } => self.call(name, args),
+            Expr::Condition {
+                predicate,
+                then,
+                other,
+            } => self.condition(predicate, then, other.as_deref(), expected),
+            Expr::Array(elements) => {
+                for element in elements {
+                    self.word(element)?;
+                }
+                self.emit(Op::Array(elements.len() as u32));
+                Ok(Type::WORD)
+            }
+            Expr::Index { target, index } => {
+                self.word(target)?;
+                self.word(index)?;
+                self.emit(Op::Index);
+                Ok(Type::WORD)
+            }
+            Expr::Struct { name, fields } => self.struct_literal(name, fields),
+            Expr::Field { target, field } => self.field(target, field),
+            Expr::Ref(value) => self.reference(value),
+            Expr::Block(body) => {
+                let last = body.len() - 1;
+                for expr in &body[..last] {
+                    self.expr(expr, None)?;
+                    self.emit(Op::Pop);
+                }
+                self.expr(&body[last], expected)
+            }
+            Expr::Let { name, value, body } => {
+                let ty = self.expr(value, None)?;
+                let slot = self.slot(name);
+                self.emit(Op::Store(slot));
+                self.locals.push((name.clone(), slot, ty));
+                let body = self.expr(body, expected);
+                self.locals.pop();
+                body
+            }
+            Expr::Lambda { args, ret, body } => self.lambda(args, ret, body, expected),
+        }
+    }
+}
@@ -1,36 +1,99 @@
+use llvm::attributes::{Attribute, AttributeLoc};
+use llvm::basic_block::BasicBlock;
 use llvm::builder::Builder;
 use llvm::context::Context;
+use llvm::execution_engine::ExecutionEngine;
 use llvm::module::Module;
 use llvm::passes::PassManager;
-use llvm::types::{BasicMetadataTypeEnum, IntType};
-use llvm::values::{BasicMetadataValueEnum, BasicValue, BasicValueEnum, FunctionValue, IntValue};
-use llvm::{IntPredicate, OptimizationLevel};
+use llvm::targets::TargetData;
+use llvm::types::{
+    BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, IntType, PointerType, StructType,
+};
+use llvm::values::{
+    AsValueRef, BasicMetadataValueEnum, BasicValueEnum, CallSiteValue, CallableValue,
+    FunctionValue, InstructionValue, IntValue, PointerValue,
+};
+use llvm::{AddressSpace, FloatPredicate, IntPredicate, OptimizationLevel};
+use llvm_sys::prelude::LLVMValueRef;
 
 use crate::ast::*;
+use crate::const_fold;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::Lexer;
 use crate::operator::*;
-use crate::parser::*;
-use crate::util::str_from_u8;
+use crate::parser::Parser;
+use crate::resolve;
+use crate::runtime;
+use crate::types::{self, Type, TypeTable};
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str;
 
+extern "C" {
+    // `src/musttail.cpp`, built by `build.rs`
+    fn ks_set_must_tail(call: LLVMValueRef);
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct CodeGenOptions {
+    /// Check `a[i]` against `len(a)` and stop with a runtime error instead
+    /// of reading past the buffer.
+    pub bounds_checks: bool,
+    /// Print the IR of every function once it's optimized, and of every
+    /// top-level expression instead of running it.
+    pub emit_ir: bool,
+}
+
+// An LLVM value together with the language type it was generated for,
+// which LLVM alone can't tell us (signedness, struct field names).
+#[derive(Clone)]
+struct Value<'ctx> {
+    value: BasicValueEnum<'ctx>,
+    ty: Type,
+}
+
 pub struct CodeGen<'ctx, 'a> {
     context: &'ctx Context,
-    parser: &'a mut dyn Iterator<Item = (Function, Vec<u8>)>,
+    parser: &'a mut dyn Iterator<Item = Result<(Item, String, Spans), Diagnostic>>,
     module: &'a Module<'ctx>,
     builder: &'a Builder<'ctx>,
     fpm: &'a PassManager<FunctionValue<'ctx>>,
-    symbol_table: HashMap<Vec<u8>, BasicValueEnum<'ctx>>,
-    parsed_buffer: Vec<u8>,
+    types: &'a mut TypeTable,
+    symbol_table: HashMap<String, Value<'ctx>>,
+    // whether the expression being emitted is the last thing its function
+    // does, so a call there can reuse the caller's frame
+    tail_position: bool,
+    // the `musttail` calls of the function being emitted, each with the
+    // `ret` right after it and the block its caller's code went on in, for
+    // as long as that code might still convert the result
+    tail_calls: Vec<(CallSiteValue<'ctx>, InstructionValue<'ctx>, BasicBlock<'ctx>)>,
+    parsed_buffer: String,
+    options: CodeGenOptions,
+    // the file being compiled, imports are looked up next to it
+    path: PathBuf,
+    // prefix for functions defined here, `math` in `math::sqrt`
+    namespace: Option<String>,
+    // names brought in by `use`, mapped to the full path
+    aliases: HashMap<String, String>,
+    // where the item being compiled and the name it declares are, for the
+    // errors only found while compiling it
+    item_span: Option<Span>,
+    name_span: Option<Span>,
 }
 
 impl<'ctx, 'a> CodeGen<'ctx, 'a> {
     pub fn new(
-        parser: &'a mut dyn Iterator<Item = (Function, Vec<u8>)>,
+        parser: &'a mut dyn Iterator<Item = Result<(Item, String, Spans), Diagnostic>>,
         context: &'ctx Context,
         module: &'a Module<'ctx>,
         builder: &'a Builder<'ctx>,
         fpm: &'a PassManager<FunctionValue<'ctx>>,
+        types: &'a mut TypeTable,
+        options: CodeGenOptions,
     ) -> CodeGen<'ctx, 'a> {
         CodeGen {
             parser,
@@ -38,150 +101,1203 @@ impl<'ctx, 'a> CodeGen<'ctx, 'a> {
             module,
             builder,
             fpm,
+            types,
             symbol_table: HashMap::new(),
-            parsed_buffer: Vec::new(),
+            tail_position: false,
+            tail_calls: Vec::new(),
+            parsed_buffer: String::new(),
+            options,
+            path: PathBuf::new(),
+            namespace: None,
+            aliases: HashMap::new(),
+            item_span: None,
+            name_span: None,
+        }
+    }
+
+    /// Compiles the code as the contents of `path`, putting the functions it
+    /// defines under `namespace::` if one is given.
+    pub fn in_file(mut self, path: &Path, namespace: Option<String>) -> CodeGen<'ctx, 'a> {
+        self.path = path.to_path_buf();
+        self.namespace = namespace;
+        self
+    }
+
+    /// Runs every item, reporting problems against `source` as they come up.
+    pub fn run_to_end(&mut self, path: &str, source: &str) {
+        while let Some(result) = self.emit_and_run() {
+            if let Err(err) = result {
+                eprintln!("{}", err.render(path, source));
+            }
         }
     }
 
-    fn run_anon_fn(&mut self, body: Expr) {
+    fn run_anon_fn(&mut self, body: Expr) -> Result<(), Diagnostic> {
         let proto = Prototype::default();
         let anon_module = self.context.create_module("__anon_module");
-        self.emit_fn_code(proto, body, &anon_module);
+
+        // The result is stored through the pointer we pass in, so a value of
+        // any type can come back without knowing it before the body exists.
+        let out_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let fn_type = self.context.void_type().fn_type(&[out_type.into()], false);
+        let fn_val = anon_module.add_function(&proto.name, fn_type, None);
+        let entry = self.context.append_basic_block(fn_val, "entry");
+        self.builder.position_at_end(entry);
+        self.symbol_table.clear();
+
+        let result = self.emit_value_code(body, None, &fn_val, &anon_module)?;
+        let result_type = self.llvm_type(&result.ty);
+        let out = fn_val.get_first_param().unwrap().into_pointer_value();
+        let out = self.builder.build_pointer_cast(
+            out,
+            result_type.ptr_type(AddressSpace::Generic),
+            "out",
+        );
+        self.builder.build_store(out, result.value);
+        self.builder.build_return(None);
+        self.verify_and_optimize(fn_val);
+        if self.options.emit_ir {
+            return Ok(());
+        }
 
         let ee = anon_module
             .create_jit_execution_engine(OptimizationLevel::None)
             .unwrap();
         ee.add_module(self.module).unwrap();
-        let maybe_fn = unsafe { ee.get_function::<unsafe extern "C" fn() -> usize>("__anon_fn") };
+        register(&ee, &[&anon_module, self.module], &self.types.symbols);
+        let maybe_fn = unsafe { ee.get_function::<unsafe extern "C" fn(*mut u8)>(&proto.name) };
         let compiled_fn = match maybe_fn {
             Ok(f) => f,
             Err(err) => panic!("!> Error during execution: {:?}", err),
         };
-        unsafe {
-            println!(
-                "{} => {}",
-                str_from_u8(&self.parsed_buffer),
-                compiled_fn.call()
-            );
-        }
+        let target_data = ee.get_target_data();
+        let size = target_data.get_abi_size(&result_type) as usize;
+        // u64s keep the buffer aligned for anything a function can return
+        let mut buf = vec![0u64; size.div_ceil(8)];
+        let text = unsafe {
+            compiled_fn.call(buf.as_mut_ptr() as *mut u8);
+            self.format_value(buf.as_ptr() as *const u8, &result.ty, target_data)
+        };
+        println!("{} => {}", self.parsed_buffer, text);
         ee.remove_module(self.module).unwrap();
+        Ok(())
     }
 
-    pub fn emit_and_run(&mut self) -> Option<()> {
-        match self.consume_node() {
-            Some(fun) => Some(match (fun.prototype, fun.body) {
-                (Some(proto), Some(body)) => {
-                    self.emit_fn_code(proto, body, self.module);
-                }
-                (None, Some(body)) => {
-                    self.run_anon_fn(body);
+    unsafe fn format_value(&self, ptr: *const u8, ty: &Type, target_data: &TargetData) -> String {
+        match ty {
+            Type::Int(int) => match int {
+                types::IntType::I8 => (ptr as *const i8).read().to_string(),
+                types::IntType::I16 => (ptr as *const i16).read().to_string(),
+                types::IntType::I32 => (ptr as *const i32).read().to_string(),
+                types::IntType::I64 => (ptr as *const i64).read().to_string(),
+                types::IntType::U8 => ptr.read().to_string(),
+                types::IntType::U16 => (ptr as *const u16).read().to_string(),
+                types::IntType::U32 => (ptr as *const u32).read().to_string(),
+                types::IntType::U64 => (ptr as *const u64).read().to_string(),
+            },
+            Type::F64 => (ptr as *const f64).read().to_string(),
+            Type::Pointer(_) | Type::Function(..) => {
+                format!("{:#x}", (ptr as *const usize).read())
+            }
+            Type::Struct(name) => {
+                let struct_type = self.llvm_type(ty).into_struct_type();
+                let mut fields = Vec::new();
+                for (i, (field, ty)) in self.types.structs[name].iter().enumerate() {
+                    let offset = target_data
+                        .offset_of_element(&struct_type, i as u32)
+                        .unwrap();
+                    let value = self.format_value(ptr.add(offset as usize), ty, target_data);
+                    fields.push(format!("{}: {}", field, value));
                 }
-                (Some(proto), None) => {
-                    self.emit_proto_type(proto.name, proto.args, self.module);
+                format!("{} {{ {} }}", name, fields.join(", "))
+            }
+        }
+    }
+
+    pub fn emit_and_run(&mut self) -> Option<Result<(), Diagnostic>> {
+        let result = match self.consume_node()? {
+            Ok(Item::Struct(def)) => self.emit_struct_type(def),
+            Ok(Item::Import(target)) => self.emit_import(&target),
+            Ok(Item::Use(path)) => self.emit_use(path),
+            Ok(Item::Function(fun)) => match (fun.prototype, fun.body) {
+                (Some(mut proto), Some(body)) => {
+                    // externs keep their name, it's the symbol they link to
+                    if let Some(namespace) = &self.namespace {
+                        proto.name = format!("{}::{}", namespace, proto.name);
+                    }
+                    self.emit_fn_code(proto, body, self.module)
                 }
+                (None, Some(body)) => self.run_anon_fn(body),
+                (Some(proto), None) if proto.library.is_some() => self.emit_native_extern(proto),
+                (Some(proto), None) => self.emit_proto_type(&proto, self.module).map(|_| ()),
                 (None, None) => {
                     panic!("Unsupposed to see a function without nither prototype nor body!")
                 }
-            }),
-            None => None,
+            },
+            Err(err) => Err(err),
+        };
+        // whatever has no more precise place points at the whole item
+        Some(result.map_err(|err| match (err.span, self.item_span) {
+            (None, Some(span)) => err.with_span(span),
+            _ => err,
+        }))
+    }
+
+    fn emit_import(&mut self, target: &str) -> Result<(), Diagnostic> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let path = dir.join(target);
+        let key = match self.types.import_key(&path).map_err(Diagnostic::error)? {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let source = fs::read_to_string(&path)
+            .map_err(|err| Diagnostic::error(format!("could not import `{}`: {}", target, err)))?;
+        let namespace = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        self.types.imported.insert(key.clone());
+        self.types.importing.push(key);
+        let display = path.to_string_lossy().into_owned();
+        let mut lex = Lexer::new(&source);
+        let mut par = Parser::new(&mut lex);
+        CodeGen::new(
+            &mut par,
+            self.context,
+            self.module,
+            self.builder,
+            self.fpm,
+            self.types,
+            self.options,
+        )
+        .in_file(&path, Some(namespace))
+        .run_to_end(&display, &source);
+        self.types.importing.pop();
+        Ok(())
+    }
+
+    fn emit_use(&mut self, path: String) -> Result<(), Diagnostic> {
+        if !self.types.functions.contains_key(&path) {
+            return Err(Diagnostic::error(format!("unresolved import `{}`", path)));
         }
+        let name = path.rsplit("::").next().unwrap().to_string();
+        self.aliases.insert(name, path);
+        Ok(())
     }
 
-    #[inline]
-    fn consume_node(&mut self) -> Option<Function> {
-        match self.parser.next() {
-            Some((fun, buf)) => {
-                self.parsed_buffer = buf;
-                Some(fun)
+    // Functions of this file win over `use`d ones, which win over globals.
+    fn resolve_function(&self, name: &str) -> String {
+        if let Some(namespace) = &self.namespace {
+            let local = format!("{}::{}", namespace, name);
+            if self.types.functions.contains_key(&local) {
+                return local;
             }
-            None => None,
+        }
+        match self.aliases.get(name) {
+            Some(path) => path.clone(),
+            None => name.to_string(),
         }
     }
 
+    #[inline]
+    fn consume_node(&mut self) -> Option<Result<Item, Diagnostic>> {
+        let (item, buf, spans) = match self.parser.next()? {
+            Ok(parsed) => parsed,
+            Err(err) => return Some(Err(err)),
+        };
+        self.parsed_buffer = buf;
+        self.item_span = Some(spans.item);
+        self.name_span = spans.name;
+        let arity = |name: &str| {
+            let name = self.resolve_function(name);
+            self.types.functions.get(&name).map(|(args, _)| args.len())
+        };
+        Some(resolve::resolve_item(&item, &spans, &arity).map(|()| const_fold::fold_item(item)))
+    }
+
     #[inline]
     fn usize_type(&self) -> IntType<'ctx> {
         self.context.i64_type()
         // .ptr_sized_int_type(self.execution_engine.get_target_data(), None)
     }
 
-    fn emit_op_code(
+    #[inline]
+    fn usize_ptr_type(&self) -> PointerType<'ctx> {
+        self.usize_type().ptr_type(AddressSpace::Generic)
+    }
+
+    fn llvm_type(&self, ty: &Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::Int(ty) => self.context.custom_width_int_type(ty.bits()).into(),
+            Type::F64 => self.context.f64_type().into(),
+            Type::Struct(name) => match self.module.get_struct_type(name) {
+                Some(struct_type) => struct_type.into(),
+                None => panic!("Could not find type `{}`", name),
+            },
+            Type::Pointer(ty) => self.llvm_type(ty).ptr_type(AddressSpace::Generic).into(),
+            Type::Function(..) => self.closure_type().into(),
+        }
+    }
+
+    // Every function value is a closure: the code and the environment it
+    // was created with, which the code takes as its first argument.
+    fn closure_type(&self) -> StructType<'ctx> {
+        let byte_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        self.context
+            .struct_type(&[byte_ptr.into(), byte_ptr.into()], false)
+    }
+
+    fn closure_code_type(&self, args: &[Type], ret: &Type) -> FunctionType<'ctx> {
+        let byte_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let args_types = std::iter::once(byte_ptr.into())
+            .chain(args.iter().map(|ty| self.llvm_type(ty).into()))
+            .collect::<Vec<BasicMetadataTypeEnum>>();
+        self.llvm_type(ret).fn_type(&args_types, false)
+    }
+
+    fn emit_closure(
+        &self,
+        code: FunctionValue<'ctx>,
+        env: PointerValue<'ctx>,
+        args: Vec<Type>,
+        ret: Type,
+    ) -> Value<'ctx> {
+        let byte_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let code = self.builder.build_pointer_cast(
+            code.as_global_value().as_pointer_value(),
+            byte_ptr,
+            "code",
+        );
+        let closure = self.closure_type().get_undef();
+        let closure = self
+            .builder
+            .build_insert_value(closure, code, 0, "closure")
+            .unwrap();
+        let closure = self
+            .builder
+            .build_insert_value(closure, env, 1, "closure")
+            .unwrap();
+        Value {
+            value: closure.into_struct_value().into(),
+            ty: Type::Function(args, Box::new(ret)),
+        }
+    }
+
+    fn fn_type(&self, args: &[Type], ret: &Type) -> FunctionType<'ctx> {
+        let args_types = args
+            .iter()
+            .map(|ty| self.llvm_type(ty).into())
+            .collect::<Vec<BasicMetadataTypeEnum>>();
+        self.llvm_type(ret).fn_type(&args_types, false)
+    }
+
+    // Functions live in the preload module, other modules get a declaration.
+    fn get_function(&self, name: &str, module: &Module<'ctx>) -> Option<FunctionValue<'ctx>> {
+        match module.get_function(name) {
+            Some(fn_val) => Some(fn_val),
+            None => self
+                .module
+                .get_function(name)
+                .map(|fn_val| module.add_function(name, fn_val.get_type(), None)),
+        }
+    }
+
+    // An error in the item being compiled, pointing at `span` or at the whole
+    // item if there's nothing more precise.
+    fn error_at(&self, span: Option<Span>, message: String) -> Diagnostic {
+        match span.or(self.item_span) {
+            Some(span) => Diagnostic::error(message).with_span(span),
+            None => Diagnostic::error(message),
+        }
+    }
+
+    fn emit_struct_type(&mut self, def: StructDef) -> Result<(), Diagnostic> {
+        if self.types.structs.contains_key(&def.name) {
+            return Err(self.error_at(
+                self.name_span,
+                format!("struct `{}` is already defined", def.name),
+            ));
+        }
+        self.types
+            .check_fields(&def.name, &def.fields)
+            .map_err(Diagnostic::error)?;
+        // declared before its body so fields can point back at it
+        let struct_type = self.context.opaque_struct_type(&def.name);
+        let field_types: Vec<BasicTypeEnum> = def
+            .fields
+            .iter()
+            .map(|(_, ty)| self.llvm_type(ty))
+            .collect();
+        struct_type.set_body(&field_types, false);
+        self.types.structs.insert(def.name, def.fields);
+        Ok(())
+    }
+
+    fn field_index(&self, name: &str, field: &str) -> Result<(u32, Type), Diagnostic> {
+        let fields = match self.types.structs.get(name) {
+            Some(fields) => fields,
+            None => return Err(self.error_at(None, format!("could not find type `{}`", name))),
+        };
+        match fields.iter().position(|(f, _)| f == field) {
+            Some(i) => Ok((i as u32, fields[i].1.clone())),
+            None => Err(self.error_at(None, format!("`{}` has no field `{}`", name, field))),
+        }
+    }
+
+    // Converts between numeric types; anything else has to match exactly.
+    fn coerce(&self, value: Value<'ctx>, ty: &Type) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        if value.ty == *ty {
+            return Ok(value.value);
+        }
+        Ok(match (&value.ty, ty) {
+            (Type::Int(from), Type::Int(to)) => {
+                let int = value.value.into_int_value();
+                let int_type = self.context.custom_width_int_type(to.bits());
+                if to.bits() < from.bits() {
+                    self.builder.build_int_truncate(int, int_type, "trunc")
+                } else if to.bits() == from.bits() {
+                    int
+                } else if from.is_signed() {
+                    self.builder.build_int_s_extend(int, int_type, "sext")
+                } else {
+                    self.builder.build_int_z_extend(int, int_type, "zext")
+                }
+                .into()
+            }
+            (Type::Int(from), Type::F64) => {
+                let int = value.value.into_int_value();
+                let f64_type = self.context.f64_type();
+                if from.is_signed() {
+                    self.builder
+                        .build_signed_int_to_float(int, f64_type, "itof")
+                } else {
+                    self.builder
+                        .build_unsigned_int_to_float(int, f64_type, "itof")
+                }
+                .into()
+            }
+            (Type::F64, Type::Int(to)) => {
+                let float = value.value.into_float_value();
+                let int_type = self.context.custom_width_int_type(to.bits());
+                if to.is_signed() {
+                    self.builder
+                        .build_float_to_signed_int(float, int_type, "ftoi")
+                } else {
+                    self.builder
+                        .build_float_to_unsigned_int(float, int_type, "ftoi")
+                }
+                .into()
+            }
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "expected a value of type `{}`, but found `{}`",
+                    ty, value.ty
+                )))
+            }
+        })
+    }
+
+    // Everything array related still works on plain words.
+    fn emit_word_code(
+        &mut self,
+        expr: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<IntValue<'ctx>, Diagnostic> {
+        let value = self.emit_value_code(expr, Some(&Type::WORD), parent, module)?;
+        Ok(self.coerce(value, &Type::WORD)?.into_int_value())
+    }
+
+    fn bool_to_word(&self, value: IntValue<'ctx>) -> Value<'ctx> {
+        Value {
+            value: self
+                .builder
+                .build_int_z_extend(value, self.usize_type(), "booltmp")
+                .into(),
+            ty: Type::WORD,
+        }
+    }
+
+    fn emit_truth_code(&self, value: Value<'ctx>) -> Result<IntValue<'ctx>, Diagnostic> {
+        Ok(match value.ty {
+            Type::Int(_) => {
+                let int = value.value.into_int_value();
+                let zero = int.get_type().const_zero();
+                self.builder
+                    .build_int_compare(IntPredicate::NE, int, zero, "cond")
+            }
+            Type::F64 => {
+                let float = value.value.into_float_value();
+                let zero = self.context.f64_type().const_zero();
+                self.builder
+                    .build_float_compare(FloatPredicate::ONE, float, zero, "cond")
+            }
+            ty => {
+                return Err(Diagnostic::error(format!(
+                    "expected a number as condition, but found `{}`",
+                    ty
+                )))
+            }
+        })
+    }
+
+    fn declare_function(
+        &self,
+        name: &str,
+        fn_type: FunctionType<'ctx>,
+        module: &Module<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        match module.get_function(name) {
+            Some(fn_val) => fn_val,
+            None => module.add_function(name, fn_type, None),
+        }
+    }
+
+    fn emit_calloc(
+        &self,
+        count: IntValue<'ctx>,
+        size: IntValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> PointerValue<'ctx> {
+        let byte_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let calloc_type = byte_ptr.fn_type(&[self.usize_type().into(); 2], false);
+        let calloc = self.declare_function("calloc", calloc_type, module);
+        self.builder
+            .build_call(calloc, &[count.into(), size.into()], "raw")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    // Arrays are a length word followed by the elements, handed around as a
+    // pointer-sized integer like every other value.
+    fn emit_array_alloc(&self, count: IntValue<'ctx>, module: &Module<'ctx>) -> PointerValue<'ctx> {
+        let raw = self.emit_calloc(count, self.usize_type().size_of(), module);
+        self.builder
+            .build_pointer_cast(raw, self.usize_ptr_type(), "header")
+    }
+
+    fn emit_alloc_code(
+        &mut self,
+        len: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<IntValue<'ctx>, Diagnostic> {
+        let len = self.emit_word_code(len, parent, module)?;
+        let one = self.usize_type().const_int(1, false);
+        let count = self.builder.build_int_add(len, one, "count");
+        let header = self.emit_array_alloc(count, module);
+        self.builder.build_store(header, len);
+        Ok(self
+            .builder
+            .build_ptr_to_int(header, self.usize_type(), "array"))
+    }
+
+    fn emit_array_code(
+        &mut self,
+        elements: Vec<Expr>,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<IntValue<'ctx>, Diagnostic> {
+        let len = elements.len() as u32;
+        let array_type = self.context.struct_type(
+            &[
+                self.usize_type().into(),
+                self.usize_type().array_type(len).into(),
+            ],
+            false,
+        );
+        let count = self.usize_type().const_int(len as u64 + 1, false);
+        let header = self.emit_array_alloc(count, module);
+        let array = self.builder.build_pointer_cast(
+            header,
+            array_type.ptr_type(AddressSpace::Generic),
+            "fixed",
+        );
+        let len_ptr = self.builder.build_struct_gep(array, 0, "len").unwrap();
+        self.builder
+            .build_store(len_ptr, self.usize_type().const_int(len as u64, false));
+        let data = self.builder.build_struct_gep(array, 1, "data").unwrap();
+        let zero = self.usize_type().const_zero();
+        for (i, element) in elements.into_iter().enumerate() {
+            let value = self.emit_word_code(element, parent, module)?;
+            let i = self.usize_type().const_int(i as u64, false);
+            let slot = unsafe { self.builder.build_in_bounds_gep(data, &[zero, i], "slot") };
+            self.builder.build_store(slot, value);
+        }
+        Ok(self
+            .builder
+            .build_ptr_to_int(header, self.usize_type(), "array"))
+    }
+
+    fn emit_len_code(
+        &mut self,
+        array: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<IntValue<'ctx>, Diagnostic> {
+        let array = self.emit_word_code(array, parent, module)?;
+        let header = self
+            .builder
+            .build_int_to_ptr(array, self.usize_ptr_type(), "header");
+        Ok(self.builder.build_load(header, "len").into_int_value())
+    }
+
+    fn emit_bounds_check(
+        &self,
+        header: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) {
+        let len = self.builder.build_load(header, "len").into_int_value();
+        // negative indices wrap around and fail the same unsigned compare
+        let in_bounds = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, index, len, "inbounds");
+        let fail = self.context.append_basic_block(*parent, "out_of_bounds");
+        let ok = self.context.append_basic_block(*parent, "in_bounds");
+        self.builder.build_conditional_branch(in_bounds, ok, fail);
+
+        self.builder.position_at_end(fail);
+        let fail_type = self
+            .context
+            .void_type()
+            .fn_type(&[self.usize_type().into(); 2], false);
+        let bounds_fail = self.declare_function(runtime::BOUNDS_FAIL, fail_type, module);
+        self.builder
+            .build_call(bounds_fail, &[index.into(), len.into()], "");
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(ok);
+    }
+
+    fn emit_element_ptr(
+        &mut self,
+        target: Expr,
+        index: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<PointerValue<'ctx>, Diagnostic> {
+        let array = self.emit_word_code(target, parent, module)?;
+        let index = self.emit_word_code(index, parent, module)?;
+        let header = self
+            .builder
+            .build_int_to_ptr(array, self.usize_ptr_type(), "header");
+        if self.options.bounds_checks {
+            self.emit_bounds_check(header, index, parent, module);
+        }
+        let one = self.usize_type().const_int(1, false);
+        let offset = self.builder.build_int_add(index, one, "offset");
+        Ok(unsafe { self.builder.build_gep(header, &[offset], "element") })
+    }
+
+    fn field_ptr(
+        &self,
+        target: Value<'ctx>,
+        field: &str,
+    ) -> Result<(PointerValue<'ctx>, Type), Diagnostic> {
+        let name = match &target.ty {
+            Type::Pointer(inner) => match inner.as_ref() {
+                Type::Struct(name) => name,
+                _ => {
+                    return Err(
+                        self.error_at(None, format!("`{}` has no field `{}`", target.ty, field))
+                    )
+                }
+            },
+            Type::Struct(_) => {
+                return Err(self.error_at(
+                    None,
+                    format!(
+                        "fields of `{}` can only be changed through a pointer, try `&`",
+                        target.ty
+                    ),
+                ))
+            }
+            _ => {
+                return Err(self.error_at(None, format!("`{}` has no field `{}`", target.ty, field)))
+            }
+        };
+        let (index, ty) = self.field_index(name, field)?;
+        let ptr = self
+            .builder
+            .build_struct_gep(target.value.into_pointer_value(), index, field)
+            .unwrap();
+        Ok((ptr, ty))
+    }
+
+    fn emit_assign_code(
+        &mut self,
+        target: Expr,
+        value: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        let (ptr, ty) = match target {
+            Expr::Index { target, index } => (
+                self.emit_element_ptr(*target, *index, parent, module)?,
+                Type::WORD,
+            ),
+            Expr::Field { target, field } => {
+                let target = self.emit_value_code(*target, None, parent, module)?;
+                self.field_ptr(target, &field)?
+            }
+            _ => {
+                return Err(Diagnostic::error(
+                    "can only assign to an array element or a field",
+                ))
+            }
+        };
+        let value = self.emit_value_code(value, Some(&ty), parent, module)?;
+        let value = self.coerce(value, &ty)?;
+        self.builder.build_store(ptr, value);
+        Ok(Value { value, ty })
+    }
+
+    fn emit_struct_code(
+        &mut self,
+        name: String,
+        fields: Vec<(String, Expr)>,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        if !self.types.structs.contains_key(&name) {
+            return Err(self.error_at(None, format!("could not find type `{}`", name)));
+        }
+        let ty = Type::Struct(name);
+        let struct_type = self.llvm_type(&ty).into_struct_type();
+        let mut aggregate = struct_type.get_undef();
+        let mut initialized = vec![false; struct_type.count_fields() as usize];
+        for (field, value) in fields {
+            let (index, field_type) = self.field_index(&ty.to_string(), &field)?;
+            if initialized[index as usize] {
+                return Err(self.error_at(None, format!("field `{}` is initialized twice", field)));
+            }
+            initialized[index as usize] = true;
+            let value = self.emit_value_code(value, Some(&field_type), parent, module)?;
+            let value = self.coerce(value, &field_type)?;
+            aggregate = self
+                .builder
+                .build_insert_value(aggregate, value, index, &field)
+                .unwrap()
+                .into_struct_value();
+        }
+        if let Some(i) = initialized.iter().position(|done| !done) {
+            let fields = &self.types.structs[&ty.to_string()];
+            return Err(self.error_at(None, format!("missing field `{}` in `{}`", fields[i].0, ty)));
+        }
+        Ok(Value {
+            value: aggregate.into(),
+            ty,
+        })
+    }
+
+    fn emit_field_code(
+        &mut self,
+        target: Expr,
+        field: String,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        let target = self.emit_value_code(target, None, parent, module)?;
+        if let Type::Struct(name) = &target.ty {
+            let (index, ty) = self.field_index(name, &field)?;
+            let value = self
+                .builder
+                .build_extract_value(target.value.into_struct_value(), index, &field)
+                .unwrap();
+            return Ok(Value { value, ty });
+        }
+        let (ptr, ty) = self.field_ptr(target, &field)?;
+        Ok(Value {
+            value: self.builder.build_load(ptr, &field),
+            ty,
+        })
+    }
+
+    // `&value` moves the value to the heap, so the pointer stays good after
+    // the function that took it returns.
+    fn emit_ref_code(
+        &mut self,
+        value: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        // `&square` takes the address of a function instead
+        if let Expr::Variable(name) = &value {
+            if !self.symbol_table.contains_key(name) {
+                return Ok(self.emit_fn_ref_code(name, module));
+            }
+        }
+        let value = self.emit_value_code(value, None, parent, module)?;
+        let value_type = self.llvm_type(&value.ty);
+        let one = self.usize_type().const_int(1, false);
+        let raw = self.emit_calloc(one, value_type.size_of().unwrap(), module);
+        let ptr =
+            self.builder
+                .build_pointer_cast(raw, value_type.ptr_type(AddressSpace::Generic), "ref");
+        self.builder.build_store(ptr, value.value);
+        Ok(Value {
+            value: ptr.into(),
+            ty: Type::Pointer(Box::new(value.ty)),
+        })
+    }
+
+    // A named function becomes a closure through a thunk that drops the
+    // environment argument and calls the real thing.
+    fn emit_fn_ref_code(&self, name: &str, module: &Module<'ctx>) -> Value<'ctx> {
+        let name = &self.resolve_function(name);
+        let (fn_val, (args, ret)) = match (
+            self.get_function(name, module),
+            self.types.functions.get(name),
+        ) {
+            (Some(fn_val), Some(signature)) => (fn_val, signature.clone()),
+            _ => panic!("Could not find function `{}`", name),
+        };
+        let thunk_name = format!("{}.closure", name);
+        let thunk = match module.get_function(&thunk_name) {
+            Some(thunk) => thunk,
+            None => {
+                let thunk_type = self.closure_code_type(&args, &ret);
+                let thunk = module.add_function(&thunk_name, thunk_type, None);
+                let outer = self.builder.get_insert_block().unwrap();
+                let entry = self.context.append_basic_block(thunk, "entry");
+                self.builder.position_at_end(entry);
+                let argsv: Vec<BasicMetadataValueEnum> = thunk
+                    .get_param_iter()
+                    .skip(1)
+                    .map(|arg| arg.into())
+                    .collect();
+                let call = self.builder.build_call(fn_val, &argsv, "tmp");
+                call.set_tail_call(true);
+                let value = call.try_as_basic_value().left().unwrap();
+                self.builder.build_return(Some(&value));
+                self.verify_and_optimize(thunk);
+                self.builder.position_at_end(outer);
+                thunk
+            }
+        };
+        let byte_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        self.emit_closure(thunk, byte_ptr.const_null(), args, ret)
+    }
+
+    // The lambda's body becomes a function of its own; whatever it uses from
+    // the enclosing scope is copied into a heap environment when it's created.
+    fn emit_lambda_code(
+        &mut self,
+        args: Vec<(String, Option<Type>)>,
+        ret: Option<Type>,
+        body: Expr,
+        expected: Option<&Type>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        // unannotated parts take their types from where the lambda is used
+        let (arg_types, ret) = types::lambda_signature(&args, &ret, expected);
+        self.types
+            .check_types(arg_types.iter().chain(Some(&ret)))
+            .map_err(Diagnostic::error)?;
+
+        let params: Vec<String> = args.into_iter().map(|(name, _)| name).collect();
+        let captures: Vec<(String, Value<'ctx>)> = body
+            .free_variables(&params)
+            .into_iter()
+            .filter_map(|name| {
+                let value = self.symbol_table.get(&name)?.clone();
+                Some((name, value))
+            })
+            .collect();
+        let capture_types: Vec<BasicTypeEnum> = captures
+            .iter()
+            .map(|(_, value)| self.llvm_type(&value.ty))
+            .collect();
+        let env_type = self.context.struct_type(&capture_types, false);
+        let env_ptr_type = env_type.ptr_type(AddressSpace::Generic);
+
+        let code_type = self.closure_code_type(&arg_types, &ret);
+        let code = module.add_function("lambda", code_type, None);
+        let outer_block = self.builder.get_insert_block().unwrap();
+        let outer_symbols = std::mem::take(&mut self.symbol_table);
+        let outer_tail_calls = std::mem::take(&mut self.tail_calls);
+
+        let entry = self.context.append_basic_block(code, "entry");
+        self.builder.position_at_end(entry);
+        let env = code.get_first_param().unwrap().into_pointer_value();
+        let env = self.builder.build_pointer_cast(env, env_ptr_type, "env");
+        for (i, (name, value)) in captures.iter().enumerate() {
+            let ptr = self.builder.build_struct_gep(env, i as u32, name).unwrap();
+            let captured = Value {
+                value: self.builder.build_load(ptr, name),
+                ty: value.ty.clone(),
+            };
+            self.symbol_table.insert(name.clone(), captured);
+        }
+        for ((name, arg), ty) in params
+            .into_iter()
+            .zip(code.get_param_iter().skip(1))
+            .zip(arg_types.clone())
+        {
+            self.symbol_table.insert(name, Value { value: arg, ty });
+        }
+        self.tail_position = true;
+        let value = self
+            .emit_value_code(body, Some(&ret), &code, module)
+            .and_then(|value| self.coerce_result(value, &ret));
+        let value = match value {
+            Ok(value) => value,
+            // the function it's in goes away too, but this one would stay
+            // behind in the module half built
+            Err(err) => {
+                unsafe { code.delete() };
+                return Err(err);
+            }
+        };
+        self.builder.build_return(Some(&value));
+        self.verify_and_optimize(code);
+
+        self.symbol_table = outer_symbols;
+        self.tail_calls = outer_tail_calls;
+        self.builder.position_at_end(outer_block);
+
+        let byte_ptr = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let env = if captures.is_empty() {
+            byte_ptr.const_null()
+        } else {
+            let one = self.usize_type().const_int(1, false);
+            let raw = self.emit_calloc(one, env_type.size_of().unwrap(), module);
+            let env = self.builder.build_pointer_cast(raw, env_ptr_type, "env");
+            for (i, (name, value)) in captures.into_iter().enumerate() {
+                let ptr = self.builder.build_struct_gep(env, i as u32, &name).unwrap();
+                self.builder.build_store(ptr, value.value);
+            }
+            raw
+        };
+        Ok(self.emit_closure(code, env, arg_types, ret))
+    }
+
+    fn emit_builtin_code(
+        &mut self,
+        name: &str,
+        mut args: Vec<Expr>,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        let value = match (name, args.len()) {
+            ("alloc", 1) => self.emit_alloc_code(args.remove(0), parent, module)?,
+            ("len", 1) => self.emit_len_code(args.remove(0), parent, module)?,
+            ("alloc", _) | ("len", _) => {
+                return Err(Diagnostic::error("incorrect # of arguments passed"))
+            }
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "could not find function `{}`",
+                    name
+                )))
+            }
+        };
+        Ok(Value {
+            value: value.into(),
+            ty: Type::WORD,
+        })
+    }
+
+    // Integer `**` goes through the runtime on words, narrower types are
+    // zero-extended there and truncated back.
+    fn emit_int_pow(
         &self,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> IntValue<'ctx> {
+        let word = self.usize_type();
+        let pow_type = word.fn_type(&[word.into(); 2], false);
+        let pow = self.declare_function(runtime::POW_INT, pow_type, module);
+        let base = self
+            .builder
+            .build_int_z_extend_or_bit_cast(lhs, word, "base");
+        let exponent = self
+            .builder
+            .build_int_z_extend_or_bit_cast(rhs, word, "exponent");
+        let power = self
+            .builder
+            .build_call(pow, &[base.into(), exponent.into()], "tmppow")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        self.builder
+            .build_int_truncate_or_bit_cast(power, lhs.get_type(), "tmppow")
+    }
+
+    fn emit_op_code(
+        &mut self,
         op: Operator,
         left: Expr,
         right: Expr,
-        parent: &FunctionValue,
+        expected: Option<&Type>,
+        parent: &FunctionValue<'ctx>,
         module: &Module<'ctx>,
-    ) -> IntValue<'ctx> {
-        let lhs = self.emit_value_code(left, parent, module);
-        let rhs = self.emit_value_code(right, parent, module);
-        match op {
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        // a comparison's operands don't have to look like its result
+        let expected = match op {
+            Operator::Les => None,
+            _ => expected,
+        };
+        // an unsuffixed literal takes the type of the other operand
+        let (lhs, rhs) = if let Expr::Number { ty: None, .. } = left {
+            let rhs = self.emit_value_code(right, expected, parent, module)?;
+            let lhs = self.emit_value_code(left, Some(&rhs.ty), parent, module)?;
+            (lhs, rhs)
+        } else {
+            let lhs = self.emit_value_code(left, expected, parent, module)?;
+            let rhs = self.emit_value_code(right, Some(&lhs.ty), parent, module)?;
+            (lhs, rhs)
+        };
+        let ty = match lhs.ty.unify(&rhs.ty) {
+            Some(ty) if ty.is_numeric() => ty,
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "operator `{}` cannot be applied to `{}` and `{}`",
+                    op, lhs.ty, rhs.ty
+                )))
+            }
+        };
+        let lhs = self.coerce(lhs, &ty)?;
+        let rhs = self.coerce(rhs, &ty)?;
+
+        if let Type::F64 = ty {
+            let (lhs, rhs) = (lhs.into_float_value(), rhs.into_float_value());
+            let value = match op {
+                Operator::Add => self.builder.build_float_add(lhs, rhs, "tmpadd"),
+                Operator::Sub => self.builder.build_float_sub(lhs, rhs, "tmpsub"),
+                Operator::Mul => self.builder.build_float_mul(lhs, rhs, "tmpmul"),
+                Operator::Pow => {
+                    let f64_type = self.context.f64_type();
+                    let pow_type = f64_type.fn_type(&[f64_type.into(); 2], false);
+                    let pow = self.declare_function(runtime::POW_F64, pow_type, module);
+                    self.builder
+                        .build_call(pow, &[lhs.into(), rhs.into()], "tmppow")
+                        .try_as_basic_value()
+                        .left()
+                        .unwrap()
+                        .into_float_value()
+                }
+                Operator::Les => {
+                    return Ok(self.bool_to_word(self.builder.build_float_compare(
+                        FloatPredicate::OLT,
+                        lhs,
+                        rhs,
+                        "tmpcmp",
+                    )))
+                }
+                // handled by `emit_assign_code` before the operands are evaluated
+                Operator::Assign => unreachable!(),
+            };
+            return Ok(Value {
+                value: value.into(),
+                ty,
+            });
+        }
+
+        let (lhs, rhs) = (lhs.into_int_value(), rhs.into_int_value());
+        let value = match op {
             Operator::Add => self.builder.build_int_add(lhs, rhs, "tmpadd"),
             Operator::Sub => self.builder.build_int_sub(lhs, rhs, "tmpsub"),
             Operator::Mul => self.builder.build_int_mul(lhs, rhs, "tmpmul"),
-            Operator::Les => self
-                .builder
-                .build_int_compare(IntPredicate::ULT, lhs, rhs, "tmpcmp"),
+            Operator::Pow => self.emit_int_pow(lhs, rhs, module),
+            Operator::Les => {
+                let predicate = match ty {
+                    Type::Int(int) if int.is_signed() => IntPredicate::SLT,
+                    _ => IntPredicate::ULT,
+                };
+                return Ok(self.bool_to_word(
+                    self.builder
+                        .build_int_compare(predicate, lhs, rhs, "tmpcmp"),
+                ));
+            }
+            // handled by `emit_assign_code` before the operands are evaluated
+            Operator::Assign => unreachable!(),
             // _ => panic!("Operator not supported "),
-        }
+        };
+        Ok(Value {
+            value: value.into(),
+            ty,
+        })
     }
 
     fn emit_call_code(
-        &self,
+        &mut self,
         name: &str,
         args: Vec<Expr>,
-        parent: &FunctionValue,
+        tail: bool,
+        parent: &FunctionValue<'ctx>,
         module: &Module<'ctx>,
-    ) -> IntValue<'ctx> {
-        let fn_val = match module.get_function(name) {
-            Some(fn_val) => fn_val,
-            None => match self.module.get_function(name) {
-                Some(fn_val) => module.add_function(name, fn_val.get_type(), None),
-                None => panic!("Could not find function `{}`", name),
-            },
-        };
-
-        if fn_val.count_params() as usize != args.len() {
-            panic!("Incorrect # of arguments passed");
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        // whether it's the script's own function named like one of C's
+        let mut shadows_c = false;
+        // a variable holding a function shadows the function of that name
+        let (callee, callee_type, call_conv, env, arg_types, ret) =
+            match self.symbol_table.get(name) {
+                Some(Value {
+                    value,
+                    ty: Type::Function(args, ret),
+                }) => {
+                    let closure = value.into_struct_value();
+                    let code = self
+                        .builder
+                        .build_extract_value(closure, 0, "code")
+                        .unwrap();
+                    let env = self.builder.build_extract_value(closure, 1, "env").unwrap();
+                    let code_type = self.closure_code_type(args, ret);
+                    let code = self.builder.build_pointer_cast(
+                        code.into_pointer_value(),
+                        code_type.ptr_type(AddressSpace::Generic),
+                        "code",
+                    );
+                    let callee = CallableValue::try_from(code).unwrap();
+                    (
+                        callee,
+                        code_type,
+                        None,
+                        Some(env),
+                        args.clone(),
+                        *ret.clone(),
+                    )
+                }
+                Some(var) => {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` is a `{}`, not a function",
+                        name, var.ty
+                    )))
+                }
+                None => {
+                    let name = &self.resolve_function(name);
+                    let fn_val = match self.get_function(name, module) {
+                        Some(fn_val) => fn_val,
+                        None => return self.emit_builtin_code(name, args, parent, module),
+                    };
+                    shadows_c = self
+                        .module
+                        .get_function(name)
+                        .is_some_and(|fn_val| fn_val.count_basic_blocks() > 0)
+                        && runtime::find_symbol(name).is_ok();
+                    let (arg_types, ret) = match self.types.functions.get(name) {
+                        Some(signature) => signature.clone(),
+                        None => {
+                            return Err(Diagnostic::error(format!(
+                                "could not find function `{}`",
+                                name
+                            )))
+                        }
+                    };
+                    let call_conv = Some(fn_val.get_call_conventions());
+                    let callee_type = fn_val.get_type();
+                    (
+                        CallableValue::from(fn_val),
+                        callee_type,
+                        call_conv,
+                        None,
+                        arg_types,
+                        ret,
+                    )
+                }
+            };
+        if arg_types.len() != args.len() {
+            return Err(Diagnostic::error("incorrect # of arguments passed"));
         }
 
         let mut compiled_args = Vec::with_capacity(args.len());
-        for arg in args {
-            compiled_args.push(self.emit_value_code(arg, parent, module));
+        for (arg, ty) in args.into_iter().zip(&arg_types) {
+            let value = self.emit_value_code(arg, Some(ty), parent, module)?;
+            compiled_args.push(self.coerce(value, ty)?);
         }
 
-        let argsv: Vec<BasicMetadataValueEnum> = compiled_args
+        let argsv: Vec<BasicMetadataValueEnum> = env
             .iter()
-            .by_ref()
+            .chain(compiled_args.iter())
             .map(|&val| val.into())
             .collect();
 
-        match self
-            .builder
-            .build_call(fn_val, argsv.as_slice(), "tmp")
-            .try_as_basic_value()
-            .left()
-        {
-            Some(value) => value.into_int_value(),
+        let call = self.builder.build_call(callee, argsv.as_slice(), "tmp");
+        // closures are called with the default, C convention
+        let call_conv = call_conv.unwrap_or(0);
+        call.set_call_convention(call_conv);
+        // LLVM knows what C's `labs` and the like do and folds calls to them
+        // by name, which isn't what the script's `labs` does
+        if shadows_c {
+            let nobuiltin = Attribute::get_named_enum_kind_id("nobuiltin");
+            call.add_attribute(
+                AttributeLoc::Function,
+                self.context.create_enum_attribute(nobuiltin, 0),
+            );
+        }
+        let value = match call.try_as_basic_value().left() {
+            Some(value) => value,
             None => panic!("Invalid call produced."),
+        };
+        // Only a call whose result is returned as is can be a tail call,
+        // otherwise the caller still has work to do after it. Its arguments
+        // go where the caller's were, so it has to take and return the same,
+        // the same way; every function the JIT defines has the C convention,
+        // so any two that match in type do. Nothing the JIT allocates is on
+        // the stack, so the callee can't be handed the caller's frame.
+        if tail && callee_type == parent.get_type() && call_conv == parent.get_call_conventions() {
+            // `musttail` has to be followed by its `ret`, the code the caller
+            // goes on with is never run unless `demote_tail_calls` says so
+            unsafe { ks_set_must_tail(call.as_value_ref()) };
+            let exit = self.builder.build_return(Some(&value));
+            let rest = self.context.append_basic_block(*parent, "rest");
+            self.builder.position_at_end(rest);
+            self.tail_calls.push((call, exit, rest));
+        }
+        Ok(Value { value, ty: ret })
+    }
+
+    // Makes the `musttail` calls in `calls` plain calls again, going on to
+    // the code after them, once their result turns out to be converted on
+    // its way out.
+    fn demote_tail_calls(&mut self, calls: Range<usize>) {
+        let block = self.builder.get_insert_block().unwrap();
+        for (call, exit, rest) in self.tail_calls.drain(calls) {
+            let call_block = exit.get_parent().unwrap();
+            exit.erase_from_basic_block();
+            call.set_tail_call(true);
+            self.builder.position_at_end(call_block);
+            self.builder.build_unconditional_branch(rest);
         }
+        self.builder.position_at_end(block);
+    }
+
+    // The result of a function body converted to what the function returns.
+    fn coerce_result(
+        &mut self,
+        value: Value<'ctx>,
+        ty: &Type,
+    ) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        if value.ty != *ty {
+            self.demote_tail_calls(0..self.tail_calls.len());
+        }
+        self.coerce(value, ty)
     }
 
     fn emit_condition_code(
-        &self,
+        &mut self,
         predicate: Expr,
         consequence: Expr,
         alternative: Expr,
-        parent: &FunctionValue,
+        expected: Option<&Type>,
+        parent: &FunctionValue<'ctx>,
         module: &Module<'ctx>,
-    ) -> IntValue<'ctx> {
+    ) -> Result<Value<'ctx>, Diagnostic> {
         // entry
-        let cond = self.emit_value_code(predicate, parent, module);
-        let zero_const = self.context.bool_type().const_int(0, false);
+        let tail = self.tail_position;
+        let cond = self.emit_value_code(predicate, None, parent, module)?;
 
         //blocks
-        let cond = self
-            .builder
-            .build_int_compare(IntPredicate::NE, cond, zero_const, "cond");
+        let cond = self.emit_truth_code(cond)?;
         let then = self.context.append_basic_block(*parent, "then");
         let other = self.context.append_basic_block(*parent, "other");
         let merge = self.context.append_basic_block(*parent, "merge");
@@ -189,123 +1305,391 @@ impl<'ctx, 'a> CodeGen<'ctx, 'a> {
 
         // then
         self.builder.position_at_end(then);
-        let then_val = self.emit_value_code(consequence, parent, module);
-        self.builder.build_unconditional_branch(merge);
+        self.tail_position = tail;
+        let then_calls = self.tail_calls.len();
+        let then_val = self.emit_value_code(consequence, expected, parent, module)?;
+        let then_branch = self.builder.build_unconditional_branch(merge);
 
         let then = self.builder.get_insert_block().unwrap();
 
         // build else block
         self.builder.position_at_end(other);
-        let other_val = self.emit_value_code(alternative, parent, module);
+        self.tail_position = tail;
+        let other_calls = self.tail_calls.len();
+        let other_val = self.emit_value_code(alternative, Some(&then_val.ty), parent, module)?;
+        let ty = match then_val.ty.unify(&other_val.ty) {
+            Some(ty) => ty,
+            None => {
+                return Err(Diagnostic::error(format!(
+                    "branches of `if` have different types `{}` and `{}`",
+                    then_val.ty, other_val.ty
+                )))
+            }
+        };
+        // a branch converted to the type of the `if` isn't returned as is
+        if other_val.ty != ty {
+            self.demote_tail_calls(other_calls..self.tail_calls.len());
+        }
+        if then_val.ty != ty {
+            self.demote_tail_calls(then_calls..other_calls);
+        }
+        let other_val = self.coerce(other_val, &ty)?;
         self.builder.build_unconditional_branch(merge);
 
         let other = self.builder.get_insert_block().unwrap();
 
+        // the then branch may need widening too, before it leaves its block
+        self.builder.position_before(&then_branch);
+        let then_val = self.coerce(then_val, &ty)?;
+
         // emit merge block
         self.builder.position_at_end(merge);
 
-        let phi = self.builder.build_phi(self.usize_type(), "iftmp");
+        let phi = self.builder.build_phi(self.llvm_type(&ty), "iftmp");
 
         phi.add_incoming(&[(&then_val, then), (&other_val, other)]);
 
-        phi.as_basic_value().into_int_value()
+        Ok(Value {
+            value: phi.as_basic_value(),
+            ty,
+        })
+    }
+
+    // `if` without `else`: nothing to merge, so no phi and no `other` block
+    fn emit_when_code(
+        &mut self,
+        predicate: Expr,
+        consequence: Expr,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        let cond = self.emit_value_code(predicate, None, parent, module)?;
+        let cond = self.emit_truth_code(cond)?;
+        let then = self.context.append_basic_block(*parent, "then");
+        let merge = self.context.append_basic_block(*parent, "merge");
+        self.builder.build_conditional_branch(cond, then, merge);
+
+        self.builder.position_at_end(then);
+        self.emit_value_code(consequence, None, parent, module)?;
+        self.builder.build_unconditional_branch(merge);
+
+        self.builder.position_at_end(merge);
+        Ok(Value {
+            value: self.usize_type().const_zero().into(),
+            ty: Type::WORD,
+        })
     }
 
     fn emit_value_code(
-        &self,
+        &mut self,
         expr: Expr,
-        parent: &FunctionValue,
+        expected: Option<&Type>,
+        parent: &FunctionValue<'ctx>,
         module: &Module<'ctx>,
-    ) -> IntValue<'ctx> {
-        match expr {
-            Expr::Number(value) => self
-                .usize_type()
-                .const_int(value.try_into().unwrap(), false),
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        // sub-expressions are never in tail position unless their parent says
+        // so; `if`, blocks and `let` pick the flag back up for their last part
+        let tail = std::mem::replace(&mut self.tail_position, false);
+        Ok(match expr {
+            // the lexer already checked the value fits its suffix
+            Expr::Number { value, ty, .. } => {
+                let ty = Type::literal(value, ty, expected);
+                let value = match ty {
+                    Type::F64 => self.context.f64_type().const_float(value as f64).into(),
+                    _ => self
+                        .llvm_type(&ty)
+                        .into_int_type()
+                        .const_int(value, false)
+                        .into(),
+                };
+                Value { value, ty }
+            }
+            Expr::Float(value) => Value {
+                value: self.context.f64_type().const_float(value).into(),
+                ty: Type::F64,
+            },
             Expr::Variable(name) => match self.symbol_table.get(&name) {
-                Some(var) => var.into_int_value(),
+                Some(var) => var.clone(),
                 // self
                 //     .builder
                 //     .build_load(
                 //         (*var).into_pointer_value(),
-                //         str_from_u8(name),
+                //         &name,
                 //     )
                 //     .into_int_value(),
-                None => panic!(
-                    "Could not find variable `{}` in symbol table",
-                    str_from_u8(&name)
-                ),
+                None => {
+                    return Err(Diagnostic::error(format!(
+                        "could not find variable `{}`",
+                        name
+                    )))
+                }
             },
-            Expr::Binary { op, lhs, rhs } => self.emit_op_code(op, *lhs, *rhs, parent, module),
-            Expr::Call { name, args } => {
-                self.emit_call_code(str_from_u8(&name), args, parent, module)
+            Expr::Binary {
+                op: Operator::Assign,
+                lhs,
+                rhs,
+            } => self.emit_assign_code(*lhs, *rhs, parent, module)?,
+            Expr::Binary { op, lhs, rhs } => {
+                self.emit_op_code(op, *lhs, *rhs, expected, parent, module)?
             }
+            Expr::Call { name, args } => self.emit_call_code(&name, args, tail, parent, module)?,
             Expr::Condition {
                 predicate,
                 then,
                 other,
-            } => self.emit_condition_code(*predicate, *then, *other, parent, module),
+            } => match other {
+                Some(other) => {
+                    self.tail_position = tail;
+                    self.emit_condition_code(*predicate, *then, *other, expected, parent, module)?
+                }
+                None => self.emit_when_code(*predicate, *then, parent, module)?,
+            },
+            Expr::Array(elements) => Value {
+                value: self.emit_array_code(elements, parent, module)?.into(),
+                ty: Type::WORD,
+            },
+            Expr::Index { target, index } => {
+                let ptr = self.emit_element_ptr(*target, *index, parent, module)?;
+                Value {
+                    value: self.builder.build_load(ptr, "element"),
+                    ty: Type::WORD,
+                }
+            }
+            Expr::Struct { name, fields } => self.emit_struct_code(name, fields, parent, module)?,
+            Expr::Field { target, field } => {
+                self.emit_field_code(*target, field, parent, module)?
+            }
+            Expr::Ref(value) => self.emit_ref_code(*value, parent, module)?,
+            Expr::Block(body) => {
+                self.tail_position = tail;
+                self.emit_block_code(body, expected, parent, module)?
+            }
+            Expr::Let { name, value, body } => {
+                self.tail_position = tail;
+                self.emit_let_code(name, *value, *body, expected, parent, module)?
+            }
+            Expr::Lambda { args, ret, body } => {
+                self.emit_lambda_code(args, ret, *body, expected, module)?
+            }
+        })
+    }
+
+    // A `let` is just a name for an SSA value, nothing is stored in memory.
+    fn emit_let_code(
+        &mut self,
+        name: String,
+        value: Expr,
+        body: Expr,
+        expected: Option<&Type>,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        let tail = self.tail_position;
+        let value = self.emit_value_code(value, None, parent, module)?;
+        let shadowed = self.symbol_table.insert(name.clone(), value);
+
+        self.tail_position = tail;
+        let body = self.emit_value_code(body, expected, parent, module)?;
+
+        match shadowed {
+            Some(outer) => self.symbol_table.insert(name, outer),
+            None => self.symbol_table.remove(&name),
+        };
+        Ok(body)
+    }
+
+    fn emit_block_code(
+        &mut self,
+        body: Vec<Expr>,
+        expected: Option<&Type>,
+        parent: &FunctionValue<'ctx>,
+        module: &Module<'ctx>,
+    ) -> Result<Value<'ctx>, Diagnostic> {
+        // bindings made inside the block go away with it
+        let tail = self.tail_position;
+        let outer = self.symbol_table.clone();
+        let last = body.len() - 1;
+        let mut value = None;
+        for (i, expr) in body.into_iter().enumerate() {
+            let expected = if i == last { expected } else { None };
+            self.tail_position = tail && i == last;
+            value = Some(self.emit_value_code(expr, expected, parent, module)?);
         }
+        self.symbol_table = outer;
+        Ok(value.unwrap())
     }
 
+    // Makes `prototype` callable by name. A name declared before keeps its
+    // function, code compiled since then already calls it.
     fn emit_proto_type(
+        &mut self,
+        prototype: &Prototype,
+        module: &Module<'ctx>,
+    ) -> Result<FunctionValue<'ctx>, Diagnostic> {
+        let (arg_types, ret) = prototype.signature();
+        self.types
+            .check_types(arg_types.iter().chain(Some(&ret)))
+            .map_err(Diagnostic::error)?;
+        self.types
+            .check_signature(&prototype.name, &arg_types, &ret)
+            .map_err(|message| self.error_at(self.name_span, message))?;
+        if let Some(fn_val) = module.get_function(&prototype.name) {
+            return Ok(fn_val);
+        }
+        let fn_val = self.add_function(&prototype.name, prototype, module);
+        self.types
+            .functions
+            .insert(prototype.name.clone(), (arg_types, ret));
+        Ok(fn_val)
+    }
+
+    fn add_function(
         &self,
-        name: Vec<u8>,
-        args: Vec<Vec<u8>>,
+        name: &str,
+        prototype: &Prototype,
         module: &Module<'ctx>,
     ) -> FunctionValue<'ctx> {
-        let ret_type = self.usize_type();
-        let args_types = std::iter::repeat(ret_type)
-            .take(args.len())
-            .map(|ty| ty.into())
-            .collect::<Vec<BasicMetadataTypeEnum>>();
-        let args_types = args_types.as_slice();
+        let (arg_types, ret) = prototype.signature();
+        let fn_val = module.add_function(name, self.fn_type(&arg_types, &ret), None);
+        for (arg, (name, _)) in fn_val.get_param_iter().zip(&prototype.args) {
+            match arg {
+                BasicValueEnum::IntValue(arg) => arg.set_name(name),
+                BasicValueEnum::FloatValue(arg) => arg.set_name(name),
+                BasicValueEnum::PointerValue(arg) => arg.set_name(name),
+                BasicValueEnum::StructValue(arg) => arg.set_name(name),
+                _ => {}
+            }
+        }
+        fn_val
+    }
 
-        let fn_type = self.usize_type().fn_type(args_types, false);
-        let fn_val = module.add_function(str_from_u8(&name), fn_type, None);
+    // `extern "libm.so.6" cos(x: f64) -> f64` calls straight into the library
+    // with the platform's C calling convention, LLVM's default, so only
+    // scalars can cross over. There's no way to declare another convention,
+    // so functions of the library using one can't be called.
+    fn emit_native_extern(&mut self, prototype: Prototype) -> Result<(), Diagnostic> {
+        let library = prototype.library.clone().unwrap();
+        let types = prototype
+            .args
+            .iter()
+            .filter_map(|(_, ty)| ty.as_ref())
+            .chain(prototype.ret.as_ref());
+        for ty in types {
+            if let Type::Struct(_) | Type::Function(..) = ty {
+                return Err(Diagnostic::error(format!(
+                    "`{}` can't be passed to `{}` in `{}`, pass a pointer instead",
+                    ty, prototype.name, library
+                )));
+            }
+        }
+        let address = runtime::load_symbol(&library, &prototype.name).map_err(|err| {
+            Diagnostic::error(format!(
+                "could not bind `{}` from `{}`: {}",
+                prototype.name, library, err
+            ))
+        })?;
+        let fn_val = self.emit_proto_type(&prototype, self.module)?;
+        // the library's function replaces a body defined before
+        for block in fn_val.get_basic_blocks() {
+            unsafe { block.delete() }.unwrap();
+        }
+        self.types.symbols.insert(prototype.name, address);
+        Ok(())
+    }
 
-        for (i, arg) in fn_val.get_param_iter().enumerate() {
-            arg.into_int_value().set_name(str_from_u8(&args[i]));
+    fn verify_and_optimize(&self, fn_val: FunctionValue<'ctx>) {
+        if fn_val.verify(true) {
+            self.fpm.run_on(&fn_val);
+            if self.options.emit_ir {
+                // `LLVMString`'s `Display` quotes and escapes it like `Debug`
+                print!("{}", fn_val.print_to_string().to_string_lossy());
+            }
+        } else {
+            unsafe {
+                fn_val.delete();
+            }
+            panic!("Invalid generated function.")
         }
-        fn_val
     }
 
+    // A function already declared or defined gets its new body in a new
+    // function, which only takes over the name and the callers once the body
+    // compiled, so a broken redefinition leaves the old one working.
     fn emit_fn_code(
         &mut self,
         prototype: Prototype,
         body: Expr,
         module: &Module<'ctx>,
-    ) -> FunctionValue<'ctx> {
-        let name = prototype.name;
-        let args = prototype.args;
-        let args_num = args.len();
-        let fn_val = match module.get_function(str_from_u8(&name)) {
-            Some(fn_val) => fn_val,
-            None => self.emit_proto_type(name, args, module),
+    ) -> Result<(), Diagnostic> {
+        let outer = module.get_function(&prototype.name);
+        let declared = self.emit_proto_type(&prototype, module)?;
+        let fn_val = match outer {
+            Some(_) => self.add_function("", &prototype, module),
+            None => declared,
         };
+        let (arg_types, ret) = prototype.signature();
         let entry = self.context.append_basic_block(fn_val, "entry");
         self.builder.position_at_end(entry);
-        self.symbol_table.reserve(args_num);
+        self.symbol_table.clear();
+        self.symbol_table.reserve(arg_types.len());
 
-        for arg in fn_val.get_param_iter() {
-            // let arg_name = str_from_u8(args[i]);
-            // let alloca = self.create_entry_block_alloca(arg_name);
+        for ((arg, (name, _)), ty) in fn_val.get_param_iter().zip(prototype.args).zip(arg_types) {
+            // let alloca = self.create_entry_block_alloca(&name);
             // self.builder.build_store(alloca, arg);
-            self.symbol_table.insert(
-                arg.into_int_value().get_name().to_bytes().to_vec(),
-                arg.into(),
-            );
+            self.symbol_table.insert(name, Value { value: arg, ty });
         }
 
-        let body = self.emit_value_code(body, &fn_val, module);
+        self.tail_position = true;
+        self.tail_calls.clear();
+        let body = self
+            .emit_value_code(body, Some(&ret), &fn_val, module)
+            .and_then(|body| self.coerce_result(body, &ret));
+        let body = match body {
+            Ok(body) => body,
+            // the name keeps meaning whatever it did before
+            Err(err) => {
+                unsafe { fn_val.delete() };
+                if outer.is_none() {
+                    self.types.functions.remove(&prototype.name);
+                }
+                return Err(err);
+            }
+        };
         self.builder.build_return(Some(&body));
-        if fn_val.verify(true) {
-            self.fpm.run_on(&fn_val);
-            fn_val
-        } else {
-            unsafe {
-                fn_val.delete();
+        if let Some(outer) = outer {
+            outer.replace_all_uses_with(fn_val);
+            unsafe { outer.delete() };
+            fn_val.as_global_value().set_name(&prototype.name);
+        }
+        // a body of its own wins over a library's function bound to the name
+        self.types.symbols.remove(&prototype.name);
+        self.verify_and_optimize(fn_val);
+        Ok(())
+    }
+}
+
+// Points the runtime declarations of every module at their Rust definitions,
+// and `extern "library"` ones at the `symbols` found for them.
+fn register<'ctx>(
+    ee: &ExecutionEngine<'ctx>,
+    modules: &[&Module<'ctx>],
+    symbols: &HashMap<String, usize>,
+) {
+    for module in modules {
+        for (name, address) in symbols {
+            if let Some(fn_val) = module.get_function(name) {
+                ee.add_global_mapping(&fn_val, *address);
+            }
+        }
+        for fn_val in module.get_functions() {
+            // a script may define its own `floor`, leave that alone
+            if fn_val.count_basic_blocks() > 0 {
+                continue;
+            }
+            let name = fn_val.get_name().to_string_lossy();
+            if let Some(address) = runtime::helper(&name).or_else(|| runtime::native(&name)) {
+                ee.add_global_mapping(&fn_val, address);
             }
-            panic!("Invalid generated function.")
         }
     }
 }
@@ -0,0 +1,114 @@
+use crate::ast::*;
+use crate::operator::Operator;
+
+/// Simplifies the bodies of `item`, see `fold_expr`.
+pub fn fold_item(item: Item) -> Item {
+    ConstFold.fold_item(item)
+}
+
+/// Evaluates arithmetic on literals, picks the branch of an `if` whose
+/// predicate is a literal and drops `+ 0`, `- 0` and `* 1`.
+///
+/// Anything whose result wouldn't fit the literal it came from is left
+/// alone, so the folded tree computes exactly what the original did.
+pub fn fold_expr(expr: Expr) -> Expr {
+    ConstFold.fold_expr(expr)
+}
+
+// Folds the children first, so operators and `if`s see literals wherever
+// their operands fold to one.
+struct ConstFold;
+
+impl Folder for ConstFold {
+    fn fold_binary(&mut self, op: Operator, lhs: Expr, rhs: Expr) -> Expr {
+        fold_binary(op, self.fold_expr(lhs), self.fold_expr(rhs))
+    }
+
+    fn fold_condition(&mut self, predicate: Expr, then: Expr, other: Option<Expr>) -> Expr {
+        fold_condition(
+            self.fold_expr(predicate),
+            self.fold_expr(then),
+            other.map(|other| self.fold_expr(other)),
+        )
+    }
+}
+
+fn is_unsuffixed(expr: &Expr, value: u64) -> bool {
+    matches!(expr, Expr::Number { value: literal, ty: None, .. } if *literal == value)
+}
+
+fn fold_binary(op: Operator, lhs: Expr, rhs: Expr) -> Expr {
+    match (&op, &lhs, &rhs) {
+        (
+            _,
+            Expr::Number {
+                value: a, ty: lty, ..
+            },
+            Expr::Number {
+                value: b, ty: rty, ..
+            },
+        ) if lty == rty => {
+            let max = lty.map_or(u64::MAX, |ty| ty.max_value());
+            let value = match op {
+                Operator::Add => a.checked_add(*b),
+                Operator::Sub => a.checked_sub(*b),
+                Operator::Mul => a.checked_mul(*b),
+                Operator::Pow => u32::try_from(*b).ok().and_then(|b| a.checked_pow(b)),
+                // comparisons are words whatever their operands were
+                Operator::Les => return Expr::number((a < b) as u64, None),
+                Operator::Assign => None,
+            };
+            if let Some(value) = value.filter(|value| *value <= max) {
+                return Expr::number(value, *lty);
+            }
+        }
+        (_, Expr::Float(a), Expr::Float(b)) => {
+            let (a, b) = (*a, *b);
+            return match op {
+                Operator::Add => Expr::Float(a + b),
+                Operator::Sub => Expr::Float(a - b),
+                Operator::Mul => Expr::Float(a * b),
+                Operator::Pow => Expr::Float(a.powf(b)),
+                Operator::Les => Expr::number((a < b) as u64, None),
+                Operator::Assign => Expr::Binary {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        // an unsuffixed literal adopts the other side's type, so these are
+        // no-ops whatever that type is
+        (Operator::Add | Operator::Sub, _, zero) if is_unsuffixed(zero, 0) => return lhs,
+        (Operator::Mul, _, one) if is_unsuffixed(one, 1) => return lhs,
+        (Operator::Add, zero, _) if is_unsuffixed(zero, 0) => return rhs,
+        (Operator::Mul, one, _) if is_unsuffixed(one, 1) => return rhs,
+        _ => {}
+    }
+    Expr::Binary {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+fn fold_condition(predicate: Expr, then: Expr, other: Option<Expr>) -> Expr {
+    let taken = match predicate {
+        Expr::Number { value, .. } => value != 0,
+        Expr::Float(value) => value != 0.0,
+        predicate => {
+            return Expr::Condition {
+                predicate: Box::new(predicate),
+                then: Box::new(then),
+                other: other.map(Box::new),
+            }
+        }
+    };
+    match (taken, other) {
+        (true, Some(_)) => then,
+        (false, Some(other)) => other,
+        // a one-armed `if` is zero whether or not it runs
+        (true, None) => Expr::Block(vec![then, Expr::number(0, None)]),
+        (false, None) => Expr::number(0, None),
+    }
+}
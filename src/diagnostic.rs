@@ -0,0 +1,129 @@
+use std::fmt;
+
+/// Byte range into the source a diagnostic points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Option<Span>,
+    // the stable name of the kind of problem, e.g. the lint that found it
+    pub code: Option<&'static str>,
+    // further problems found together with this one, rendered after it
+    pub related: Vec<Diagnostic>,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            level: Level::Error,
+            message: message.into(),
+            span: None,
+            code: None,
+            related: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            level: Level::Warning,
+            message: message.into(),
+            span: None,
+            code: None,
+            related: Vec::new(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+
+    /// The first of `diagnostics` with the rest as related to it, or `None`
+    /// if there are none, so several problems can be reported as one.
+    pub fn joined(diagnostics: Vec<Diagnostic>) -> Option<Diagnostic> {
+        let mut diagnostics = diagnostics.into_iter();
+        let mut first = diagnostics.next()?;
+        first.related.extend(diagnostics);
+        Some(first)
+    }
+
+    /// Renders the diagnostic together with the line of `source` it points
+    /// at, followed by the related ones.
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let mut out = self.render_one(path, source);
+        for related in &self.related {
+            out += "\n";
+            out += &related.render(path, source);
+        }
+        out
+    }
+
+    fn render_one(&self, path: &str, source: &str) -> String {
+        let mut out = match self.code {
+            Some(code) => format!("{}[{}]: {}\n", self.level, code, self.message),
+            None => format!("{}: {}\n", self.level, self.message),
+        };
+        let span = match self.span {
+            Some(span) => span,
+            None => {
+                out += &format!(" --> {}", path);
+                return out;
+            }
+        };
+        let start = floor_char_boundary(source, span.start);
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let end = floor_char_boundary(source, span.end.clamp(start, line_end));
+        let line_no = source[..start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count() + 1;
+        let gutter = " ".repeat(line_no.to_string().len());
+        // keep tabs so the caret lines up with what the terminal shows
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source[start..end].chars().count().max(1);
+
+        out += &format!("{}--> {}:{}:{}\n", gutter, path, line_no, column);
+        out += &format!("{} |\n", gutter);
+        out += &format!("{} | {}\n", line_no, &source[line_start..line_end]);
+        out += &format!("{} | {}{}", gutter, padding, "^".repeat(width));
+        out
+    }
+}
+
+fn floor_char_boundary(source: &str, mut index: usize) -> usize {
+    index = index.min(source.len());
+    while !source.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
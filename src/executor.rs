@@ -0,0 +1,1336 @@
+use crate::ast::*;
+use crate::const_fold;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::Lexer;
+use crate::operator::Operator;
+use crate::parser::Parser;
+use crate::resolve;
+use crate::runtime::{self, CType, CValue};
+use crate::types::{lambda_signature, IntType, Type, TypeTable};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+type Env = HashMap<String, Value>;
+
+/// A value together with its type, which decides how arithmetic wraps,
+/// compares and converts, exactly as in the generated code.
+#[derive(Clone, Debug)]
+pub enum Value {
+    // the bits of the value, zero-extended from its width
+    Int(u64, IntType),
+    F64(f64),
+    // fields in declaration order
+    Struct(String, Vec<(String, Value)>),
+    // `&value`, shared by every copy of the pointer; the type is the pointee's
+    Pointer(Rc<RefCell<Value>>, Type),
+    // the `Type::Function` the closure was created as
+    Closure(Callee, Type),
+}
+
+#[derive(Clone, Debug)]
+pub enum Callee {
+    Function(String),
+    Lambda(Rc<Code>),
+}
+
+/// The body of a `def` or a lambda and what it needs to run.
+#[derive(Debug)]
+pub struct Code {
+    params: Vec<String>,
+    ret: Type,
+    body: Expr,
+    captures: Env,
+    scope: Rc<Scope>,
+}
+
+// How names are resolved in the file a function was defined in.
+#[derive(Clone, Debug, Default)]
+struct Scope {
+    namespace: Option<String>,
+    aliases: HashMap<String, String>,
+}
+
+enum Definition {
+    Script(Rc<Code>),
+    // an `extern` bound to an address, called with the C convention
+    Foreign(usize),
+    // an `extern` looked up in the process the first time it's called
+    Extern,
+}
+
+/// What outlives a single `Interpreter`, so a function defined on one REPL
+/// line can be called on the next.
+#[derive(Default)]
+pub struct Globals {
+    pub types: TypeTable,
+    definitions: HashMap<String, Definition>,
+    // arrays are words like in the generated code, the word indexes this
+    arrays: Vec<Vec<u64>>,
+}
+
+/// Runs items straight off the AST, without LLVM. Arrays are always bounds
+/// checked, there's no memory to read past.
+pub struct Interpreter<'a> {
+    parser: &'a mut dyn Iterator<Item = Result<(Item, String, Spans), Diagnostic>>,
+    globals: &'a mut Globals,
+    parsed_buffer: String,
+    // the file being run, imports are looked up next to it
+    path: PathBuf,
+    scope: Rc<Scope>,
+    // the name of the item being run, problems with it point there
+    name_span: Option<Span>,
+    // result type of the function being run
+    ret: Type,
+    // whether the expression being evaluated is the last thing its function
+    // does, so a call there can replace the caller instead of nesting
+    tail_position: bool,
+    // such a call, waiting for the caller to return to `call`
+    tail_call: Option<(Callee, Vec<Value>)>,
+}
+
+impl Value {
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Int(_, int) => Type::Int(*int),
+            Value::F64(_) => Type::F64,
+            Value::Struct(name, _) => Type::Struct(name.clone()),
+            Value::Pointer(_, ty) => Type::Pointer(Box::new(ty.clone())),
+            Value::Closure(_, ty) => ty.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(bits, int) if int.is_signed() => write!(f, "{}", int.sign_extend(*bits)),
+            Value::Int(bits, _) => write!(f, "{}", bits),
+            Value::F64(value) => write!(f, "{}", value),
+            Value::Struct(name, fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(field, value)| format!("{}: {}", field, value))
+                    .collect();
+                write!(f, "{} {{ {} }}", name, fields.join(", "))
+            }
+            Value::Pointer(target, _) => write!(f, "{:#x}", Rc::as_ptr(target) as usize),
+            Value::Closure(Callee::Lambda(code), _) => {
+                write!(f, "{:#x}", Rc::as_ptr(code) as usize)
+            }
+            Value::Closure(Callee::Function(name), _) => write!(f, "&{}", name),
+        }
+    }
+}
+
+fn truth(value: &Value) -> Result<bool, Diagnostic> {
+    match value {
+        Value::Int(bits, _) => Ok(*bits != 0),
+        // ordered, so NaN is false like `fcmp one`
+        Value::F64(value) => Ok(*value != 0.0 && !value.is_nan()),
+        value => Err(Diagnostic::error(format!(
+            "expected a number as condition, but found `{}`",
+            value.ty()
+        ))),
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(
+        parser: &'a mut dyn Iterator<Item = Result<(Item, String, Spans), Diagnostic>>,
+        globals: &'a mut Globals,
+    ) -> Interpreter<'a> {
+        Interpreter {
+            parser,
+            globals,
+            parsed_buffer: String::new(),
+            path: PathBuf::new(),
+            scope: Rc::new(Scope::default()),
+            name_span: None,
+            ret: Type::WORD,
+            tail_position: false,
+            tail_call: None,
+        }
+    }
+
+    /// Runs the code as the contents of `path`, putting the functions it
+    /// defines under `namespace::` if one is given.
+    pub fn in_file(mut self, path: &Path, namespace: Option<String>) -> Interpreter<'a> {
+        self.path = path.to_path_buf();
+        self.scope = Rc::new(Scope {
+            namespace,
+            aliases: HashMap::new(),
+        });
+        self
+    }
+
+    /// Runs every item, reporting problems against `source` as they come up.
+    pub fn run_to_end(&mut self, path: &str, source: &str) {
+        while let Some(result) = self.run_item() {
+            if let Err(err) = result {
+                eprintln!("{}", err.render(path, source));
+            }
+        }
+    }
+
+    pub fn run_item(&mut self) -> Option<Result<(), Diagnostic>> {
+        let (item, spans) = match self.parser.next()? {
+            Ok((item, buf, spans)) => {
+                self.parsed_buffer = buf;
+                let arity = |name: &str| {
+                    let name = self.resolve_function(name);
+                    self.globals
+                        .types
+                        .functions
+                        .get(&name)
+                        .map(|(args, _)| args.len())
+                };
+                if let Err(err) = resolve::resolve_item(&item, &spans, &arity) {
+                    return Some(Err(err));
+                }
+                (const_fold::fold_item(item), spans)
+            }
+            Err(err) => return Some(Err(err)),
+        };
+        self.name_span = spans.name;
+        let result = match item {
+            Item::Struct(def) => self.define_struct(def),
+            Item::Import(target) => self.import(&target),
+            Item::Use(path) => self.use_path(path),
+            Item::Function(fun) => match (fun.prototype, fun.body) {
+                (Some(mut proto), Some(body)) => {
+                    // externs keep their name, it's the symbol they link to
+                    if let Some(namespace) = &self.scope.namespace {
+                        proto.name = format!("{}::{}", namespace, proto.name);
+                    }
+                    self.define(proto, body)
+                }
+                (None, Some(body)) => self.run_anon(body),
+                (Some(proto), None) if proto.library.is_some() => self.bind_extern(proto),
+                (Some(proto), None) => self.declare(&proto).map(|_| {
+                    self.globals
+                        .definitions
+                        .entry(proto.name)
+                        .or_insert(Definition::Extern);
+                }),
+                (None, None) => {
+                    panic!("Unsupposed to see a function without nither prototype nor body!")
+                }
+            },
+        };
+        // like the JIT, whatever has no more precise place points at the item
+        Some(result.map_err(|err| match err.span {
+            Some(_) => err,
+            None => err.with_span(spans.item),
+        }))
+    }
+
+    fn run_anon(&mut self, body: Expr) -> Result<(), Diagnostic> {
+        // checked as a whole first, so nothing runs if any of it is wrong
+        self.check(&body, None, &mut Vec::new())?;
+        self.ret = Type::WORD;
+        let value = self.eval(&body, None, &mut Env::new())?;
+        println!("{} => {}", self.parsed_buffer, value);
+        Ok(())
+    }
+
+    fn define_struct(&mut self, def: StructDef) -> Result<(), Diagnostic> {
+        if self.globals.types.structs.contains_key(&def.name) {
+            return Err(self.name_error(format!("struct `{}` is already defined", def.name)));
+        }
+        self.globals
+            .types
+            .check_fields(&def.name, &def.fields)
+            .map_err(Diagnostic::error)?;
+        self.globals.types.structs.insert(def.name, def.fields);
+        Ok(())
+    }
+
+    fn import(&mut self, target: &str) -> Result<(), Diagnostic> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let path = dir.join(target);
+        let key = match self
+            .globals
+            .types
+            .import_key(&path)
+            .map_err(Diagnostic::error)?
+        {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let source = fs::read_to_string(&path)
+            .map_err(|err| Diagnostic::error(format!("could not import `{}`: {}", target, err)))?;
+        let namespace = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        self.globals.types.imported.insert(key.clone());
+        self.globals.types.importing.push(key);
+        let display = path.to_string_lossy().into_owned();
+        let mut lex = Lexer::new(&source);
+        let mut par = Parser::new(&mut lex);
+        Interpreter::new(&mut par, self.globals)
+            .in_file(&path, Some(namespace))
+            .run_to_end(&display, &source);
+        self.globals.types.importing.pop();
+        Ok(())
+    }
+
+    fn use_path(&mut self, path: String) -> Result<(), Diagnostic> {
+        if !self.globals.types.functions.contains_key(&path) {
+            return Err(Diagnostic::error(format!("unresolved import `{}`", path)));
+        }
+        let name = path.rsplit("::").next().unwrap().to_string();
+        // functions defined before keep the aliases they were defined with
+        Rc::make_mut(&mut self.scope).aliases.insert(name, path);
+        Ok(())
+    }
+
+    // Functions of this file win over `use`d ones, which win over globals.
+    fn resolve_function(&self, name: &str) -> String {
+        if let Some(namespace) = &self.scope.namespace {
+            let local = format!("{}::{}", namespace, name);
+            if self.globals.types.functions.contains_key(&local) {
+                return local;
+            }
+        }
+        match self.scope.aliases.get(name) {
+            Some(path) => path.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    // A problem with the name of the item being run, pointing at it.
+    fn name_error(&self, message: String) -> Diagnostic {
+        match self.name_span {
+            Some(span) => Diagnostic::error(message).with_span(span),
+            None => Diagnostic::error(message),
+        }
+    }
+
+    fn declare(&mut self, prototype: &Prototype) -> Result<(Vec<Type>, Type), Diagnostic> {
+        let (arg_types, ret) = prototype.signature();
+        self.globals
+            .types
+            .check_types(arg_types.iter().chain(Some(&ret)))
+            .map_err(Diagnostic::error)?;
+        self.globals
+            .types
+            .check_signature(&prototype.name, &arg_types, &ret)
+            .map_err(|message| self.name_error(message))?;
+        self.globals
+            .types
+            .functions
+            .insert(prototype.name.clone(), (arg_types.clone(), ret.clone()));
+        Ok((arg_types, ret))
+    }
+
+    // The body is checked once, here, rather than whenever it's called. A
+    // redefinition replaces the body for every caller, older ones included.
+    fn define(&mut self, prototype: Prototype, body: Expr) -> Result<(), Diagnostic> {
+        let declared = self.globals.types.functions.contains_key(&prototype.name);
+        let (arg_types, ret) = self.declare(&prototype)?;
+        let params: Vec<String> = prototype.args.into_iter().map(|(name, _)| name).collect();
+        let mut bound = params.iter().cloned().zip(arg_types).collect();
+        if let Err(err) = self.check_as(&body, &ret, &mut bound) {
+            // calls keep going to whatever the name meant before
+            if !declared {
+                self.globals.types.functions.remove(&prototype.name);
+            }
+            return Err(err);
+        }
+        let code = Code {
+            params,
+            ret,
+            body,
+            captures: Env::new(),
+            scope: self.scope.clone(),
+        };
+        self.globals
+            .definitions
+            .insert(prototype.name, Definition::Script(Rc::new(code)));
+        Ok(())
+    }
+
+    // `extern "libm.so.6" cos(x: f64) -> f64` is bound when it's declared,
+    // like the JIT does, so a missing library is reported right away.
+    fn bind_extern(&mut self, prototype: Prototype) -> Result<(), Diagnostic> {
+        let library = prototype.library.clone().unwrap();
+        let types = prototype
+            .args
+            .iter()
+            .filter_map(|(_, ty)| ty.as_ref())
+            .chain(prototype.ret.as_ref());
+        for ty in types {
+            if let Type::Struct(_) | Type::Function(..) = ty {
+                return Err(Diagnostic::error(format!(
+                    "`{}` can't be passed to `{}` in `{}`, pass a pointer instead",
+                    ty, prototype.name, library
+                )));
+            }
+        }
+        let address = runtime::load_symbol(&library, &prototype.name).map_err(|err| {
+            Diagnostic::error(format!(
+                "could not bind `{}` from `{}`: {}",
+                prototype.name, library, err
+            ))
+        })?;
+        self.declare(&prototype)?;
+        self.globals
+            .definitions
+            .insert(prototype.name, Definition::Foreign(address));
+        Ok(())
+    }
+
+    fn field_index(&self, name: &str, field: &str) -> Result<(usize, Type), Diagnostic> {
+        let fields = match self.globals.types.structs.get(name) {
+            Some(fields) => fields,
+            None => return Err(Diagnostic::error(format!("could not find type `{}`", name))),
+        };
+        match fields.iter().position(|(f, _)| f == field) {
+            Some(i) => Ok((i, fields[i].1.clone())),
+            None => Err(Diagnostic::error(format!(
+                "`{}` has no field `{}`",
+                name, field
+            ))),
+        }
+    }
+
+    // Converts between numeric types; anything else has to match exactly.
+    fn coerce(&self, value: Value, ty: &Type) -> Result<Value, Diagnostic> {
+        Ok(match (value, ty) {
+            (Value::Int(bits, from), Type::Int(to)) => {
+                let bits = if from.is_signed() {
+                    from.sign_extend(bits) as u64
+                } else {
+                    bits
+                };
+                Value::Int(to.truncate(bits), *to)
+            }
+            (Value::Int(bits, from), Type::F64) if from.is_signed() => {
+                Value::F64(from.sign_extend(bits) as f64)
+            }
+            (Value::Int(bits, _), Type::F64) => Value::F64(bits as f64),
+            (Value::F64(value), Type::Int(to)) if to.is_signed() => {
+                Value::Int(to.truncate(value as i64 as u64), *to)
+            }
+            (Value::F64(value), Type::Int(to)) => Value::Int(to.truncate(value as u64), *to),
+            (value, ty) if value.ty() == *ty => value,
+            (value, ty) => {
+                return Err(Diagnostic::error(format!(
+                    "expected a value of type `{}`, but found `{}`",
+                    ty,
+                    value.ty()
+                )))
+            }
+        })
+    }
+
+    // Everything array related still works on plain words.
+    fn eval_word(&mut self, expr: &Expr, env: &mut Env) -> Result<u64, Diagnostic> {
+        let value = self.eval(expr, Some(&Type::WORD), env)?;
+        match self.coerce(value, &Type::WORD)? {
+            Value::Int(bits, _) => Ok(bits),
+            _ => unreachable!(),
+        }
+    }
+
+    fn array(&self, array: u64) -> Result<&Vec<u64>, Diagnostic> {
+        self.globals
+            .arrays
+            .get(array as usize)
+            .ok_or_else(|| Diagnostic::error(format!("`{}` is not an array", array)))
+    }
+
+    fn element_index(&self, array: u64, index: u64) -> Result<usize, Diagnostic> {
+        let len = self.array(array)?.len() as u64;
+        if index >= len {
+            return Err(Diagnostic::error(format!(
+                "index out of bounds: the len is {} but the index is {}",
+                len, index
+            )));
+        }
+        Ok(index as usize)
+    }
+
+    fn alloc(&mut self, elements: Vec<u64>) -> Value {
+        self.globals.arrays.push(elements);
+        Value::Int(self.globals.arrays.len() as u64 - 1, IntType::U64)
+    }
+
+    fn eval_assign(
+        &mut self,
+        target: &Expr,
+        value: &Expr,
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        match target {
+            Expr::Index { target, index } => {
+                let array = self.eval_word(target, env)?;
+                let index = self.eval_word(index, env)?;
+                let index = self.element_index(array, index)?;
+                let value = self.eval(value, Some(&Type::WORD), env)?;
+                let value = self.coerce(value, &Type::WORD)?;
+                if let Value::Int(bits, _) = value {
+                    self.globals.arrays[array as usize][index] = bits;
+                }
+                Ok(value)
+            }
+            Expr::Field { target, field } => {
+                let (target, pointee) = match self.eval(target, None, env)? {
+                    Value::Pointer(target, Type::Struct(name)) => (target, name),
+                    value @ Value::Struct(..) => {
+                        return Err(Diagnostic::error(format!(
+                            "fields of `{}` can only be changed through a pointer, try `&`",
+                            value.ty()
+                        )))
+                    }
+                    value => {
+                        return Err(Diagnostic::error(format!(
+                            "`{}` has no field `{}`",
+                            value.ty(),
+                            field
+                        )))
+                    }
+                };
+                let (index, ty) = self.field_index(&pointee, field)?;
+                let value = self.eval(value, Some(&ty), env)?;
+                let value = self.coerce(value, &ty)?;
+                if let Value::Struct(_, fields) = &mut *target.borrow_mut() {
+                    fields[index].1 = value.clone();
+                }
+                Ok(value)
+            }
+            _ => Err(Diagnostic::error(
+                "can only assign to an array element or a field",
+            )),
+        }
+    }
+
+    fn eval_struct(
+        &mut self,
+        name: &str,
+        fields: &[(String, Expr)],
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        let count = match self.globals.types.structs.get(name) {
+            Some(def) => def.len(),
+            None => return Err(Diagnostic::error(format!("could not find type `{}`", name))),
+        };
+        let mut values = vec![None; count];
+        for (field, value) in fields {
+            let (index, ty) = self.field_index(name, field)?;
+            if values[index].is_some() {
+                return Err(Diagnostic::error(format!(
+                    "field `{}` is initialized twice",
+                    field
+                )));
+            }
+            let value = self.eval(value, Some(&ty), env)?;
+            values[index] = Some((field.clone(), self.coerce(value, &ty)?));
+        }
+        if let Some(i) = values.iter().position(Option::is_none) {
+            let fields = &self.globals.types.structs[name];
+            return Err(Diagnostic::error(format!(
+                "missing field `{}` in `{}`",
+                fields[i].0, name
+            )));
+        }
+        Ok(Value::Struct(
+            name.to_string(),
+            values.into_iter().flatten().collect(),
+        ))
+    }
+
+    fn eval_field(
+        &mut self,
+        target: &Expr,
+        field: &str,
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        let (name, fields) = match self.eval(target, None, env)? {
+            Value::Struct(name, fields) => (name, fields),
+            Value::Pointer(target, Type::Struct(name)) => match &*target.borrow() {
+                Value::Struct(_, fields) => (name, fields.clone()),
+                _ => unreachable!(),
+            },
+            value => {
+                return Err(Diagnostic::error(format!(
+                    "`{}` has no field `{}`",
+                    value.ty(),
+                    field
+                )))
+            }
+        };
+        let (index, _) = self.field_index(&name, field)?;
+        Ok(fields.into_iter().nth(index).unwrap().1)
+    }
+
+    // `&value` puts a copy of the value behind a shared pointer, `&square`
+    // takes a named function instead.
+    fn eval_ref(&mut self, value: &Expr, env: &mut Env) -> Result<Value, Diagnostic> {
+        if let Expr::Variable(name) = value {
+            if !env.contains_key(name) {
+                let name = self.resolve_function(name);
+                return match self.globals.types.functions.get(&name) {
+                    Some((args, ret)) => {
+                        let ty = Type::Function(args.clone(), Box::new(ret.clone()));
+                        Ok(Value::Closure(Callee::Function(name), ty))
+                    }
+                    None => Err(Diagnostic::error(format!(
+                        "could not find function `{}`",
+                        name
+                    ))),
+                };
+            }
+        }
+        let value = self.eval(value, None, env)?;
+        let ty = value.ty();
+        Ok(Value::Pointer(Rc::new(RefCell::new(value)), ty))
+    }
+
+    // Whatever the body uses from the enclosing scope is copied in when the
+    // lambda is created.
+    fn eval_lambda(
+        &mut self,
+        args: &[(String, Option<Type>)],
+        ret: &Option<Type>,
+        body: &Expr,
+        expected: Option<&Type>,
+        env: &Env,
+    ) -> Value {
+        let (arg_types, ret) = lambda_signature(args, ret, expected);
+        let params: Vec<String> = args.iter().map(|(name, _)| name.clone()).collect();
+        let captures = body
+            .free_variables(&params)
+            .into_iter()
+            .filter_map(|name| {
+                let value = env.get(&name)?.clone();
+                Some((name, value))
+            })
+            .collect();
+        let code = Code {
+            params,
+            ret: ret.clone(),
+            body: body.clone(),
+            captures,
+            scope: self.scope.clone(),
+        };
+        let ty = Type::Function(arg_types, Box::new(ret));
+        Value::Closure(Callee::Lambda(Rc::new(code)), ty)
+    }
+
+    fn eval_builtin(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        match (name, args) {
+            ("alloc", [len]) => {
+                let len = self.eval_word(len, env)?;
+                let mut elements = Vec::new();
+                elements
+                    .try_reserve_exact(len as usize)
+                    .map_err(|_| Diagnostic::error(format!("could not allocate {} words", len)))?;
+                elements.resize(len as usize, 0);
+                Ok(self.alloc(elements))
+            }
+            ("len", [array]) => {
+                let array = self.eval_word(array, env)?;
+                let len = self.array(array)?.len() as u64;
+                Ok(Value::Int(len, IntType::U64))
+            }
+            ("alloc", _) | ("len", _) => Err(Diagnostic::error("incorrect # of arguments passed")),
+            _ => Err(Diagnostic::error(format!(
+                "could not find function `{}`",
+                name
+            ))),
+        }
+    }
+
+    fn eval_op(
+        &mut self,
+        op: Operator,
+        left: &Expr,
+        right: &Expr,
+        expected: Option<&Type>,
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        // a comparison's operands don't have to look like its result
+        let expected = match op {
+            Operator::Les => None,
+            _ => expected,
+        };
+        // an unsuffixed literal takes the type of the other operand
+        let (lhs, rhs) = if let Expr::Number { ty: None, .. } = left {
+            let rhs = self.eval(right, expected, env)?;
+            let lhs = self.eval(left, Some(&rhs.ty()), env)?;
+            (lhs, rhs)
+        } else {
+            let lhs = self.eval(left, expected, env)?;
+            let rhs = self.eval(right, Some(&lhs.ty()), env)?;
+            (lhs, rhs)
+        };
+        let ty = match lhs.ty().unify(&rhs.ty()) {
+            Some(ty) if ty.is_numeric() => ty,
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "operator `{}` cannot be applied to `{}` and `{}`",
+                    op,
+                    lhs.ty(),
+                    rhs.ty()
+                )))
+            }
+        };
+        let word = |flag: bool| Value::Int(flag as u64, IntType::U64);
+        Ok(match (self.coerce(lhs, &ty)?, self.coerce(rhs, &ty)?) {
+            (Value::F64(lhs), Value::F64(rhs)) => match op {
+                Operator::Add => Value::F64(lhs + rhs),
+                Operator::Sub => Value::F64(lhs - rhs),
+                Operator::Mul => Value::F64(lhs * rhs),
+                Operator::Pow => Value::F64(lhs.powf(rhs)),
+                Operator::Les => word(lhs < rhs),
+                // handled by `eval_assign` before the operands are evaluated
+                Operator::Assign => unreachable!(),
+            },
+            (Value::Int(lhs, int), Value::Int(rhs, _)) => match op {
+                Operator::Add => Value::Int(int.truncate(lhs.wrapping_add(rhs)), int),
+                Operator::Sub => Value::Int(int.truncate(lhs.wrapping_sub(rhs)), int),
+                Operator::Mul => Value::Int(int.truncate(lhs.wrapping_mul(rhs)), int),
+                Operator::Pow => Value::Int(int.truncate(runtime::int_pow(lhs, rhs)), int),
+                Operator::Les if int.is_signed() => {
+                    word(int.sign_extend(lhs) < int.sign_extend(rhs))
+                }
+                Operator::Les => word(lhs < rhs),
+                // handled by `eval_assign` before the operands are evaluated
+                Operator::Assign => unreachable!(),
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    fn eval_call(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        tail: bool,
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        // a variable holding a function shadows the function of that name
+        let (callee, arg_types, ret) = match env.get(name) {
+            Some(Value::Closure(callee, Type::Function(args, ret))) => {
+                (callee.clone(), args.clone(), *ret.clone())
+            }
+            Some(var) => {
+                return Err(Diagnostic::error(format!(
+                    "`{}` is a `{}`, not a function",
+                    name,
+                    var.ty()
+                )))
+            }
+            None => {
+                let name = self.resolve_function(name);
+                let (arg_types, ret) = match self.globals.types.functions.get(&name) {
+                    Some(signature) => signature.clone(),
+                    None => return self.eval_builtin(&name, args, env),
+                };
+                (Callee::Function(name), arg_types, ret)
+            }
+        };
+        if arg_types.len() != args.len() {
+            return Err(Diagnostic::error("incorrect # of arguments passed"));
+        }
+
+        let mut values = Vec::with_capacity(args.len());
+        for (arg, ty) in args.iter().zip(&arg_types) {
+            let value = self.eval(arg, Some(ty), env)?;
+            values.push(self.coerce(value, ty)?);
+        }
+        // only a call whose result is returned as is can replace the caller,
+        // otherwise the caller still has work to do after it
+        if tail && ret == self.ret {
+            self.tail_call = Some((callee, values));
+            // never looked at, `call` picks up `tail_call` instead
+            return Ok(Value::Int(0, IntType::U64));
+        }
+        self.call(callee, values)
+    }
+
+    fn call(&mut self, callee: Callee, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        let outer = (self.ret.clone(), self.scope.clone());
+        let result = self.run_calls(callee, args);
+        (self.ret, self.scope) = outer;
+        result
+    }
+
+    // Runs `callee` and every call it ends in, in constant stack.
+    fn run_calls(&mut self, mut callee: Callee, mut args: Vec<Value>) -> Result<Value, Diagnostic> {
+        loop {
+            let code = match callee {
+                Callee::Lambda(code) => code,
+                Callee::Function(name) => match self.globals.definitions.get(&name) {
+                    Some(Definition::Script(code)) => code.clone(),
+                    Some(Definition::Foreign(address)) => {
+                        return self.call_foreign(&name, *address, args)
+                    }
+                    Some(Definition::Extern) | None => {
+                        let address = match runtime::native(&name) {
+                            Some(address) => address,
+                            None => runtime::find_symbol(&name).map_err(|err| {
+                                Diagnostic::error(format!(
+                                    "could not find function `{}`: {}",
+                                    name, err
+                                ))
+                            })?,
+                        };
+                        self.globals
+                            .definitions
+                            .insert(name.clone(), Definition::Foreign(address));
+                        return self.call_foreign(&name, address, args);
+                    }
+                },
+            };
+            let mut env = code.captures.clone();
+            env.extend(code.params.iter().cloned().zip(args));
+            self.ret = code.ret.clone();
+            self.scope = code.scope.clone();
+            self.tail_position = true;
+            let value = self.eval(&code.body, Some(&code.ret), &mut env)?;
+            match self.tail_call.take() {
+                Some((next, next_args)) => {
+                    callee = next;
+                    args = next_args;
+                }
+                None => return self.coerce(value, &code.ret),
+            }
+        }
+    }
+
+    fn call_foreign(
+        &self,
+        name: &str,
+        address: usize,
+        args: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        let mut c_args = Vec::with_capacity(args.len());
+        for arg in args {
+            c_args.push(match arg {
+                Value::Int(bits, int) => CValue::Int(bits, int),
+                Value::F64(value) => CValue::Float(value),
+                arg => {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` can't be passed to `{}` by the interpreter",
+                        arg.ty(),
+                        name
+                    )))
+                }
+            });
+        }
+        // `extern` declarations only allow scalars, see `bind_extern`
+        let ret = match &self.globals.types.functions[name].1 {
+            Type::F64 => CType::Float,
+            Type::Int(int) => CType::Int(*int),
+            ty => {
+                return Err(Diagnostic::error(format!(
+                    "`{}` can't be returned from `{}` to the interpreter",
+                    ty, name
+                )))
+            }
+        };
+        Ok(match unsafe { runtime::call_c(address, &c_args, ret) } {
+            CValue::Int(bits, int) => Value::Int(bits, int),
+            CValue::Float(value) => Value::F64(value),
+        })
+    }
+
+    fn eval_condition(
+        &mut self,
+        predicate: &Expr,
+        then: &Expr,
+        other: &Expr,
+        expected: Option<&Type>,
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        // the predicate is never in tail position, only the branch taken is
+        let tail = std::mem::replace(&mut self.tail_position, false);
+        let cond = self.eval(predicate, None, env)?;
+        let taken = truth(&cond)?;
+
+        // only one branch runs, but the `if` has the type of both
+        let then_type = self.type_of(then, expected, env, &mut Vec::new())?;
+        let other_type = self.type_of(other, Some(&then_type), env, &mut Vec::new())?;
+        let ty = match then_type.unify(&other_type) {
+            Some(ty) => ty,
+            None => unreachable!("`check` made sure the branches unify"),
+        };
+        let (branch, expected) = if taken {
+            (then, expected)
+        } else {
+            (other, Some(&then_type))
+        };
+        self.tail_position = tail && ty == self.ret;
+        let value = self.eval(branch, expected, env)?;
+        if self.tail_call.is_some() {
+            return Ok(value);
+        }
+        self.coerce(value, &ty)
+    }
+
+    pub fn eval(
+        &mut self,
+        expr: &Expr,
+        expected: Option<&Type>,
+        env: &mut Env,
+    ) -> Result<Value, Diagnostic> {
+        // sub-expressions are never in tail position unless their parent says
+        // so; `if`, blocks and `let` pick the flag back up for their last part
+        let tail = std::mem::replace(&mut self.tail_position, false);
+        match expr {
+            Expr::Number { value, ty, .. } => Ok(match Type::literal(*value, *ty, expected) {
+                Type::F64 => Value::F64(*value as f64),
+                Type::Int(int) => Value::Int(*value, int),
+                _ => unreachable!(),
+            }),
+            Expr::Float(value) => Ok(Value::F64(*value)),
+            Expr::Variable(name) => match env.get(name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(Diagnostic::error(format!(
+                    "could not find variable `{}`",
+                    name
+                ))),
+            },
+            Expr::Binary {
+                op: Operator::Assign,
+                lhs,
+                rhs,
+            } => self.eval_assign(lhs, rhs, env),
+            Expr::Binary { op, lhs, rhs } => self.eval_op(*op, lhs, rhs, expected, env),
+            Expr::Call { name, args } => self.eval_call(name, args, tail, env),
+            Expr::Condition {
+                predicate,
+                then,
+                other,
+            } => match other {
+                Some(other) => {
+                    self.tail_position = tail;
+                    self.eval_condition(predicate, then, other, expected, env)
+                }
+                // without `else` the value is zero, the `if` only runs for its effects
+                None => {
+                    let cond = self.eval(predicate, None, env)?;
+                    if truth(&cond)? {
+                        self.eval(then, None, env)?;
+                    }
+                    Ok(Value::Int(0, IntType::U64))
+                }
+            },
+            Expr::Array(elements) => {
+                let mut words = Vec::with_capacity(elements.len());
+                for element in elements {
+                    words.push(self.eval_word(element, env)?);
+                }
+                Ok(self.alloc(words))
+            }
+            Expr::Index { target, index } => {
+                let array = self.eval_word(target, env)?;
+                let index = self.eval_word(index, env)?;
+                let index = self.element_index(array, index)?;
+                Ok(Value::Int(
+                    self.globals.arrays[array as usize][index],
+                    IntType::U64,
+                ))
+            }
+            Expr::Struct { name, fields } => self.eval_struct(name, fields, env),
+            Expr::Field { target, field } => self.eval_field(target, field, env),
+            Expr::Ref(value) => self.eval_ref(value, env),
+            Expr::Block(body) => {
+                let last = body.len() - 1;
+                let mut value = None;
+                for (i, expr) in body.iter().enumerate() {
+                    let expected = if i == last { expected } else { None };
+                    self.tail_position = tail && i == last;
+                    value = Some(self.eval(expr, expected, env)?);
+                }
+                Ok(value.unwrap())
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.eval(value, None, env)?;
+                let shadowed = env.insert(name.clone(), value);
+                self.tail_position = tail;
+                let body = self.eval(body, expected, env);
+                match shadowed {
+                    Some(outer) => env.insert(name.clone(), outer),
+                    None => env.remove(name),
+                };
+                body
+            }
+            Expr::Lambda { args, ret, body } => {
+                Ok(self.eval_lambda(args, ret, body, expected, env))
+            }
+        }
+    }
+
+    // Finds everything `eval` would reject in `expr` without running any of
+    // it, lambda bodies included, and returns the type `eval` would give it.
+    // `bound` holds the types of the bindings in scope.
+    fn check(
+        &self,
+        expr: &Expr,
+        expected: Option<&Type>,
+        bound: &mut Vec<(String, Type)>,
+    ) -> Result<Type, Diagnostic> {
+        match expr {
+            Expr::Binary {
+                op: Operator::Assign,
+                lhs,
+                rhs,
+            } => {
+                match lhs.as_ref() {
+                    Expr::Index { target, index } => {
+                        self.check_as(target, &Type::WORD, bound)?;
+                        self.check_as(index, &Type::WORD, bound)?;
+                    }
+                    Expr::Field { target, .. } => {
+                        self.check(target, None, bound)?;
+                    }
+                    _ => {}
+                }
+                let ty = self.type_of(expr, None, &Env::new(), bound)?;
+                self.check_as(rhs, &ty, bound)?;
+                return Ok(ty);
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                let expected = match op {
+                    Operator::Les => None,
+                    _ => expected,
+                };
+                if let Expr::Number { ty: None, .. } = lhs.as_ref() {
+                    let rhs = self.check(rhs, expected, bound)?;
+                    self.check(lhs, Some(&rhs), bound)?;
+                } else {
+                    let lhs = self.check(lhs, expected, bound)?;
+                    self.check(rhs, Some(&lhs), bound)?;
+                }
+            }
+            Expr::Call { name, args } => {
+                // reports a call of something that isn't a function
+                let ty = self.type_of(expr, expected, &Env::new(), bound)?;
+                let arg_types = match bound.iter().rev().find(|(bound, _)| bound == name) {
+                    Some((_, Type::Function(arg_types, _))) => arg_types.clone(),
+                    _ => match self
+                        .globals
+                        .types
+                        .functions
+                        .get(&self.resolve_function(name))
+                    {
+                        Some((arg_types, _)) => arg_types.clone(),
+                        // `alloc` and `len`
+                        None => vec![Type::WORD],
+                    },
+                };
+                if arg_types.len() != args.len() {
+                    return Err(Diagnostic::error("incorrect # of arguments passed"));
+                }
+                for (arg, ty) in args.iter().zip(&arg_types) {
+                    self.check_as(arg, ty, bound)?;
+                }
+                return Ok(ty);
+            }
+            Expr::Condition {
+                predicate,
+                then,
+                other,
+            } => {
+                let ty = self.check(predicate, None, bound)?;
+                if !ty.is_numeric() {
+                    return Err(Diagnostic::error(format!(
+                        "expected a number as condition, but found `{}`",
+                        ty
+                    )));
+                }
+                match other {
+                    Some(other) => {
+                        let then = self.check(then, expected, bound)?;
+                        self.check(other, Some(&then), bound)?;
+                    }
+                    None => {
+                        self.check(then, None, bound)?;
+                    }
+                }
+            }
+            Expr::Array(elements) => {
+                for element in elements {
+                    self.check_as(element, &Type::WORD, bound)?;
+                }
+            }
+            Expr::Index { target, index } => {
+                self.check_as(target, &Type::WORD, bound)?;
+                self.check_as(index, &Type::WORD, bound)?;
+            }
+            Expr::Struct { name, fields } => {
+                let count = match self.globals.types.structs.get(name) {
+                    Some(def) => def.len(),
+                    None => {
+                        return Err(Diagnostic::error(format!("could not find type `{}`", name)))
+                    }
+                };
+                let mut initialized = vec![false; count];
+                for (field, value) in fields {
+                    let (index, ty) = self.field_index(name, field)?;
+                    if initialized[index] {
+                        return Err(Diagnostic::error(format!(
+                            "field `{}` is initialized twice",
+                            field
+                        )));
+                    }
+                    initialized[index] = true;
+                    self.check_as(value, &ty, bound)?;
+                }
+                if let Some(i) = initialized.iter().position(|done| !done) {
+                    let fields = &self.globals.types.structs[name];
+                    return Err(Diagnostic::error(format!(
+                        "missing field `{}` in `{}`",
+                        fields[i].0, name
+                    )));
+                }
+            }
+            Expr::Field { target, .. } => {
+                self.check(target, None, bound)?;
+            }
+            Expr::Ref(value) => match value.as_ref() {
+                Expr::Variable(name) if !bound.iter().any(|(bound, _)| bound == name) => {}
+                value => {
+                    self.check(value, None, bound)?;
+                }
+            },
+            Expr::Block(body) => {
+                let last = body.len() - 1;
+                for expr in &body[..last] {
+                    self.check(expr, None, bound)?;
+                }
+                return self.check(&body[last], expected, bound);
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.check(value, None, bound)?;
+                bound.push((name.clone(), value));
+                let body = self.check(body, expected, bound);
+                bound.pop();
+                return body;
+            }
+            Expr::Lambda { args, ret, body } => {
+                let (arg_types, ret) = lambda_signature(args, ret, expected);
+                self.globals
+                    .types
+                    .check_types(arg_types.iter().chain(Some(&ret)))
+                    .map_err(Diagnostic::error)?;
+                let outer = bound.len();
+                bound.extend(
+                    args.iter()
+                        .map(|(name, _)| name.clone())
+                        .zip(arg_types.clone()),
+                );
+                let body = self.check_as(body, &ret, bound);
+                bound.truncate(outer);
+                body?;
+                return Ok(Type::Function(arg_types, Box::new(ret)));
+            }
+            Expr::Number { .. } | Expr::Float(_) | Expr::Variable(_) => {}
+        }
+        self.type_of(expr, expected, &Env::new(), bound)
+    }
+
+    // `check`s that `expr` can be converted to `ty`, like `coerce` does.
+    fn check_as(
+        &self,
+        expr: &Expr,
+        ty: &Type,
+        bound: &mut Vec<(String, Type)>,
+    ) -> Result<(), Diagnostic> {
+        let found = self.check(expr, Some(ty), bound)?;
+        if found == *ty || found.is_numeric() && ty.is_numeric() {
+            return Ok(());
+        }
+        Err(Diagnostic::error(format!(
+            "expected a value of type `{}`, but found `{}`",
+            ty, found
+        )))
+    }
+
+    // The type `eval` would give `expr`, without running it. `bound` holds
+    // the types of bindings made inside `expr` itself.
+    fn type_of(
+        &self,
+        expr: &Expr,
+        expected: Option<&Type>,
+        env: &Env,
+        bound: &mut Vec<(String, Type)>,
+    ) -> Result<Type, Diagnostic> {
+        let lookup = |name: &str, bound: &[(String, Type)]| match bound
+            .iter()
+            .rev()
+            .find(|(bound, _)| bound == name)
+        {
+            Some((_, ty)) => Some(ty.clone()),
+            None => env.get(name).map(Value::ty),
+        };
+        Ok(match expr {
+            Expr::Number { value, ty, .. } => Type::literal(*value, *ty, expected),
+            Expr::Float(_) => Type::F64,
+            Expr::Variable(name) => match lookup(name, bound) {
+                Some(ty) => ty,
+                None => {
+                    return Err(Diagnostic::error(format!(
+                        "could not find variable `{}`",
+                        name
+                    )))
+                }
+            },
+            Expr::Binary {
+                op: Operator::Assign,
+                lhs,
+                ..
+            } => match lhs.as_ref() {
+                Expr::Index { .. } => Type::WORD,
+                Expr::Field { target, field } => match self.type_of(target, None, env, bound)? {
+                    Type::Pointer(inner) => match *inner {
+                        Type::Struct(name) => self.field_index(&name, field)?.1,
+                        ty => {
+                            return Err(Diagnostic::error(format!(
+                                "`*{}` has no field `{}`",
+                                ty, field
+                            )))
+                        }
+                    },
+                    ty => {
+                        return Err(Diagnostic::error(format!(
+                            "fields of `{}` can only be changed through a pointer, try `&`",
+                            ty
+                        )))
+                    }
+                },
+                _ => {
+                    return Err(Diagnostic::error(
+                        "can only assign to an array element or a field",
+                    ))
+                }
+            },
+            Expr::Binary { op, lhs, rhs } => {
+                let expected = match op {
+                    Operator::Les => None,
+                    _ => expected,
+                };
+                let (lhs, rhs) = if let Expr::Number { ty: None, .. } = lhs.as_ref() {
+                    let rhs = self.type_of(rhs, expected, env, bound)?;
+                    (self.type_of(lhs, Some(&rhs), env, bound)?, rhs)
+                } else {
+                    let lhs = self.type_of(lhs, expected, env, bound)?;
+                    let rhs = self.type_of(rhs, Some(&lhs), env, bound)?;
+                    (lhs, rhs)
+                };
+                match (op, lhs.unify(&rhs)) {
+                    (Operator::Les, Some(ty)) if ty.is_numeric() => Type::WORD,
+                    (_, Some(ty)) if ty.is_numeric() => ty,
+                    _ => {
+                        return Err(Diagnostic::error(format!(
+                            "operator `{}` cannot be applied to `{}` and `{}`",
+                            op, lhs, rhs
+                        )))
+                    }
+                }
+            }
+            Expr::Call { name, args } => match lookup(name, bound) {
+                Some(Type::Function(_, ret)) => *ret,
+                Some(ty) => {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` is a `{}`, not a function",
+                        name, ty
+                    )))
+                }
+                None => {
+                    let name = self.resolve_function(name);
+                    match (self.globals.types.functions.get(&name), name.as_str()) {
+                        (Some((_, ret)), _) => ret.clone(),
+                        (None, "alloc" | "len") if args.len() == 1 => Type::WORD,
+                        _ => {
+                            return Err(Diagnostic::error(format!(
+                                "could not find function `{}`",
+                                name
+                            )))
+                        }
+                    }
+                }
+            },
+            Expr::Condition {
+                then,
+                other: Some(other),
+                ..
+            } => {
+                let then = self.type_of(then, expected, env, bound)?;
+                let other = self.type_of(other, Some(&then), env, bound)?;
+                match then.unify(&other) {
+                    Some(ty) => ty,
+                    None => {
+                        return Err(Diagnostic::error(format!(
+                            "branches of `if` have different types `{}` and `{}`",
+                            then, other
+                        )))
+                    }
+                }
+            }
+            Expr::Condition { other: None, .. } | Expr::Array(_) | Expr::Index { .. } => Type::WORD,
+            Expr::Struct { name, .. } => Type::Struct(name.clone()),
+            Expr::Field { target, field } => match self.type_of(target, None, env, bound)? {
+                Type::Struct(name) => self.field_index(&name, field)?.1,
+                Type::Pointer(inner) => match *inner {
+                    Type::Struct(name) => self.field_index(&name, field)?.1,
+                    ty => {
+                        return Err(Diagnostic::error(format!(
+                            "`*{}` has no field `{}`",
+                            ty, field
+                        )))
+                    }
+                },
+                ty => {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` has no field `{}`",
+                        ty, field
+                    )))
+                }
+            },
+            Expr::Ref(value) => match value.as_ref() {
+                Expr::Variable(name) if lookup(name, bound).is_none() => {
+                    let name = self.resolve_function(name);
+                    match self.globals.types.functions.get(&name) {
+                        Some((args, ret)) => Type::Function(args.clone(), Box::new(ret.clone())),
+                        None => {
+                            return Err(Diagnostic::error(format!(
+                                "could not find function `{}`",
+                                name
+                            )))
+                        }
+                    }
+                }
+                value => Type::Pointer(Box::new(self.type_of(value, None, env, bound)?)),
+            },
+            Expr::Block(body) => self.type_of(body.last().unwrap(), expected, env, bound)?,
+            Expr::Let { name, value, body } => {
+                let value = self.type_of(value, None, env, bound)?;
+                bound.push((name.clone(), value));
+                let body = self.type_of(body, expected, env, bound);
+                bound.pop();
+                body?
+            }
+            Expr::Lambda { args, ret, .. } => {
+                let (args, ret) = lambda_signature(args, ret, expected);
+                Type::Function(args, Box::new(ret))
+            }
+        })
+    }
+}
@@ -1,99 +1,367 @@
-use std::mem::replace;
+use std::mem::take;
 
+use crate::diagnostic::{Diagnostic, Span};
 use crate::operator::Operator;
 use crate::token::*;
+use crate::types::IntType;
 use crate::util::*;
 
 pub struct Lexer<'a> {
-    source: &'a Vec<u8>,
+    source: &'a str,
     index: usize,
-    parsed_buffer: Vec<u8>,
+    parsed_buffer: String,
+    retain_comments: bool,
+    // where the token emitted last starts
+    token_start: usize,
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = (Token, Vec<u8>);
+    type Item = Result<(Token, String, Span), Diagnostic>;
     fn next(&mut self) -> Option<Self::Item> {
-        Some(self.emit_token())
+        let token = self.emit_token();
+        Some(token.map(|(token, text)| (token, text, self.token_span())))
     }
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source: &Vec<u8>) -> Lexer {
+    pub fn new(source: &'a str) -> Lexer<'a> {
         Lexer {
             source,
             index: 0,
-            parsed_buffer: Vec::new(),
+            parsed_buffer: String::new(),
+            retain_comments: false,
+            token_start: 0,
         }
     }
 
-    fn consume_char(&mut self) -> &u8 {
-        if self.index < self.source.len() {
-            if !is_space(&self.source[self.index]) {
-                self.parsed_buffer.push(self.source[self.index]);
-            }
-            self.index += 1;
-            &self.source[self.index - 1]
-        } else {
-            &0
+    /// Like `new`, but comments are emitted as `Token::Comment` trivia
+    /// instead of being dropped, so a formatter can put them back.
+    pub fn with_trivia(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            retain_comments: true,
+            ..Lexer::new(source)
         }
     }
 
-    fn look_ahead(&self) -> &u8 {
-        if self.index < self.source.len() {
-            &self.source[self.index]
-        } else {
-            &0
+    fn consume_char(&mut self) -> Option<char> {
+        let ch = self.look_ahead()?;
+        if !is_space(ch) {
+            self.parsed_buffer.push(ch);
+        }
+        self.index += ch.len_utf8();
+        Some(ch)
+    }
+
+    fn look_ahead(&self) -> Option<char> {
+        self.look_ahead_at(0)
+    }
+
+    fn look_ahead_at(&self, offset: usize) -> Option<char> {
+        self.source[self.index..].chars().nth(offset)
+    }
+
+    // `#` up to the end of the line
+    fn skip_line_comment(&mut self) -> String {
+        let mut text = String::new();
+        while !matches!(self.look_ahead(), None | Some('\n')) {
+            text.extend(self.consume_char());
         }
+        text
     }
 
-    fn emit_op(&mut self) -> Token {
-        match self.consume_char() {
-            40 => Token::LeftParenthesis,
-            41 => Token::RightParenthesis,
-            42 => Token::Operator(Operator::Mul),
-            43 => Token::Operator(Operator::Add),
-            44 => Token::Comma,
-            45 => Token::Operator(Operator::Sub),
-            // 47 => Token::Operator(Operator::Div),
-            60 => Token::Operator(Operator::Les),
-            ch @ _ => panic!("{} is not valid here", ch),
+    // `/* ... */`, which may nest
+    fn skip_block_comment(&mut self) -> Result<String, Diagnostic> {
+        let start = self.index;
+        let mut text = String::new();
+        let mut depth = 0;
+        loop {
+            match (self.look_ahead(), self.look_ahead_at(1)) {
+                (Some('/'), Some('*')) => depth += 1,
+                (Some('*'), Some('/')) => depth -= 1,
+                (None, _) => {
+                    return Err(
+                        Diagnostic::error("unterminated block comment").with_span(Span {
+                            start,
+                            end: start + 2,
+                        }),
+                    )
+                }
+                _ => {
+                    text.extend(self.consume_char());
+                    continue;
+                }
+            }
+            text.extend(self.consume_char());
+            text.extend(self.consume_char());
+            if depth == 0 {
+                return Ok(text);
+            }
         }
     }
 
-    pub fn pop_parsed_buffer(&mut self) -> Vec<u8> {
-        replace(&mut self.parsed_buffer, Vec::new())
+    fn emit_op(&mut self) -> Result<Token, Diagnostic> {
+        let start = self.index;
+        Ok(match self.consume_char() {
+            None => Token::Eof,
+            Some('(') => Token::LeftParenthesis,
+            Some(')') => Token::RightParenthesis,
+            Some('*') if self.look_ahead() == Some('*') => {
+                self.consume_char();
+                Token::Operator(Operator::Pow)
+            }
+            Some('*') => Token::Operator(Operator::Mul),
+            Some('+') => Token::Operator(Operator::Add),
+            Some(',') => Token::Comma,
+            Some('-') if self.look_ahead() == Some('>') => {
+                self.consume_char();
+                Token::Arrow
+            }
+            Some('-') => Token::Operator(Operator::Sub),
+            Some('.') => Token::Dot,
+            Some(':') if self.look_ahead() == Some(':') => {
+                self.consume_char();
+                Token::PathSep
+            }
+            Some(':') => Token::Colon,
+            Some(';') => Token::Semicolon,
+            Some('&') => Token::Ampersand,
+            Some('|') => Token::Pipe,
+            Some('{') => Token::LeftBrace,
+            Some('}') => Token::RightBrace,
+            // Some('/') => Token::Operator(Operator::Div),
+            Some('<') => Token::Operator(Operator::Les),
+            Some('=') => Token::Operator(Operator::Assign),
+            Some('[') => Token::LeftBracket,
+            Some(']') => Token::RightBracket,
+            Some(ch) => {
+                return Err(
+                    Diagnostic::error(format!("`{}` is not valid here", ch.escape_debug()))
+                        .with_span(Span {
+                            start,
+                            end: self.index,
+                        }),
+                )
+            }
+        })
     }
 
-    pub fn emit_token(&mut self) -> (Token, Vec<u8>) {
-        while is_space(self.look_ahead()) {
+    // `42`, `0x2a`, `0b10_1010`, `0o52`, optionally followed by a type
+    // suffix such as `42u8`, or a decimal float like `4.25`.
+    fn emit_number(&mut self) -> Result<Token, Diagnostic> {
+        let start = self.index;
+        let (radix, base) = match (self.look_ahead(), self.look_ahead_at(1)) {
+            (Some('0'), Some('x')) => (16, "hexadecimal"),
+            (Some('0'), Some('o')) => (8, "octal"),
+            (Some('0'), Some('b')) => (2, "binary"),
+            _ => (10, "decimal"),
+        };
+        if radix != 10 {
+            self.consume_char();
             self.consume_char();
         }
-        let tok = match self.look_ahead() {
-            0 => Token::Eof,
-            ch @ _ if is_digit(ch) => {
-                let mut number: usize = 0;
-                while is_digit(self.look_ahead()) {
-                    number *= 10;
-                    number += usize::from(self.consume_char() - 48);
+
+        let mut digits = 0;
+        // `None` once the value no longer fits in 64 bits
+        let mut value = Some(0u64);
+        while let Some(ch) = self.look_ahead() {
+            if ch == '_' {
+                self.consume_char();
+                continue;
+            }
+            let digit = match ch.to_digit(radix) {
+                Some(digit) => digit,
+                None if is_digit(ch) => {
+                    let at = self.index;
+                    return Err(Diagnostic::error(format!(
+                        "invalid digit `{}` in {} literal",
+                        ch, base
+                    ))
+                    .with_span(Span {
+                        start: at,
+                        end: at + 1,
+                    }));
                 }
-                Token::Number(number)
+                None => break,
+            };
+            self.consume_char();
+            digits += 1;
+            value = value
+                .and_then(|value| value.checked_mul(radix.into()))
+                .and_then(|value| value.checked_add(digit.into()));
+        }
+        if radix == 10
+            && self.look_ahead() == Some('.')
+            && self.look_ahead_at(1).is_some_and(is_digit)
+        {
+            return self.emit_float(start);
+        }
+        if digits == 0 {
+            return Err(
+                Diagnostic::error(format!("no digits in {} literal", base)).with_span(Span {
+                    start,
+                    end: self.index,
+                }),
+            );
+        }
+
+        let suffix_start = self.index;
+        let mut suffix = String::new();
+        while let Some(ch) = self.look_ahead().filter(|&ch| is_ident_continue(ch)) {
+            self.consume_char();
+            suffix.push(ch);
+        }
+        let ty = match &suffix[..] {
+            "" => None,
+            _ => match IntType::from_suffix(&suffix) {
+                Some(ty) => Some(ty),
+                None => {
+                    return Err(Diagnostic::error(format!(
+                        "invalid suffix `{}` for number literal",
+                        suffix
+                    ))
+                    .with_span(Span {
+                        start: suffix_start,
+                        end: self.index,
+                    }))
+                }
+            },
+        };
+
+        let max = ty.map_or(u64::MAX, |ty| ty.max_value());
+        match value {
+            Some(value) if value <= max => Ok(Token::Number(value, ty)),
+            _ => Err(Diagnostic::error(format!(
+                "literal out of range for `{}`",
+                ty.unwrap_or(IntType::U64)
+            ))
+            .with_span(Span {
+                start,
+                end: self.index,
+            })),
+        }
+    }
+
+    fn emit_float(&mut self, start: usize) -> Result<Token, Diagnostic> {
+        self.consume_char();
+        while self
+            .look_ahead()
+            .is_some_and(|ch| is_digit(ch) || ch == '_')
+        {
+            self.consume_char();
+        }
+        if self.look_ahead().is_some_and(is_ident_continue) {
+            let suffix_start = self.index;
+            while self.look_ahead().is_some_and(is_ident_continue) {
+                self.consume_char();
             }
-            ch @ _ if is_alpha(ch) => {
-                let mut str: Vec<u8> = Vec::new();
-                while is_alnum(self.look_ahead()) {
-                    str.push(*self.consume_char());
+            return Err(
+                Diagnostic::error("float literals cannot have a suffix").with_span(Span {
+                    start: suffix_start,
+                    end: self.index,
+                }),
+            );
+        }
+        let text = self.source[start..self.index].replace('_', "");
+        Ok(Token::Float(text.parse().unwrap()))
+    }
+
+    // `"math.ks"`, with `\"`, `\\`, `\n` and `\t` escapes
+    fn emit_string(&mut self) -> Result<Token, Diagnostic> {
+        let start = self.index;
+        self.consume_char();
+        let mut text = String::new();
+        loop {
+            match self.consume_char() {
+                Some('"') => return Ok(Token::Str(text)),
+                Some('\\') => {
+                    let at = self.index;
+                    text.push(match self.consume_char() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(ch) => {
+                            return Err(Diagnostic::error(format!(
+                                "unknown escape `\\{}` in string",
+                                ch.escape_debug()
+                            ))
+                            .with_span(Span {
+                                start: at - 1,
+                                end: self.index,
+                            }))
+                        }
+                        None => continue,
+                    })
+                }
+                Some(ch) => text.push(ch),
+                None => {
+                    return Err(
+                        Diagnostic::error("unterminated string literal").with_span(Span {
+                            start,
+                            end: start + 1,
+                        }),
+                    )
+                }
+            }
+        }
+    }
+
+    /// Where the token `emit_token` returned last is in the source.
+    pub fn token_span(&self) -> Span {
+        Span {
+            start: self.token_start,
+            end: self.index,
+        }
+    }
+
+    pub fn pop_parsed_buffer(&mut self) -> String {
+        take(&mut self.parsed_buffer)
+    }
+
+    pub fn emit_token(&mut self) -> Result<(Token, String), Diagnostic> {
+        // left over from a token that failed to lex
+        self.parsed_buffer.clear();
+        loop {
+            while self.look_ahead().is_some_and(is_space) {
+                self.consume_char();
+            }
+            self.token_start = self.index;
+            let comment = match (self.look_ahead(), self.look_ahead_at(1)) {
+                (Some('#'), _) => self.skip_line_comment(),
+                (Some('/'), Some('*')) => self.skip_block_comment()?,
+                _ => break,
+            };
+            self.parsed_buffer.clear();
+            if self.retain_comments {
+                return Ok((Token::Comment(comment), String::new()));
+            }
+        }
+        let tok = match self.look_ahead() {
+            None => Token::Eof,
+            Some(ch) if is_digit(ch) => self.emit_number()?,
+            Some('"') => self.emit_string()?,
+            Some(ch) if is_ident_start(ch) => {
+                let mut str = String::new();
+                while let Some(ch) = self.look_ahead().filter(|&ch| is_ident_continue(ch)) {
+                    self.consume_char();
+                    str.push(ch);
                 }
                 match &str[..] {
-                    b"def" => Token::Def,
-                    b"extern" => Token::Extern,
-                    b"if" => Token::If,
-                    b"then" => Token::Then,
-                    b"else" => Token::Else,
+                    "def" => Token::Def,
+                    "extern" => Token::Extern,
+                    "struct" => Token::Struct,
+                    "if" => Token::If,
+                    "then" => Token::Then,
+                    "else" => Token::Else,
+                    "import" => Token::Import,
+                    "use" => Token::Use,
+                    "let" => Token::Let,
+                    "in" => Token::In,
                     _ => Token::Identifier(str),
                 }
             }
-            _ => self.emit_op(),
+            _ => self.emit_op()?,
         };
-        (tok, self.pop_parsed_buffer())
+        Ok((tok, self.pop_parsed_buffer()))
     }
 }
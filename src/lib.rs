@@ -0,0 +1,26 @@
+//! The language itself, from `Lexer` to the three backends. `main.rs` is the
+//! REPL and command line on top, the fuzz targets in `fuzz/` use it directly.
+//! `code_generator`, the JIT, is only there with the `jit` feature, which
+//! needs LLVM.
+
+#[cfg(feature = "jit")]
+extern crate inkwell as llvm;
+
+pub mod ast;
+pub mod bytecode;
+#[cfg(feature = "jit")]
+pub mod code_generator;
+pub mod const_fold;
+pub mod diagnostic;
+pub mod executor;
+pub mod lexer;
+pub mod lint;
+pub mod operator;
+pub mod parser;
+pub mod pretty;
+pub mod resolve;
+pub mod runtime;
+pub mod token;
+pub mod types;
+pub mod util;
+pub mod vm;
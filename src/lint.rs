@@ -0,0 +1,410 @@
+//! Warnings about code that runs but probably doesn't do what was meant.
+//! Every lint has a stable name, which its warnings show and `-W` and `-A`
+//! take to turn it on or off.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::const_fold;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::Lexer;
+use crate::operator::Operator;
+use crate::parser::Parser;
+use crate::types::Type;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    // a parameter the body never reads
+    UnusedParameters,
+    // a `let` whose body never reads it
+    UnusedBindings,
+    // the `else` of `if 1 then a else b`
+    UnreachableElse,
+    // a `def` of a name an `extern` already links to something else
+    ShadowedExtern,
+    // an `extern` nothing in its file calls or defines
+    UnusedExtern,
+    // `(a < b) * c`, which counts on comparisons being 1 or 0
+    ComparisonArithmetic,
+}
+
+impl Lint {
+    pub const ALL: &'static [Lint] = &[
+        Lint::UnusedParameters,
+        Lint::UnusedBindings,
+        Lint::UnreachableElse,
+        Lint::ShadowedExtern,
+        Lint::UnusedExtern,
+        Lint::ComparisonArithmetic,
+    ];
+
+    /// What the lint is called in warnings and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedParameters => "unused_parameters",
+            Lint::UnusedBindings => "unused_bindings",
+            Lint::UnreachableElse => "unreachable_else",
+            Lint::ShadowedExtern => "shadowed_extern",
+            Lint::UnusedExtern => "unused_extern",
+            Lint::ComparisonArithmetic => "comparison_arithmetic",
+        }
+    }
+}
+
+/// Which lints are reported, every one unless it's turned off.
+#[derive(Debug, Clone, Default)]
+pub struct Lints {
+    allowed: HashSet<Lint>,
+}
+
+impl Lints {
+    /// Turns the lint called `name` on or off, or every lint for `all`.
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let lints = match Lint::ALL.iter().find(|lint| lint.name() == name) {
+            Some(lint) => vec![*lint],
+            None if name == "all" => Lint::ALL.to_vec(),
+            None => {
+                let names: Vec<String> = Lint::ALL
+                    .iter()
+                    .map(|lint| format!("`{}`", lint.name()))
+                    .collect();
+                return Err(format!(
+                    "unknown lint `{}`, expected `all`, {}",
+                    name,
+                    names.join(", ")
+                ));
+            }
+        };
+        for lint in lints {
+            if enabled {
+                self.allowed.remove(&lint);
+            } else {
+                self.allowed.insert(lint);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, lint: Lint) -> bool {
+        !self.allowed.contains(&lint)
+    }
+}
+
+fn warning(lint: Lint, message: String, span: Option<Span>) -> (Lint, Diagnostic) {
+    let warning = Diagnostic::warning(message).with_code(lint.name());
+    match span {
+        Some(span) => (lint, warning.with_span(span)),
+        None => (lint, warning),
+    }
+}
+
+// What a `def` of the same name has to match to be the definition an
+// `extern` declared ahead of it.
+struct Extern {
+    path: String,
+    args: Vec<Type>,
+    ret: Type,
+    library: bool,
+}
+
+impl Extern {
+    fn new(path: &str, prototype: &Prototype) -> Extern {
+        let (args, ret) = prototype.signature();
+        Extern {
+            path: path.to_string(),
+            args,
+            ret,
+            library: prototype.library.is_some(),
+        }
+    }
+
+    // Only a `def` later in the same file with the same signature defines
+    // what was declared, anything else replaces what the `extern` would
+    // have linked to, like a native or a library's function.
+    fn is_defined_by(&self, path: &str, prototype: &Prototype) -> bool {
+        !self.library
+            && self.path == path
+            && (self.args.clone(), self.ret.clone()) == prototype.signature()
+    }
+}
+
+/// Lints the sources of a session one after the other, remembering the
+/// `extern`s declared so far to check the `def`s that come after them.
+#[derive(Default)]
+pub struct Linter {
+    lints: Lints,
+    externs: HashMap<String, Extern>,
+}
+
+impl Linter {
+    pub fn new(lints: Lints) -> Linter {
+        Linter {
+            lints,
+            externs: HashMap::new(),
+        }
+    }
+
+    /// Warnings for the file at `path`. Items that don't parse are skipped,
+    /// the backend reports them.
+    pub fn lint_file(&mut self, path: &str, source: &str) -> Vec<Diagnostic> {
+        self.lint_source(path, source, true)
+    }
+
+    /// Warnings for a line of the REPL, whose `extern`s the lines after it
+    /// may still use.
+    pub fn lint_line(&mut self, path: &str, source: &str) -> Vec<Diagnostic> {
+        self.lint_source(path, source, false)
+    }
+
+    fn lint_source(&mut self, path: &str, source: &str, whole: bool) -> Vec<Diagnostic> {
+        let mut warnings = Vec::new();
+        // the externs declared here, and the functions called or defined
+        let mut declared = Vec::new();
+        let mut used = HashSet::new();
+        let mut lex = Lexer::new(source);
+        for (item, _, spans) in Parser::new(&mut lex).flatten() {
+            if let Item::Function(Function {
+                prototype: Some(prototype),
+                body,
+            }) = &item
+            {
+                let name = &prototype.name;
+                if body.is_none() {
+                    declared.push((name.clone(), spans.name));
+                    self.externs
+                        .insert(name.clone(), Extern::new(path, prototype));
+                } else {
+                    used.insert(name.clone());
+                    match self.externs.remove(name) {
+                        Some(ext) if !ext.is_defined_by(path, prototype) => warnings.push(warning(
+                            Lint::ShadowedExtern,
+                            format!(
+                                "`{}` shadows the `extern` of the same name in {}",
+                                name, ext.path
+                            ),
+                            spans.name,
+                        )),
+                        _ => {}
+                    }
+                }
+            }
+            let mut pass = Body {
+                spans: &spans,
+                next: 0,
+                next_param: 0,
+                next_binding: 0,
+                scope: Vec::new(),
+                called: HashSet::new(),
+                warnings: Vec::new(),
+            };
+            pass.visit_item(&item);
+            used.extend(pass.called);
+            warnings.extend(pass.warnings);
+        }
+        if whole {
+            for (name, span) in declared {
+                if !used.contains(&name) {
+                    warnings.push(warning(
+                        Lint::UnusedExtern,
+                        format!("extern `{}` is never used or defined", name),
+                        span,
+                    ));
+                }
+            }
+        }
+        warnings.sort_by_key(|(_, warning)| warning.span.map(|span| span.start));
+        warnings
+            .into_iter()
+            .filter(|(lint, _)| self.lints.is_enabled(*lint))
+            .map(|(_, warning)| warning)
+            .collect()
+    }
+}
+
+struct Binding {
+    name: String,
+    // a parameter or a `let`, and where its name is
+    kind: Lint,
+    span: Option<Span>,
+    used: bool,
+}
+
+// Lints the expressions of one item, following the spans it was parsed with.
+struct Body<'a> {
+    spans: &'a Spans,
+    // which of `spans.exprs`, `spans.params` and `spans.bindings` come next
+    next: usize,
+    next_param: usize,
+    next_binding: usize,
+    // variables in scope, innermost last
+    scope: Vec<Binding>,
+    // functions called or taken the address of
+    called: HashSet<String>,
+    warnings: Vec<(Lint, Diagnostic)>,
+}
+
+impl Body<'_> {
+    // where the next expression visited is
+    fn next_span(&self) -> Option<Span> {
+        self.spans.exprs.get(self.next).copied()
+    }
+
+    fn bind_params(&mut self, args: &[(String, Option<Type>)]) {
+        for (name, _) in args {
+            let span = self.spans.params.get(self.next_param).copied();
+            self.next_param += 1;
+            self.scope.push(Binding {
+                name: name.clone(),
+                kind: Lint::UnusedParameters,
+                span,
+                used: false,
+            });
+        }
+    }
+
+    // Drops the bindings made since the scope was `outer` long.
+    fn unbind(&mut self, outer: usize) {
+        for binding in self.scope.split_off(outer) {
+            if binding.used || binding.name.starts_with('_') {
+                continue;
+            }
+            let what = match binding.kind {
+                Lint::UnusedParameters => "parameter",
+                _ => "binding",
+            };
+            self.warnings.push(warning(
+                binding.kind,
+                format!(
+                    "unused {} `{}`, prefix it with an underscore if this is intentional",
+                    what, binding.name
+                ),
+                binding.span,
+            ));
+        }
+    }
+
+    // Marks the variable `name` as read, or returns false if there is none.
+    fn read(&mut self, name: &str) -> bool {
+        match self
+            .scope
+            .iter_mut()
+            .rev()
+            .find(|binding| binding.name == name)
+        {
+            Some(binding) => {
+                binding.used = true;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn is_comparison(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Binary {
+            op: Operator::Les,
+            ..
+        }
+    )
+}
+
+// Whether the predicate is a literal once folded, and not zero.
+fn is_always_true(predicate: &Expr) -> bool {
+    match const_fold::fold_expr(predicate.clone()) {
+        Expr::Number { value, .. } => value != 0,
+        Expr::Float(value) => value != 0.0,
+        _ => false,
+    }
+}
+
+impl Visitor for Body<'_> {
+    fn visit_function(&mut self, function: &Function) {
+        match (&function.prototype, &function.body) {
+            (Some(prototype), Some(body)) => {
+                self.bind_params(&prototype.args);
+                self.visit_expr(body);
+                self.unbind(0);
+            }
+            _ => walk_function(self, function),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.next += 1;
+        walk_expr(self, expr);
+    }
+
+    fn visit_variable(&mut self, name: &str) {
+        // otherwise it's `&name` of a function
+        if !self.read(name) {
+            self.called.insert(name.to_string());
+        }
+    }
+
+    fn visit_call(&mut self, name: &str, args: &[Expr]) {
+        if !self.read(name) {
+            self.called.insert(name.to_string());
+        }
+        walk_call(self, name, args);
+    }
+
+    fn visit_binary(&mut self, op: Operator, lhs: &Expr, rhs: &Expr) {
+        let arithmetic = matches!(
+            op,
+            Operator::Add | Operator::Sub | Operator::Mul | Operator::Pow
+        );
+        for side in [lhs, rhs] {
+            if arithmetic && is_comparison(side) {
+                self.warnings.push(warning(
+                    Lint::ComparisonArithmetic,
+                    format!(
+                        "comparison used in arithmetic with `{}`, it counts as 1 if true and 0 if false",
+                        op
+                    ),
+                    self.next_span(),
+                ));
+            }
+            self.visit_expr(side);
+        }
+    }
+
+    fn visit_condition(&mut self, predicate: &Expr, then: &Expr, other: Option<&Expr>) {
+        self.visit_expr(predicate);
+        self.visit_expr(then);
+        if let Some(other) = other {
+            if is_always_true(predicate) {
+                self.warnings.push(warning(
+                    Lint::UnreachableElse,
+                    "unreachable `else`, the condition is always true".to_string(),
+                    self.next_span(),
+                ));
+            }
+            self.visit_expr(other);
+        }
+    }
+
+    fn visit_let(&mut self, name: &str, value: &Expr, body: &Expr) {
+        // the name comes before any `let` inside the value
+        let span = self.spans.bindings.get(self.next_binding).copied();
+        self.next_binding += 1;
+        self.visit_expr(value);
+        let outer = self.scope.len();
+        self.scope.push(Binding {
+            name: name.to_string(),
+            kind: Lint::UnusedBindings,
+            span,
+            used: false,
+        });
+        self.visit_expr(body);
+        self.unbind(outer);
+    }
+
+    fn visit_lambda(&mut self, args: &[(String, Option<Type>)], _ret: Option<&Type>, body: &Expr) {
+        let outer = self.scope.len();
+        self.bind_params(args);
+        self.visit_expr(body);
+        self.unbind(outer);
+    }
+}
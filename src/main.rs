@@ -1,27 +1,33 @@
+#[cfg(feature = "jit")]
 extern crate inkwell as llvm;
 
-use llvm::context::Context;
-use llvm::module::Module;
-use llvm::passes::PassManager;
-use llvm::values::FunctionValue;
-
-mod ast;
-mod code_generator;
-mod executor;
-mod lexer;
-mod operator;
-mod parser;
-mod token;
-mod util;
+#[cfg(feature = "jit")]
+use llvm::{
+    builder::Builder, context::Context, module::Module, passes::PassManager, values::FunctionValue,
+};
 
 use std::fs;
 use std::io::{stdin, stdout, Read, Write};
+use std::path::Path;
+use std::process;
+
+use llvm_tutorial::bytecode::Compiler;
+#[cfg(feature = "jit")]
+use llvm_tutorial::code_generator::{CodeGen, CodeGenOptions};
+use llvm_tutorial::const_fold;
+use llvm_tutorial::diagnostic::{Diagnostic, Span};
+use llvm_tutorial::executor::{Globals, Interpreter};
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::lint::{Linter, Lints};
+use llvm_tutorial::parser::Parser;
+use llvm_tutorial::pretty;
+use llvm_tutorial::token::Token;
+#[cfg(feature = "jit")]
+use llvm_tutorial::types::TypeTable;
+use llvm_tutorial::vm::Vm;
 
-use ast::Function;
-use code_generator::CodeGen;
-use lexer::Lexer;
-use parser::Parser;
-use util::*;
+// `abs`, `max`, `sqrt` and friends, available to every script
+const PRELUDE: &str = include_str!("prelude.ks");
 
 macro_rules! print_flush {
     ( $( $x:expr ),* ) => {
@@ -31,13 +37,16 @@ macro_rules! print_flush {
     };
 }
 
-fn fn_optimizer<'m, 'ctx>(module: &'m Module<'ctx>) -> PassManager<FunctionValue<'ctx>> {
+#[cfg(feature = "jit")]
+fn fn_optimizer<'ctx>(module: &Module<'ctx>) -> PassManager<FunctionValue<'ctx>> {
     let fpm = PassManager::create(module);
 
     fpm.add_instruction_combining_pass();
     fpm.add_reassociate_pass();
     fpm.add_gvn_pass();
     fpm.add_cfg_simplification_pass();
+    // turns self-recursive tail calls into loops so they run in constant stack
+    fpm.add_tail_call_elimination_pass();
     fpm.add_basic_alias_analysis_pass();
     fpm.add_promote_memory_to_register_pass();
     fpm.add_instruction_combining_pass();
@@ -48,37 +57,396 @@ fn fn_optimizer<'m, 'ctx>(module: &'m Module<'ctx>) -> PassManager<FunctionValue
     fpm
 }
 
-fn main() {
+#[cfg(feature = "jit")]
+fn run_source<'ctx>(
+    path: &str,
+    source: &str,
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    builder: &Builder<'ctx>,
+    types: &mut TypeTable,
+    options: CodeGenOptions,
+) {
+    let fpm = fn_optimizer(module);
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    CodeGen::new(&mut par, context, module, builder, &fpm, types, options)
+        .in_file(Path::new(path), None)
+        .run_to_end(path, source);
+}
+
+fn interpret_source(path: &str, source: &str, globals: &mut Globals) {
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    Interpreter::new(&mut par, globals)
+        .in_file(Path::new(path), None)
+        .run_to_end(path, source);
+}
+
+fn vm_source(path: &str, source: &str, vm: &mut Vm) {
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    Compiler::new(&mut par, vm)
+        .in_file(Path::new(path), None)
+        .run_to_end(path, source);
+}
+
+// `:ast <code>` shows what `CodeGen` gets to see, after constant folding
+fn dump_ast(source: &str) {
+    let mut lex = Lexer::new(source);
+    for result in Parser::new(&mut lex) {
+        match result {
+            Ok((item, _, _)) => println!("{:#?}", const_fold::fold_item(item)),
+            Err(err) => eprintln!("{}", err.render("<stdin>", source)),
+        }
+    }
+}
+
+// `:bytecode <code>` shows what the vm backend compiles the code to, with the
+// prelude's functions there to call but not shown
+fn dump_bytecode(source: &str, prelude: bool) {
+    let mut vm = Vm::default();
+    if prelude {
+        vm_source("<prelude>", PRELUDE, &mut vm);
+    }
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    Compiler::new(&mut par, &mut vm)
+        .showing_bytecode()
+        .run_to_end("<stdin>", source);
+}
+
+// `--dump=tokens` lists what `Lexer` makes of a file
+fn dump_tokens(path: &str, source: &str) {
+    let mut lex = Lexer::new(source);
+    loop {
+        match lex.emit_token() {
+            Ok((Token::Eof, _)) => break,
+            Ok((token, _)) => println!("{:?}", token),
+            Err(err) => {
+                eprintln!("{}", err.render(path, source));
+                break;
+            }
+        }
+    }
+}
+
+// `--dump=ast` shows items as `Parser` makes them, unlike `:ast` before any
+// folding, so precedence is still visible in `1 + 2 * 3`
+fn dump_items(path: &str, source: &str) {
+    let mut lex = Lexer::new(source);
+    for result in Parser::new(&mut lex) {
+        match result {
+            Ok((item, _, _)) => println!("{:#?}", item),
+            Err(err) => eprintln!("{}", err.render(path, source)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Dump {
+    Tokens,
+    Ast,
+    // the optimized IR `CodeGen` emits, with the prelude compiled but not shown
+    #[cfg(feature = "jit")]
+    Ir,
+}
+
+// `--dump=<stage>` prints what one stage of the pipeline makes of each file
+// instead of running it, see the golden tests.
+fn dump_files(dump: Dump, paths: Vec<String>) {
+    for path in paths {
+        if let Some(source) = read_source(&path) {
+            match dump {
+                Dump::Tokens => dump_tokens(&path, &source),
+                _ => dump_items(&path, &source),
+            }
+        }
+    }
+}
+
+// `--dump=ir`, which needs LLVM unlike the other stages
+#[cfg(feature = "jit")]
+fn dump_ir(prelude: bool, paths: Vec<String>, options: CodeGenOptions) {
     let context = Context::create();
     let module = context.create_module("preload");
     let builder = context.create_builder();
-    let fpm = fn_optimizer(&module);
-    // preload modules
-    for path in std::env::args().skip(1) {
-        let mut source = fs::File::open(&path).unwrap();
+    let mut types = TypeTable::default();
+    if prelude {
+        run_source(
+            "<prelude>",
+            PRELUDE,
+            &context,
+            &module,
+            &builder,
+            &mut types,
+            options,
+        );
+    }
+    let options = CodeGenOptions {
+        emit_ir: true,
+        ..options
+    };
+    for path in paths {
+        if let Some(source) = read_source(&path) {
+            run_source(
+                &path, &source, &context, &module, &builder, &mut types, options,
+            );
+        }
+    }
+}
 
-        let mut buf = Vec::new();
-        source.read_to_end(&mut buf).unwrap();
+#[derive(Clone, Copy)]
+enum Backend {
+    #[cfg(feature = "jit")]
+    Jit,
+    // `executor::Interpreter`, which doesn't need LLVM to run anything
+    Interp,
+    // `bytecode::Compiler` feeding a `vm::Vm`
+    Vm,
+}
+
+#[cfg(feature = "jit")]
+const DEFAULT_BACKEND: Backend = Backend::Jit;
+// the fastest of the rest
+#[cfg(not(feature = "jit"))]
+const DEFAULT_BACKEND: Backend = Backend::Vm;
 
-        let mut lex = Lexer::new(&buf);
-        let mut par = Parser::new(&mut lex);
-        let mut code_generator = CodeGen::new(&mut par, &context, &module, &builder, &fpm);
-        while let Some(_) = code_generator.emit_and_run() {}
+fn read_source(path: &str) -> Option<String> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(err) => {
+            let diag = Diagnostic::error(format!("could not read `{}`: {}", path, err));
+            eprintln!("{}", diag.render(path, ""));
+            return None;
+        }
+    };
+    match String::from_utf8(buf) {
+        Ok(source) => Some(source),
+        Err(err) => {
+            let start = err.utf8_error().valid_up_to();
+            let diag = Diagnostic::error("source is not valid UTF-8").with_span(Span {
+                start,
+                end: start + 1,
+            });
+            let source = String::from_utf8_lossy(err.as_bytes());
+            eprintln!("{}", diag.render(path, &source));
+            None
+        }
+    }
+}
+
+// `fmt [--check] [files]` rewrites each file the way `pretty` lays it out, or
+// with `--check` only lists the ones that would change. Without files it
+// formats stdin to stdout.
+fn format_files(args: Vec<String>) {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("error: unknown flag `{}` for `fmt`", arg);
+                process::exit(2);
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    let mut failed = false;
+    if paths.is_empty() {
+        let mut source = String::new();
+        stdin().read_to_string(&mut source).expect("Error reading");
+        match pretty::format_source(&source) {
+            Ok(formatted) if check => failed = formatted != source,
+            Ok(formatted) => {
+                print_flush!("{}", formatted);
+            }
+            Err(err) => {
+                eprintln!("{}", err.render("<stdin>", &source));
+                failed = true;
+            }
+        }
+    }
+    for path in paths {
+        let source = match read_source(&path) {
+            Some(source) => source,
+            None => {
+                failed = true;
+                continue;
+            }
+        };
+        match pretty::format_source(&source) {
+            Ok(formatted) if formatted == source => {}
+            Ok(_) if check => {
+                println!("{}", path);
+                failed = true;
+            }
+            Ok(formatted) => fs::write(&path, formatted).unwrap(),
+            Err(err) => {
+                eprintln!("{}", err.render(&path, &source));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+// `-W <lint>` and `-A <lint>` turn a lint on and off, see `lint::Lint`.
+fn set_lint(lints: &mut Lints, name: &str, enabled: bool) {
+    if let Err(err) = lints.set(name, enabled) {
+        eprintln!("error: {}", err);
+        process::exit(2);
+    }
+}
+
+fn print_warnings(warnings: Vec<Diagnostic>, path: &str, source: &str) {
+    for warning in warnings {
+        eprintln!("{}", warning.render(path, source));
+    }
+}
+
+// The prelude, then the files named on the command line, then the REPL, all
+// through the same `run`, each linted before it runs.
+fn run_session(prelude: bool, paths: Vec<String>, lints: Lints, mut run: impl FnMut(&str, &str)) {
+    let mut linter = Linter::new(lints);
+    if prelude {
+        // only for the externs it declares, which are there for scripts to
+        // use, not to warn about
+        linter.lint_file("<prelude>", PRELUDE);
+        run("<prelude>", PRELUDE);
+    }
+
+    // preload modules
+    for path in paths {
+        if let Some(source) = read_source(&path) {
+            print_warnings(linter.lint_file(&path, &source), &path, &source);
+            run(&path, &source);
+        }
     }
 
     loop {
         print_flush!("> ");
         let mut line = String::new();
-        stdin().read_line(&mut line).expect("Error reading");
+        // end of input, e.g. a script piped in or stdin closed
+        if stdin().read_line(&mut line).expect("Error reading") == 0 {
+            println!();
+            break;
+        }
         if line.chars().all(char::is_whitespace) {
             continue;
         } else if line.starts_with("exit") || line.starts_with("quit") {
             break;
+        } else if let Some(source) = line.strip_prefix(":ast") {
+            dump_ast(source);
+            continue;
+        } else if let Some(source) = line.strip_prefix(":bytecode") {
+            dump_bytecode(source, prelude);
+            continue;
+        }
+        print_warnings(linter.lint_line("<stdin>", &line), "<stdin>", &line);
+        run("<stdin>", &line);
+    }
+}
+
+fn main() {
+    #[cfg(feature = "jit")]
+    let mut options = CodeGenOptions::default();
+    let mut prelude = true;
+    let mut backend = DEFAULT_BACKEND;
+    let mut dump = None;
+    let mut lints = Lints::default();
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("fmt") {
+        args.next();
+        format_files(args.collect());
+        return;
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-W" | "-A" => {
+                let name = args.next().unwrap_or_default();
+                set_lint(&mut lints, &name, arg == "-W");
+            }
+            _ if arg.starts_with("-W") || arg.starts_with("-A") => {
+                set_lint(&mut lints, &arg[2..], arg.starts_with("-W"))
+            }
+            #[cfg(feature = "jit")]
+            "--bounds-checks" => options.bounds_checks = true,
+            // the other backends always check
+            #[cfg(not(feature = "jit"))]
+            "--bounds-checks" => {}
+            "--no-prelude" => prelude = false,
+            #[cfg(feature = "jit")]
+            "--backend=jit" => backend = Backend::Jit,
+            "--backend=interp" => backend = Backend::Interp,
+            "--backend=vm" => backend = Backend::Vm,
+            "--dump=tokens" => dump = Some(Dump::Tokens),
+            "--dump=ast" => dump = Some(Dump::Ast),
+            #[cfg(feature = "jit")]
+            "--dump=ir" => dump = Some(Dump::Ir),
+            #[cfg(not(feature = "jit"))]
+            "--backend=jit" | "--dump=ir" => {
+                eprintln!(
+                    "error: `{}` needs the JIT, build with `--features jit`",
+                    arg
+                );
+                process::exit(2);
+            }
+            _ if arg.starts_with("--dump=") => {
+                eprintln!(
+                    "error: unknown dump `{}`, expected `tokens`, `ast` or `ir`",
+                    &arg["--dump=".len()..]
+                );
+                process::exit(2);
+            }
+            _ if arg.starts_with("--backend=") => {
+                eprintln!(
+                    "error: unknown backend `{}`, expected `jit`, `interp` or `vm`",
+                    &arg["--backend=".len()..]
+                );
+                process::exit(2);
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    if let Some(dump) = dump {
+        match dump {
+            Dump::Tokens | Dump::Ast => dump_files(dump, paths),
+            #[cfg(feature = "jit")]
+            Dump::Ir => dump_ir(prelude, paths, options),
+        }
+        return;
+    }
+
+    match backend {
+        #[cfg(feature = "jit")]
+        Backend::Jit => {
+            let context = Context::create();
+            let module = context.create_module("preload");
+            let builder = context.create_builder();
+            let mut types = TypeTable::default();
+            run_session(prelude, paths, lints, |path, source| {
+                run_source(
+                    path, source, &context, &module, &builder, &mut types, options,
+                )
+            });
+        }
+        Backend::Interp => {
+            let mut globals = Globals::default();
+            run_session(prelude, paths, lints, |path, source| {
+                interpret_source(path, source, &mut globals)
+            });
+        }
+        Backend::Vm => {
+            let mut vm = Vm::default();
+            run_session(prelude, paths, lints, |path, source| {
+                vm_source(path, source, &mut vm)
+            });
         }
-        let buf = line.as_bytes().to_vec();
-        let mut lex = Lexer::new(&buf);
-        let mut par = Parser::new(&mut lex);
-        let mut code_generator = CodeGen::new(&mut par, &context, &module, &builder, &fpm);
-        while let Some(_) = code_generator.emit_and_run() {}
     }
 }
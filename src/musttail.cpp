@@ -0,0 +1,10 @@
+// LLVM 13's C API can mark a call `tail`, which the backend is free to
+// ignore, but not `musttail`, which it isn't. `build.rs` compiles this
+// against the LLVM `llvm-sys` links to.
+
+#include <llvm-c/Core.h>
+#include <llvm/IR/Instructions.h>
+
+extern "C" void ks_set_must_tail(LLVMValueRef call) {
+    llvm::unwrap<llvm::CallInst>(call)->setTailCallKind(llvm::CallInst::TCK_MustTail);
+}
@@ -1,50 +1,57 @@
-use std::cmp::Ordering;
+use std::fmt;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Operator {
-    // Assign,
+    Assign,
     Les,
     Add,
     Sub,
     Mul,
+    // wraps like `*` on integers, the exponent counted as unsigned
+    Pow,
     // Div,
     // Other,
 }
 
-impl Operator {
-    pub fn precedence(&self) -> i8 {
-        match self {
-            // Operator::Assign => 10,
-            Operator::Les => 20,
-            Operator::Add => 30,
-            Operator::Sub => 30,
-            Operator::Mul => 40,
-            // Operator::Div => 40,
-            // _ => 0,
-        }
-    }
+/// Which way a chain of operators with the same precedence groups.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Assoc {
+    // `a - b - c` is `(a - b) - c`
+    Left,
+    // `a = b = c` is `a = (b = c)`
+    Right,
+}
 
-    pub fn is_binary_op(&self) -> bool {
+impl Operator {
+    /// How tightly the operator binds, higher first, and how it groups with
+    /// itself. This is the whole of the expression grammar's precedence.
+    pub fn binding(&self) -> (i8, Assoc) {
         match self {
-            // Operator::Assign
-            Operator::Add
-            | Operator::Sub
-            | Operator::Mul
-            // | Operator::Div
-            | Operator::Les => true,
-            // _ => false,
+            Operator::Assign => (10, Assoc::Right),
+            Operator::Les => (20, Assoc::Left),
+            Operator::Add => (30, Assoc::Left),
+            Operator::Sub => (30, Assoc::Left),
+            Operator::Mul => (40, Assoc::Left),
+            // Operator::Div => (40, Assoc::Left),
+            Operator::Pow => (50, Assoc::Right),
         }
     }
-}
 
-impl PartialOrd for Operator {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    pub fn precedence(&self) -> i8 {
+        self.binding().0
     }
 }
 
-impl Ord for Operator {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.precedence().cmp(&other.precedence())
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Operator::Assign => "=",
+            Operator::Les => "<",
+            Operator::Add => "+",
+            Operator::Sub => "-",
+            Operator::Mul => "*",
+            Operator::Pow => "**",
+        };
+        write!(f, "{}", symbol)
     }
 }
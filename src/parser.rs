@@ -1,189 +1,713 @@
-use std::mem::replace;
+use std::cmp::Reverse;
+use std::mem::take;
 
 use crate::ast::*;
-use crate::lexer::Lexer;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::operator::{Assoc, Operator};
 use crate::token::*;
-use crate::util::*;
+use crate::types::{IntType, Type};
+
+type TokenStream<'a> = dyn Iterator<Item = Result<(Token, String, Span), Diagnostic>> + 'a;
+
+// Every pass after parsing recurses over the tree, an expression nested
+// deeper than this could overflow their stack.
+const MAX_NESTING: usize = 256;
 
 pub struct Parser<'a> {
-    lexer: &'a mut dyn Iterator<Item = (Token, Vec<u8>)>,
-    token: Token,
-    parsed_buffer: Vec<Vec<u8>>,
-    buffer: Vec<u8>,
+    lexer: &'a mut TokenStream<'a>,
+    token: Option<Token>,
+    parsed_buffer: Vec<String>,
+    buffer: String,
+    // where `token` is in the source
+    span: Span,
+    // from the first to the last token of the item being parsed
+    item_span: Option<Span>,
+    // every expression of the item so far, children before their parents
+    exprs: Vec<Span>,
+    // the item's name and parameters so far, see `Spans`
+    name: Option<Span>,
+    params: Vec<Span>,
+    bindings: Vec<Span>,
+    // how many primaries are being parsed inside each other
+    nesting: usize,
 }
 
 impl<'a> Iterator for Parser<'a> {
-    type Item = (Function, Vec<u8>);
+    type Item = Result<(Item, String, Spans), Diagnostic>;
     fn next(&mut self) -> Option<Self::Item> {
         self.emit_node()
     }
 }
 
+// Comments only matter to the formatter, the grammar never sees them.
+fn next_significant(lexer: &mut TokenStream) -> Result<(Token, String, Span), Diagnostic> {
+    loop {
+        match lexer.next().unwrap()? {
+            (Token::Comment(_), _, _) => continue,
+            next => return Ok(next),
+        }
+    }
+}
+
 impl<'a> Parser<'a> {
-    pub fn new(lexer: &'a mut dyn Iterator<Item = (Token, Vec<u8>)>) -> Parser<'a> {
-        let (token, buffer) = lexer.next().unwrap();
+    pub fn new(lexer: &'a mut TokenStream<'a>) -> Parser<'a> {
         Parser {
             lexer,
-            token,
+            token: None,
             parsed_buffer: Vec::new(),
-            buffer,
+            buffer: String::new(),
+            span: Span { start: 0, end: 0 },
+            item_span: None,
+            exprs: Vec::new(),
+            name: None,
+            params: Vec::new(),
+            bindings: Vec::new(),
+            nesting: 0,
+        }
+    }
+
+    fn consume_token(&mut self) -> Result<Token, Diagnostic> {
+        self.look_ahead()?;
+        let buf = take(&mut self.buffer);
+        self.parsed_buffer.push(buf);
+        self.item_span = Some(match self.item_span {
+            Some(span) => Span {
+                start: span.start,
+                end: self.span.end,
+            },
+            None => self.span,
+        });
+        Ok(self.token.take().unwrap())
+    }
+
+    fn look_ahead(&mut self) -> Result<&Token, Diagnostic> {
+        if self.token.is_none() {
+            let (tok, buf, span) = next_significant(self.lexer)?;
+            self.token = Some(tok);
+            self.buffer = buf;
+            self.span = span;
+        }
+        Ok(self.token.as_ref().unwrap())
+    }
+
+    // Runs `parse` one level deeper, unless that's too deep.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, Diagnostic>,
+    ) -> Result<T, Diagnostic> {
+        if self.nesting == MAX_NESTING {
+            return Err(Diagnostic::error(format!(
+                "Expression is nested more than {} deep",
+                MAX_NESTING
+            )));
         }
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
     }
 
-    fn consume_token(&mut self) -> Token {
-        let (tok, buf) = self.lexer.next().unwrap();
-        self.parsed_buffer.push(replace(&mut self.buffer, buf));
-        replace(&mut self.token, tok)
+    fn parse_primary(&mut self) -> Result<Expr, Diagnostic> {
+        self.nested(Self::parse_nested_primary)
     }
 
-    fn look_ahead(&self) -> &Token {
-        &self.token
+    // Where the next token starts, which is where whatever it begins starts.
+    fn next_start(&mut self) -> Result<usize, Diagnostic> {
+        self.look_ahead()?;
+        Ok(self.span.start)
     }
 
-    fn parse_primary(&mut self) -> Expr {
-        match self.consume_token() {
-            Token::Number(value) => Expr::Number(value),
+    // Notes that `expr` runs from `start` to the last token consumed.
+    fn spanned(&mut self, start: usize, expr: Expr) -> Expr {
+        let end = self.item_span.map_or(start, |span| span.end);
+        self.exprs.push(Span { start, end });
+        expr
+    }
+
+    fn parse_nested_primary(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.next_start()?;
+        let primary = match self.consume_token()? {
+            Token::Number(value, ty) => Expr::Number {
+                value,
+                ty,
+                // `consume_token` just kept the text of the token
+                text: self.parsed_buffer.last().unwrap().clone(),
+            },
+            Token::Float(value) => Expr::Float(value),
+            Token::Ampersand => Expr::Ref(Box::new(self.parse_primary()?)),
+            Token::LeftBrace => {
+                let body = match self.parse_sequence()? {
+                    // the braces make it a block, not the sequence inside them
+                    Expr::Block(body) => {
+                        self.exprs.pop();
+                        body
+                    }
+                    expr => vec![expr],
+                };
+                match self.consume_token()? {
+                    Token::RightBrace => Expr::Block(body),
+                    _ => return Err(Diagnostic::error("Expected to see `}` or `;` in block")),
+                }
+            }
             Token::LeftParenthesis => {
-                let ret = self.parse_expr();
-                match self.consume_token() {
-                    Token::RightParenthesis => ret,
-                    _ => panic!("Expected to see an right parenthesis!"),
+                let ret = self.parse_expr()?;
+                match self.consume_token()? {
+                    Token::RightParenthesis => return self.parse_postfix(start, ret),
+                    _ => return Err(Diagnostic::error("Expected to see an right parenthesis!")),
                 }
             }
-            Token::Identifier(name) => match self.look_ahead() {
-                Token::LeftParenthesis => {
-                    self.consume_token();
-                    let mut args = Vec::new();
-                    loop {
-                        args.push(self.parse_expr());
-                        match self.consume_token() {
-                            Token::Comma => continue,
-                            Token::RightParenthesis => break,
-                            _ => panic!(
-                                "Expected to see `)` or `,` in arguments list of {}",
-                                str_from_u8(&name)
-                            ),
+            Token::Identifier(name) => match self.look_ahead()? {
+                Token::PathSep => {
+                    let name = self.parse_path(name)?;
+                    match self.look_ahead()? {
+                        Token::LeftParenthesis => self.parse_call(name)?,
+                        _ => Expr::Variable(name),
+                    }
+                }
+                Token::LeftParenthesis => self.parse_call(name)?,
+                Token::LeftBrace => {
+                    self.consume_token()?;
+                    let mut fields = Vec::new();
+                    while let Token::Identifier(_) = self.look_ahead()? {
+                        let field = self.parse_identifier()?;
+                        self.expect_colon()?;
+                        fields.push((field, self.parse_expr()?));
+                        match self.look_ahead()? {
+                            Token::Comma => self.consume_token()?,
+                            _ => break,
                         };
                     }
-                    Expr::Call { name, args }
+                    match self.consume_token()? {
+                        Token::RightBrace => Expr::Struct { name, fields },
+                        _ => {
+                            return Err(Diagnostic::error(format!(
+                                "Expected to see `}}` or `,` in fields of {}",
+                                name
+                            )))
+                        }
+                    }
                 }
                 _ => Expr::Variable(name),
             },
             Token::If => {
-                let predicate = Box::new(self.parse_expr());
-                if let Token::Then = self.look_ahead() {
-                    self.consume_token();
-                    let then = Box::new(self.parse_expr());
-                    if let Token::Else = self.look_ahead() {
-                        self.consume_token();
-                        let other = Box::new(self.parse_expr());
-                        Expr::Condition {
-                            predicate,
-                            then,
-                            other,
+                let predicate = Box::new(self.parse_expr()?);
+                if let Token::Then = self.look_ahead()? {
+                    self.consume_token()?;
+                    let then = Box::new(self.parse_expr()?);
+                    let other = match self.look_ahead()? {
+                        Token::Else => {
+                            self.consume_token()?;
+                            Some(Box::new(self.parse_expr()?))
                         }
-                    } else {
-                        panic!("Expected to see else here");
+                        _ => None,
+                    };
+                    Expr::Condition {
+                        predicate,
+                        then,
+                        other,
                     }
                 } else {
-                    panic!("Expected to see then here.")
+                    return Err(Diagnostic::error("Expected to see then here."));
                 }
             }
-            tok @ _ => panic!("Expected to see a primary type here, but got {:?}", tok),
+            Token::Pipe => self.parse_lambda()?,
+            Token::Let => {
+                let name = self.parse_identifier()?;
+                self.bindings.push(self.span);
+                match self.consume_token()? {
+                    Token::Operator(Operator::Assign) => {}
+                    _ => return Err(Diagnostic::error("Expected to see `=` after let binding")),
+                }
+                let value = Box::new(self.parse_expr()?);
+                match self.consume_token()? {
+                    Token::In => {}
+                    _ => return Err(Diagnostic::error("Expected to see `in` after let binding")),
+                }
+                // the body runs as far as a `;` sequence would
+                let body = Box::new(self.parse_sequence()?);
+                Expr::Let { name, value, body }
+            }
+            Token::LeftBracket => {
+                let mut elements = Vec::new();
+                if let Token::RightBracket = self.look_ahead()? {
+                    self.consume_token()?;
+                } else {
+                    loop {
+                        elements.push(self.parse_expr()?);
+                        match self.consume_token()? {
+                            Token::Comma => continue,
+                            Token::RightBracket => break,
+                            _ => {
+                                return Err(Diagnostic::error(
+                                    "Expected to see `]` or `,` in array literal",
+                                ))
+                            }
+                        }
+                    }
+                }
+                Expr::Array(elements)
+            }
+            tok => {
+                return Err(Diagnostic::error(format!(
+                    "Expected to see a primary type here, but got {:?}",
+                    tok
+                )))
+            }
+        };
+        let primary = self.spanned(start, primary);
+        self.parse_postfix(start, primary)
+    }
+
+    fn parse_postfix(&mut self, start: usize, mut target: Expr) -> Result<Expr, Diagnostic> {
+        loop {
+            // whether the next token comes right after the target
+            let next = self.next_start()?;
+            let adjacent = self.item_span.is_some_and(|span| span.end == next);
+            target = match self.look_ahead()? {
+                Token::LeftBracket => {
+                    self.consume_token()?;
+                    let index = self.parse_expr()?;
+                    match self.consume_token()? {
+                        Token::RightBracket => {}
+                        _ => return Err(Diagnostic::error("Expected to see `]` after index")),
+                    }
+                    let index = Expr::Index {
+                        target: Box::new(target),
+                        index: Box::new(index),
+                    };
+                    self.spanned(start, index)
+                }
+                Token::Dot => {
+                    self.consume_token()?;
+                    let field = Expr::Field {
+                        target: Box::new(target),
+                        field: self.parse_identifier()?,
+                    };
+                    self.spanned(start, field)
+                }
+                // Only a function's name can be called. A `(` further on,
+                // the start of the next line say, starts the next item.
+                Token::LeftParenthesis if adjacent => {
+                    return Err(Diagnostic::error(
+                        "Only a name can be called, try `let f = ... in f(...)`",
+                    ))
+                }
+                _ => return Ok(target),
+            }
         }
     }
 
-    fn parse_binary_expr(&mut self, mut lhs: Expr, precedence: i8) -> Expr {
-        while let Token::Operator(op) = self.look_ahead() {
-            if precedence > op.precedence() {
-                break;
+    fn parse_call(&mut self, name: String) -> Result<Expr, Diagnostic> {
+        self.consume_token()?;
+        let mut args = Vec::new();
+        loop {
+            args.push(self.parse_expr()?);
+            match self.consume_token()? {
+                Token::Comma => continue,
+                Token::RightParenthesis => break,
+                _ => {
+                    return Err(Diagnostic::error(format!(
+                        "Expected to see `)` or `,` in arguments list of {}",
+                        name
+                    )))
+                }
+            };
+        }
+        Ok(Expr::Call { name, args })
+    }
+
+    // `math::sqrt`, given the leading `math`
+    fn parse_path(&mut self, mut path: String) -> Result<String, Diagnostic> {
+        while let Token::PathSep = self.look_ahead()? {
+            self.consume_token()?;
+            path += "::";
+            path += &self.parse_identifier()?;
+        }
+        Ok(path)
+    }
+
+    fn parse_identifier(&mut self) -> Result<String, Diagnostic> {
+        match self.consume_token()? {
+            Token::Identifier(name) => Ok(name),
+            tok => Err(Diagnostic::error(format!(
+                "Expected to see an identifier here, but got {:?}",
+                tok
+            ))),
+        }
+    }
+
+    fn expect_colon(&mut self) -> Result<(), Diagnostic> {
+        match self.consume_token()? {
+            Token::Colon => Ok(()),
+            tok => Err(Diagnostic::error(format!(
+                "Expected to see `:` here, but got {:?}",
+                tok
+            ))),
+        }
+    }
+
+    // `u8`, `f64`, `Point`, `*Point`, `fn(u64, u64) -> u64`
+    fn parse_type(&mut self) -> Result<Type, Diagnostic> {
+        match self.look_ahead()? {
+            Token::Operator(Operator::Mul) => {
+                self.consume_token()?;
+                return Ok(Type::Pointer(Box::new(self.parse_type()?)));
+            }
+            // `**Point` lexes as the power operator
+            Token::Operator(Operator::Pow) => {
+                self.consume_token()?;
+                let pointee = Type::Pointer(Box::new(self.parse_type()?));
+                return Ok(Type::Pointer(Box::new(pointee)));
             }
-            if let Token::Operator(op) = self.consume_token() {
-                let mut rhs = self.parse_primary();
-                while let Token::Operator(ahead) = self.look_ahead() {
-                    if ahead.is_binary_op() && op < *ahead {
-                        let next_prec = op.precedence() + (*ahead > op) as i8;
-                        rhs = self.parse_binary_expr(rhs, next_prec);
-                    } else {
-                        break;
+            _ => {}
+        }
+        let name = self.parse_identifier()?;
+        Ok(match IntType::from_suffix(&name) {
+            Some(ty) => Type::Int(ty),
+            None if name == "f64" => Type::F64,
+            None if name == "fn" => self.parse_function_type()?,
+            None => Type::Struct(name),
+        })
+    }
+
+    fn parse_function_type(&mut self) -> Result<Type, Diagnostic> {
+        match self.consume_token()? {
+            Token::LeftParenthesis => {}
+            _ => return Err(Diagnostic::error("Expected to see `(` in function type")),
+        }
+        let mut args = Vec::new();
+        if let Token::RightParenthesis = self.look_ahead()? {
+            self.consume_token()?;
+        } else {
+            loop {
+                args.push(self.parse_type()?);
+                match self.consume_token()? {
+                    Token::Comma => continue,
+                    Token::RightParenthesis => break,
+                    _ => {
+                        return Err(Diagnostic::error(
+                            "Expected to see `)` or `,` in function type",
+                        ))
                     }
                 }
-                lhs = Expr::Binary {
-                    op,
-                    lhs: Box::new(lhs),
-                    rhs: Box::new(rhs),
+            }
+        }
+        // like prototypes, the result is a word unless it says otherwise
+        let ret = match self.look_ahead()? {
+            Token::Arrow => {
+                self.consume_token()?;
+                self.parse_type()?
+            }
+            _ => Type::WORD,
+        };
+        Ok(Type::Function(args, Box::new(ret)))
+    }
+
+    // Precedence climbing: takes every operator binding at least as tightly
+    // as `min_precedence` into `lhs`. The right operand of a left-associative
+    // operator only takes operators binding tighter than it, that of a
+    // right-associative one takes its equals too.
+    fn parse_binary_expr(
+        &mut self,
+        start: usize,
+        mut lhs: Expr,
+        min_precedence: i8,
+    ) -> Result<Expr, Diagnostic> {
+        loop {
+            let op = match self.look_ahead()? {
+                Token::Operator(op) if op.precedence() >= min_precedence => *op,
+                _ => return Ok(lhs),
+            };
+            self.consume_token()?;
+            let (precedence, assoc) = op.binding();
+            let next_precedence = match assoc {
+                Assoc::Left => precedence + 1,
+                Assoc::Right => precedence,
+            };
+            let rhs = self.nested(|parser| {
+                let start = parser.next_start()?;
+                let rhs = parser.parse_primary()?;
+                parser.parse_binary_expr(start, rhs, next_precedence)
+            })?;
+            let binary = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+            lhs = self.spanned(start, binary);
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.next_start()?;
+        let lhs = self.parse_primary()?;
+        self.parse_binary_expr(start, lhs, 0)
+    }
+
+    // `|x, y: f64| -> f64 body`, after the opening `|`
+    fn parse_lambda(&mut self) -> Result<Expr, Diagnostic> {
+        let mut args = Vec::new();
+        loop {
+            match self.consume_token()? {
+                Token::Pipe => break,
+                Token::Comma => continue,
+                Token::Identifier(name) => {
+                    self.params.push(self.span);
+                    let ty = match self.look_ahead()? {
+                        Token::Colon => {
+                            self.consume_token()?;
+                            Some(self.parse_type()?)
+                        }
+                        _ => None,
+                    };
+                    args.push((name, ty));
+                }
+                tok => {
+                    return Err(Diagnostic::error(format!(
+                        "Expected to see `|` or an argument here, but got {:?}",
+                        tok
+                    )))
                 }
             }
         }
-        lhs
+        let ret = match self.look_ahead()? {
+            Token::Arrow => {
+                self.consume_token()?;
+                Some(self.parse_type()?)
+            }
+            _ => None,
+        };
+        let body = Box::new(self.parse_expr()?);
+        Ok(Expr::Lambda { args, ret, body })
     }
 
-    fn parse_expr(&mut self) -> Expr {
-        let lhs = self.parse_primary();
-        self.parse_binary_expr(lhs, 0)
+    // `a; b; c` binds looser than anything else, so it is only allowed
+    // where a whole body is expected rather than in `parse_expr`.
+    fn parse_sequence(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.next_start()?;
+        let mut exprs = vec![self.parse_expr()?];
+        while let Token::Semicolon = self.look_ahead()? {
+            self.consume_token()?;
+            exprs.push(self.parse_expr()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => self.spanned(start, Expr::Block(exprs)),
+        })
     }
 
-    fn parse_prototypes(&mut self) -> Prototype {
-        if let Token::Identifier(name) = self.consume_token() {
-            if let Token::LeftParenthesis = self.consume_token() {
+    fn parse_prototypes(&mut self) -> Result<Prototype, Diagnostic> {
+        if let Token::Identifier(name) = self.consume_token()? {
+            self.name = Some(self.span);
+            if let Token::LeftParenthesis = self.consume_token()? {
                 let mut args = Vec::new();
                 loop {
-                    match self.consume_token() {
+                    match self.consume_token()? {
                         Token::Comma => continue,
-                        Token::Identifier(name) => args.push(name),
+                        Token::Identifier(name) => {
+                            self.params.push(self.span);
+                            let ty = match self.look_ahead()? {
+                                Token::Colon => {
+                                    self.consume_token()?;
+                                    Some(self.parse_type()?)
+                                }
+                                _ => None,
+                            };
+                            args.push((name, ty))
+                        }
                         Token::RightParenthesis => break,
-                        tok @ _ => panic!("Unexpected token here {:?}", tok),
+                        tok => {
+                            return Err(Diagnostic::error(format!(
+                                "Unexpected token here {:?}",
+                                tok
+                            )))
+                        }
                     }
                 }
-                Prototype { name, args }
+                let ret = match self.look_ahead()? {
+                    Token::Arrow => {
+                        self.consume_token()?;
+                        Some(self.parse_type()?)
+                    }
+                    _ => None,
+                };
+                Ok(Prototype {
+                    name,
+                    args,
+                    ret,
+                    library: None,
+                })
             } else {
-                panic!("Expected to see `(` in `prototype");
+                Err(Diagnostic::error("Expected to see `(` in `prototype"))
             }
         } else {
-            panic!("Expected to see an identifier here");
+            Err(Diagnostic::error("Expected to see an identifier here"))
         }
     }
 
-    fn parse_def(&mut self) -> Function {
-        self.consume_token();
-        let prototype = Some(self.parse_prototypes());
-        let body = Some(self.parse_expr());
-        Function { prototype, body }
+    fn parse_def(&mut self) -> Result<Function, Diagnostic> {
+        self.consume_token()?;
+        let prototype = Some(self.parse_prototypes()?);
+        let body = Some(self.parse_sequence()?);
+        Ok(Function { prototype, body })
     }
 
-    fn parse_extern(&mut self) -> Function {
-        self.consume_token();
-        let prototype = Some(self.parse_prototypes());
-        Function {
+    fn parse_extern(&mut self) -> Result<Function, Diagnostic> {
+        self.consume_token()?;
+        let library = match self.look_ahead()? {
+            Token::Str(_) => match self.consume_token()? {
+                Token::Str(library) => Some(library),
+                _ => unreachable!(),
+            },
+            _ => None,
+        };
+        let mut prototype = self.parse_prototypes()?;
+        prototype.library = library;
+        let prototype = Some(prototype);
+        Ok(Function {
             prototype,
             body: None,
+        })
+    }
+
+    // `struct Point { x: f64, y: f64 }`
+    fn parse_struct(&mut self) -> Result<StructDef, Diagnostic> {
+        self.consume_token()?;
+        let name = self.parse_identifier()?;
+        self.name = Some(self.span);
+        if let Token::LeftBrace = self.consume_token()? {
+            let mut fields = Vec::new();
+            while let Token::Identifier(_) = self.look_ahead()? {
+                let field = self.parse_identifier()?;
+                self.expect_colon()?;
+                fields.push((field, self.parse_type()?));
+                match self.look_ahead()? {
+                    Token::Comma => self.consume_token()?,
+                    _ => break,
+                };
+            }
+            match self.consume_token()? {
+                Token::RightBrace => Ok(StructDef { name, fields }),
+                _ => Err(Diagnostic::error(format!(
+                    "Expected to see `}}` or `,` in struct {}",
+                    name
+                ))),
+            }
+        } else {
+            Err(Diagnostic::error("Expected to see `{` in struct"))
         }
     }
 
-    fn parse_top_level_expr(&mut self) -> Function {
-        Function {
-            prototype: None,
-            body: Some(self.parse_expr()),
+    fn parse_import(&mut self) -> Result<String, Diagnostic> {
+        self.consume_token()?;
+        match self.consume_token()? {
+            Token::Str(path) => Ok(path),
+            tok => Err(Diagnostic::error(format!(
+                "Expected to see a path like \"math.ks\" after import, but got {:?}",
+                tok
+            ))),
         }
     }
 
-    pub fn pop_parsed_buffer(&mut self) -> Vec<u8> {
-        replace(&mut self.parsed_buffer, Vec::new())
-            .into_iter()
-            .flatten()
-            .collect()
+    fn parse_use(&mut self) -> Result<String, Diagnostic> {
+        self.consume_token()?;
+        let first = self.parse_identifier()?;
+        let path = self.parse_path(first)?;
+        if !path.contains("::") {
+            return Err(Diagnostic::error(format!(
+                "Expected to see a path like `math::sqrt` after use, but got `{}`",
+                path
+            )));
+        }
+        Ok(path)
+    }
+
+    fn parse_top_level_expr(&mut self) -> Result<Function, Diagnostic> {
+        Ok(Function {
+            prototype: None,
+            body: Some(self.parse_sequence()?),
+        })
     }
 
-    pub fn emit_node(&mut self) -> Option<(Function, Vec<u8>)> {
-        let ret = match self.look_ahead() {
+    fn parse_node(&mut self) -> Result<Option<Item>, Diagnostic> {
+        Ok(match self.look_ahead()? {
             Token::Eof => None,
-            Token::Def => Some(self.parse_def()),
-            Token::Extern => Some(self.parse_extern()),
-            _ => Some(self.parse_top_level_expr()),
-        };
-        match ret {
-            Some(fun) => Some((fun, self.pop_parsed_buffer())),
-            None => None,
+            Token::Def => Some(Item::Function(self.parse_def()?)),
+            Token::Extern => Some(Item::Function(self.parse_extern()?)),
+            Token::Struct => Some(Item::Struct(self.parse_struct()?)),
+            Token::Import => Some(Item::Import(self.parse_import()?)),
+            Token::Use => Some(Item::Use(self.parse_use()?)),
+            _ => Some(Item::Function(self.parse_top_level_expr()?)),
+        })
+    }
+
+    // Drops tokens until something that can start a definition, so a
+    // single mistake doesn't cascade into errors for the rest of the input.
+    fn recover(&mut self) {
+        loop {
+            let at_item = matches!(
+                self.look_ahead(),
+                Ok(Token::Eof)
+                    | Ok(Token::Def)
+                    | Ok(Token::Extern)
+                    | Ok(Token::Struct)
+                    | Ok(Token::Import)
+                    | Ok(Token::Use)
+            );
+            if at_item {
+                break;
+            }
+            self.token = None;
+        }
+        self.parsed_buffer.clear();
+    }
+
+    pub fn pop_parsed_buffer(&mut self) -> String {
+        take(&mut self.parsed_buffer).concat()
+    }
+
+    /// Where the item `emit_node` returned last is in the source.
+    pub fn item_span(&self) -> Option<Span> {
+        self.item_span
+    }
+
+    // Where everything in the item just parsed is.
+    fn take_spans(&mut self) -> Spans {
+        let mut exprs = take(&mut self.exprs);
+        // an expression contains everything below it, so ordering them by
+        // where they start, outer ones first, puts parents before children
+        exprs.sort_by_key(|span| (span.start, Reverse(span.end)));
+        Spans {
+            item: self.item_span.unwrap_or(Span { start: 0, end: 0 }),
+            exprs,
+            name: self.name.take(),
+            params: take(&mut self.params),
+            bindings: take(&mut self.bindings),
+        }
+    }
+
+    pub fn emit_node(&mut self) -> Option<Result<(Item, String, Spans), Diagnostic>> {
+        self.item_span = None;
+        self.exprs.clear();
+        self.name = None;
+        self.params.clear();
+        self.bindings.clear();
+        match self.parse_node() {
+            Ok(Some(fun)) => {
+                let spans = self.take_spans();
+                Some(Ok((fun, self.pop_parsed_buffer(), spans)))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                // the lexer's errors say where they are, the parser's point
+                // at the token it gave up on
+                let err = match err.span {
+                    Some(_) => err,
+                    None => err.with_span(self.span),
+                };
+                self.recover();
+                Some(Err(err))
+            }
         }
     }
 }
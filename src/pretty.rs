@@ -0,0 +1,687 @@
+//! Turns the AST back into source. `Display` prints on one line with only
+//! the parentheses the parser needs to build the same tree again, and
+//! `format_source` lays out whole files the way `fmt` writes them.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::*;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::Lexer;
+use crate::operator::Assoc;
+use crate::parser::Parser;
+use crate::token::Token;
+use crate::types::Type;
+
+// blocks longer than this are broken over lines
+const WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+// What comes right after an expression in the source. `if`, `let` and
+// lambdas end in an expression of their own, which would keep going into
+// some of these unless they are parenthesized.
+#[derive(Clone, Copy, PartialEq)]
+enum Follow {
+    // `)`, `,`, `then`, `in` or the end of the item, which end anything
+    Nothing,
+    // a binary operator, `[` or `.`
+    Operator,
+    // the `else` of an enclosing `if`
+    Else,
+    // the `;` of an enclosing sequence
+    Semicolon,
+}
+
+// Whether the end of `expr` would parse `follow` as part of itself.
+fn swallows(expr: &Expr, follow: Follow) -> bool {
+    match (expr, follow) {
+        (_, Follow::Nothing) => false,
+        (Expr::Condition { .. } | Expr::Let { .. } | Expr::Lambda { .. }, Follow::Operator) => true,
+        (Expr::Condition { other: None, .. }, Follow::Else) => true,
+        // a `let` body is a whole sequence
+        (Expr::Let { .. }, Follow::Semicolon) => true,
+        (
+            Expr::Condition {
+                other: Some(last), ..
+            },
+            _,
+        )
+        | (Expr::Condition { then: last, .. }, _)
+        | (Expr::Let { body: last, .. }, _)
+        | (Expr::Lambda { body: last, .. }, _)
+        | (Expr::Binary { rhs: last, .. }, _)
+        | (Expr::Ref(last), _) => swallows(last, follow),
+        _ => false,
+    }
+}
+
+// Whether `operand` of an operator with `precedence` needs parentheses:
+// when it binds looser, or as tightly on the side the operator doesn't
+// group towards.
+fn binds_looser(operand: &Expr, precedence: i8, equal_too: bool) -> bool {
+    match operand {
+        Expr::Binary { op, .. } => {
+            op.precedence() < precedence || (equal_too && op.precedence() == precedence)
+        }
+        _ => false,
+    }
+}
+
+// `"math.ks"`, escaped the way the lexer reads it back
+fn quote(text: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn params(args: &[(String, Option<Type>)]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|(name, ty)| match ty {
+            Some(ty) => format!("{}: {}", name, ty),
+            None => name.clone(),
+        })
+        .collect();
+    args.join(", ")
+}
+
+fn list(exprs: &[Expr]) -> String {
+    let exprs: Vec<String> = exprs
+        .iter()
+        .map(|expr| Flat(expr, Follow::Nothing).to_string())
+        .collect();
+    exprs.join(", ")
+}
+
+// An expression on one line, knowing what follows it.
+struct Flat<'e>(&'e Expr, Follow);
+
+impl fmt::Display for Flat<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Flat(expr, follow) = *self;
+        if swallows(expr, follow) {
+            return write!(f, "({})", Flat(expr, Follow::Nothing));
+        }
+        match expr {
+            // `0xff` and `1_000` stay as they were written
+            Expr::Number { text, .. } => write!(f, "{}", text),
+            Expr::Float(value) => {
+                // `Display` never uses an exponent, but drops `.0`
+                let text = value.to_string();
+                match text.contains('.') || !value.is_finite() {
+                    true => write!(f, "{}", text),
+                    false => write!(f, "{}.0", text),
+                }
+            }
+            Expr::Variable(name) => write!(f, "{}", name),
+            Expr::Binary { op, lhs, rhs } => {
+                let (precedence, assoc) = op.binding();
+                match binds_looser(lhs, precedence, assoc == Assoc::Right) {
+                    true => write!(f, "({})", Flat(lhs, Follow::Nothing))?,
+                    false => write!(f, "{}", Flat(lhs, Follow::Operator))?,
+                }
+                write!(f, " {} ", op)?;
+                match binds_looser(rhs, precedence, assoc == Assoc::Left) {
+                    true => write!(f, "({})", Flat(rhs, Follow::Nothing)),
+                    false => write!(f, "{}", Flat(rhs, follow)),
+                }
+            }
+            Expr::Call { name, args } => write!(f, "{}({})", name, list(args)),
+            Expr::Condition {
+                predicate,
+                then,
+                other: Some(other),
+            } => write!(
+                f,
+                "if {} then {} else {}",
+                Flat(predicate, Follow::Nothing),
+                Flat(then, Follow::Else),
+                Flat(other, follow)
+            ),
+            Expr::Condition {
+                predicate,
+                then,
+                other: None,
+            } => write!(
+                f,
+                "if {} then {}",
+                Flat(predicate, Follow::Nothing),
+                Flat(then, follow)
+            ),
+            Expr::Array(elements) => write!(f, "[{}]", list(elements)),
+            Expr::Index { target, index } => {
+                write!(f, "{}[{}]", Target(target), Flat(index, Follow::Nothing))
+            }
+            Expr::Struct { name, fields } if fields.is_empty() => write!(f, "{} {{}}", name),
+            Expr::Struct { name, fields } => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(field, value)| format!("{}: {}", field, Flat(value, Follow::Nothing)))
+                    .collect();
+                write!(f, "{} {{ {} }}", name, fields.join(", "))
+            }
+            Expr::Field { target, field } => write!(f, "{}.{}", Target(target), field),
+            // `&` only takes a primary
+            Expr::Ref(value) if matches!(value.as_ref(), Expr::Binary { .. }) => {
+                write!(f, "&({})", Flat(value, Follow::Nothing))
+            }
+            Expr::Ref(value) => write!(f, "&{}", Flat(value, follow)),
+            Expr::Block(exprs) => {
+                write!(f, "{{ ")?;
+                for (i, expr) in exprs.iter().enumerate() {
+                    match i + 1 == exprs.len() {
+                        true => write!(f, "{}", Flat(expr, Follow::Nothing))?,
+                        false => write!(f, "{}; ", Flat(expr, Follow::Semicolon))?,
+                    }
+                }
+                write!(f, " }}")
+            }
+            Expr::Let { name, value, body } => write!(
+                f,
+                "let {} = {} in {}",
+                name,
+                Flat(value, Follow::Nothing),
+                Flat(body, follow)
+            ),
+            Expr::Lambda { args, ret, body } => {
+                write!(f, "|{}| ", params(args))?;
+                if let Some(ret) = ret {
+                    write!(f, "-> {} ", ret)?;
+                }
+                write!(f, "{}", Flat(body, follow))
+            }
+        }
+    }
+}
+
+// What `[index]` and `.field` apply to, which has to be a primary.
+struct Target<'e>(&'e Expr);
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            // `1.x` would lex as a float
+            Expr::Binary { .. } | Expr::Ref(_) | Expr::Number { .. } | Expr::Float(_) => {
+                write!(f, "({})", self.0)
+            }
+            target => write!(f, "{}", Flat(target, Follow::Operator)),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Flat(self, Follow::Nothing))
+    }
+}
+
+impl fmt::Display for Prototype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.name, params(&self.args))?;
+        match &self.ret {
+            Some(ret) => write!(f, " -> {}", ret),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.prototype, &self.body) {
+            (Some(proto), Some(body)) => write!(f, "def {} {}", proto, body),
+            (None, Some(body)) => write!(f, "{}", body),
+            (Some(proto), None) => match &proto.library {
+                Some(library) => write!(f, "extern {} {}", quote(library), proto),
+                None => write!(f, "extern {}", proto),
+            },
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for StructDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(name, ty)| format!("{}: {}", name, ty))
+            .collect();
+        write!(f, "struct {} {{ {} }}", self.name, fields.join(", "))
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Item::Function(fun) => write!(f, "{}", fun),
+            Item::Struct(def) => write!(f, "{}", def),
+            Item::Import(path) => write!(f, "import {}", quote(path)),
+            Item::Use(path) => write!(f, "use {}", path),
+        }
+    }
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    out.push_str(&" ".repeat(indent));
+}
+
+fn column(out: &str) -> usize {
+    out.len() - out.rfind('\n').map_or(0, |at| at + 1)
+}
+
+// `if`s always take lines of their own, and so does whatever holds one.
+fn needs_break(expr: &Expr) -> bool {
+    match expr {
+        Expr::Condition { .. } => true,
+        Expr::Block(exprs) => exprs.iter().any(needs_break),
+        Expr::Let { body, .. } => needs_break(body),
+        _ => false,
+    }
+}
+
+// Comments inside an item that `layout` puts back, by the expression they go
+// with: on lines of their own before a function body or a statement of a
+// block, or at the end of a statement's line.
+#[derive(Default)]
+struct Notes<'s> {
+    source: &'s str,
+    before: HashMap<*const Expr, Vec<Span>>,
+    after: HashMap<*const Expr, Span>,
+}
+
+impl Notes<'_> {
+    fn has(&self, expr: &Expr) -> bool {
+        let key = expr as *const Expr;
+        self.before.contains_key(&key) || self.after.contains_key(&key)
+    }
+
+    // Whether a comment goes with `expr` or anything in it.
+    fn within(&self, expr: &Expr) -> bool {
+        struct Search<'n, 's>(&'n Notes<'s>, bool);
+        impl Visitor for Search<'_, '_> {
+            fn visit_expr(&mut self, expr: &Expr) {
+                self.1 |= self.0.has(expr);
+                walk_expr(self, expr);
+            }
+        }
+        let mut search = Search(self, false);
+        search.visit_expr(expr);
+        search.1
+    }
+
+    // The comments before `expr`, each followed by a line at `indent`.
+    fn push_before(&self, out: &mut String, expr: &Expr, indent: usize) {
+        for comment in self
+            .before
+            .get(&(expr as *const Expr))
+            .into_iter()
+            .flatten()
+        {
+            out.push_str(&self.source[comment.start..comment.end]);
+            newline(out, indent);
+        }
+    }
+
+    fn push_after(&self, out: &mut String, expr: &Expr) {
+        if let Some(comment) = self.after.get(&(expr as *const Expr)) {
+            out.push(' ');
+            out.push_str(&self.source[comment.start..comment.end]);
+        }
+    }
+}
+
+// Appends `expr` where `out` stopped, taking more lines where the layout
+// calls for them, each indented by `indent`.
+fn layout(out: &mut String, expr: &Expr, indent: usize, follow: Follow, notes: &Notes) {
+    if swallows(expr, follow) {
+        out.push_str(&Flat(expr, follow).to_string());
+        return;
+    }
+    match expr {
+        Expr::Condition {
+            predicate,
+            then,
+            other,
+        } => {
+            out.push_str(&format!("if {} then", Flat(predicate, Follow::Nothing)));
+            let then_follow = match other {
+                Some(_) => Follow::Else,
+                None => follow,
+            };
+            branch(out, then, indent, then_follow, notes);
+            if let Some(other) = other {
+                match then.as_ref() {
+                    Expr::Block(_) => out.push_str(" else"),
+                    _ => {
+                        newline(out, indent);
+                        out.push_str("else");
+                    }
+                }
+                match other.as_ref() {
+                    // `else if` chains stay at the same depth
+                    Expr::Condition { .. } => {
+                        out.push(' ');
+                        layout(out, other, indent, follow, notes);
+                    }
+                    _ => branch(out, other, indent, follow, notes),
+                }
+            }
+        }
+        Expr::Block(exprs) => {
+            let flat = Flat(expr, follow).to_string();
+            // a comment only fits where a statement has a line of its own
+            let noted = exprs.iter().any(|expr| notes.within(expr));
+            if needs_break(expr) || noted || column(out) + flat.len() > WIDTH {
+                block(out, exprs, indent, notes);
+            } else {
+                out.push_str(&flat);
+            }
+        }
+        Expr::Let { name, value, body } => {
+            out.push_str(&format!(
+                "let {} = {} in",
+                name,
+                Flat(value, Follow::Nothing)
+            ));
+            match body.as_ref() {
+                Expr::Block(_) => out.push(' '),
+                body if needs_break(body) => newline(out, indent),
+                _ => out.push(' '),
+            }
+            layout(out, body, indent, follow, notes);
+        }
+        _ => out.push_str(&Flat(expr, follow).to_string()),
+    }
+}
+
+// A branch of an `if`: a block opens on the `then` or `else` line, anything
+// else goes on lines of its own one level deeper.
+fn branch(out: &mut String, expr: &Expr, indent: usize, follow: Follow, notes: &Notes) {
+    match expr {
+        Expr::Block(exprs) => {
+            out.push(' ');
+            block(out, exprs, indent, notes);
+        }
+        _ => {
+            newline(out, indent + INDENT);
+            layout(out, expr, indent + INDENT, follow, notes);
+        }
+    }
+}
+
+fn block(out: &mut String, exprs: &[Expr], indent: usize, notes: &Notes) {
+    out.push('{');
+    for (i, expr) in exprs.iter().enumerate() {
+        let last = i + 1 == exprs.len();
+        newline(out, indent + INDENT);
+        notes.push_before(out, expr, indent + INDENT);
+        match last {
+            true => layout(out, expr, indent + INDENT, Follow::Nothing, notes),
+            false => {
+                layout(out, expr, indent + INDENT, Follow::Semicolon, notes);
+                out.push(';');
+            }
+        }
+        notes.push_after(out, expr);
+    }
+    newline(out, indent);
+    out.push('}');
+}
+
+fn layout_item(item: &Item, notes: &Notes) -> String {
+    let mut out = String::new();
+    match item {
+        Item::Function(Function {
+            prototype: Some(proto),
+            body: Some(body),
+        }) => {
+            out.push_str(&format!("def {}", proto));
+            match body {
+                Expr::Block(_) => {
+                    out.push(' ');
+                    layout(&mut out, body, 0, Follow::Nothing, notes);
+                }
+                _ => {
+                    newline(&mut out, INDENT);
+                    notes.push_before(&mut out, body, INDENT);
+                    layout(&mut out, body, INDENT, Follow::Nothing, notes);
+                }
+            }
+        }
+        Item::Function(Function {
+            prototype: None,
+            body: Some(body),
+        }) => layout(&mut out, body, 0, Follow::Nothing, notes),
+        item => out.push_str(&item.to_string()),
+    }
+    out
+}
+
+fn comments(source: &str) -> Result<Vec<Span>, Diagnostic> {
+    let mut lex = Lexer::with_trivia(source);
+    let mut comments = Vec::new();
+    loop {
+        match lex.emit_token()? {
+            (Token::Eof, _) => return Ok(comments),
+            (Token::Comment(_), _) => comments.push(lex.token_span()),
+            _ => {}
+        }
+    }
+}
+
+// Every item in `source` with where its parts are, or the first syntax
+// error.
+fn items(source: &str) -> Result<Vec<(Item, Spans)>, Diagnostic> {
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    let mut items = Vec::new();
+    while let Some(result) = par.emit_node() {
+        let (item, _, spans) = result?;
+        items.push((item, spans));
+    }
+    Ok(items)
+}
+
+// The statements of an item a comment can go with: every expression of a
+// block and a function body that isn't a block.
+struct Statements<'a> {
+    // `Spans::exprs`, which come in the order the visitor meets them
+    spans: std::slice::Iter<'a, Span>,
+    all: HashMap<*const Expr, Span>,
+    found: Vec<(*const Expr, Span)>,
+}
+
+impl Visitor for Statements<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Some(&span) = self.spans.next() {
+            self.all.insert(expr as *const Expr, span);
+        }
+        walk_expr(self, expr);
+    }
+
+    fn visit_block(&mut self, exprs: &[Expr]) {
+        // after what's inside them, so inner statements come first
+        walk_block(self, exprs);
+        for expr in exprs {
+            let expr = expr as *const Expr;
+            if let Some(&span) = self.all.get(&expr) {
+                self.found.push((expr, span));
+            }
+        }
+    }
+}
+
+// Where the next token after `at` starts, past spaces and `comments`.
+fn next_token(source: &str, mut at: usize, comments: &[Span]) -> usize {
+    loop {
+        at = source.len() - source[at..].trim_start().len();
+        match comments.iter().find(|comment| comment.start == at) {
+            Some(comment) => at = comment.end,
+            None => return at,
+        }
+    }
+}
+
+// Where `layout` puts the comments `inside` an item, or `None` if one of
+// them isn't before or after a statement.
+fn notes<'s>(source: &'s str, item: &Item, spans: &Spans, inside: &[Span]) -> Option<Notes<'s>> {
+    let mut statements = Statements {
+        spans: spans.exprs.iter(),
+        all: HashMap::new(),
+        found: Vec::new(),
+    };
+    statements.visit_item(item);
+    if let Item::Function(Function {
+        prototype: Some(_),
+        body: Some(body),
+    }) = item
+    {
+        let span = statements.all.get(&(body as *const Expr));
+        if let (false, Some(&span)) = (matches!(body, Expr::Block(_)), span) {
+            statements.found.push((body, span));
+        }
+    }
+    // inner statements win over outer ones starting or ending with them
+    let mut starts = HashMap::new();
+    let mut ends = HashMap::new();
+    for (expr, span) in statements.found {
+        starts.entry(span.start).or_insert(expr);
+        ends.entry(span.end).or_insert(expr);
+    }
+
+    let blank = |text: &str| text.trim().is_empty();
+    let mut notes = Notes {
+        source,
+        ..Notes::default()
+    };
+    for &comment in inside {
+        let line = &source[..comment.start];
+        let line = &line[line.rfind('\n').map_or(0, |at| at + 1)..];
+        let rest = &source[comment.end..];
+        let rest = &rest[..rest.find('\n').unwrap_or(rest.len())];
+        if blank(line) {
+            let next = next_token(source, comment.end, inside);
+            notes
+                .before
+                .entry(*starts.get(&next)?)
+                .or_default()
+                .push(comment);
+        } else if blank(rest) {
+            let spaces = |ch: char| ch == ' ' || ch == '\t';
+            let head = source[..comment.start].trim_end_matches(spaces);
+            let head = head
+                .strip_suffix(';')
+                .unwrap_or(head)
+                .trim_end_matches(spaces);
+            notes.after.insert(*ends.get(&head.len())?, comment);
+        } else {
+            return None;
+        }
+    }
+    Some(notes)
+}
+
+// Whether `text` has the comments `inside` has in `source`, in that order.
+fn keeps_comments(source: &str, inside: &[Span], text: &str) -> bool {
+    let kept = match comments(text) {
+        Ok(kept) => kept,
+        Err(_) => return false,
+    };
+    kept.len() == inside.len()
+        && kept
+            .iter()
+            .zip(inside)
+            .all(|(kept, comment)| text[kept.start..kept.end] == source[comment.start..comment.end])
+}
+
+enum Piece {
+    Comment(Span),
+    // laid out anew, or copied as written if a comment inside has nowhere
+    // to go
+    Item(Span, Option<String>),
+}
+
+/// Formats a whole file: every item starts on a line of its own, function
+/// bodies go on the lines after `def` and `if`s are broken over lines and
+/// indented like in `Fib`. Comments and blank lines between items are kept.
+/// So are comments on lines of their own before a function body or a
+/// statement of a block, and at the end of a statement's line; an item with
+/// a comment anywhere else is left as written since the tree has no place
+/// for it. Formatting the result again changes nothing.
+pub fn format_source(source: &str) -> Result<String, Diagnostic> {
+    let items = items(source)?;
+    let mut comments = comments(source)?.into_iter().peekable();
+    let mut pieces = Vec::new();
+    for (item, spans) in &items {
+        let span = spans.item;
+        while let Some(&comment) = comments.peek() {
+            if comment.start >= span.start {
+                break;
+            }
+            pieces.push(Piece::Comment(comment));
+            comments.next();
+        }
+        let mut inside = Vec::new();
+        while let Some(&comment) = comments.peek() {
+            if comment.start >= span.end {
+                break;
+            }
+            inside.push(comment);
+            comments.next();
+        }
+        let text = notes(source, item, spans, &inside)
+            .map(|notes| layout_item(item, &notes))
+            .filter(|text| keeps_comments(source, &inside, text));
+        pieces.push(Piece::Item(span, text));
+    }
+    pieces.extend(comments.map(Piece::Comment));
+
+    let mut out = String::new();
+    let mut previous = None;
+    for piece in pieces {
+        let (span, text) = match &piece {
+            Piece::Comment(span) => (*span, None),
+            Piece::Item(span, text) => (*span, text.as_deref()),
+        };
+        if let Some(end) = previous {
+            let newlines = source[end..span.start].matches('\n').count();
+            match (&piece, newlines) {
+                // a comment after something on the same line stays there
+                (Piece::Comment(_), 0) => out.push(' '),
+                (_, 0 | 1) => out.push('\n'),
+                _ => out.push_str("\n\n"),
+            }
+        }
+        out.push_str(text.unwrap_or(&source[span.start..span.end]));
+        previous = Some(span.end);
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+
+    // only the layout may change, never what the program means
+    let before: Vec<String> = items
+        .iter()
+        .map(|(item, _)| format!("{:?}", item))
+        .collect();
+    let after: Vec<String> = self::items(&out)?
+        .iter()
+        .map(|(item, _)| format!("{:?}", item))
+        .collect();
+    if before != after {
+        return Err(Diagnostic::error(
+            "formatting would change what the file means, leaving it as it is",
+        ));
+    }
+    Ok(out)
+}
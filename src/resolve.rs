@@ -0,0 +1,153 @@
+//! Name resolution, run over every item before a backend compiles it: each
+//! call has to name a function that exists and pass it as many arguments as
+//! it takes, and each variable has to be bound. Every problem in the item is
+//! reported at once, pointing at where it is, instead of the backend giving
+//! up at the first one halfway through compiling it.
+
+use crate::ast::*;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::types::Type;
+
+// functions every backend has without a definition, with their arity
+const BUILTINS: &[(&str, usize)] = &[("alloc", 1), ("len", 1)];
+
+/// Checks the names used in `item`, which was parsed along with `spans`.
+/// `arity` is how many arguments the function called by a name takes,
+/// looked up the way the backend will look it up, or `None` if the backend
+/// doesn't know a function of that name.
+pub fn resolve_item(
+    item: &Item,
+    spans: &Spans,
+    arity: &dyn Fn(&str) -> Option<usize>,
+) -> Result<(), Diagnostic> {
+    let mut resolver = Resolver {
+        arity,
+        spans: &spans.exprs,
+        next: 0,
+        span: None,
+        itself: None,
+        scope: Vec::new(),
+        problems: Vec::new(),
+    };
+    resolver.visit_item(item);
+    match Diagnostic::joined(resolver.problems) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+struct Resolver<'a> {
+    arity: &'a dyn Fn(&str) -> Option<usize>,
+    spans: &'a [Span],
+    // which of `spans` the next expression visited is at
+    next: usize,
+    // where the expression being visited is
+    span: Option<Span>,
+    // the function being defined and its arity, which the backend only
+    // learns once it starts compiling it but its body may already call
+    itself: Option<(String, usize)>,
+    // variables in scope, innermost last
+    scope: Vec<String>,
+    problems: Vec<Diagnostic>,
+}
+
+impl Resolver<'_> {
+    fn report(&mut self, message: String) {
+        let problem = Diagnostic::error(message);
+        self.problems.push(match self.span {
+            Some(span) => problem.with_span(span),
+            None => problem,
+        });
+    }
+
+    fn is_bound(&self, name: &str) -> bool {
+        self.scope.iter().any(|bound| bound == name)
+    }
+
+    // Functions defined in the session win over builtins, like in the
+    // backends.
+    fn arity(&self, name: &str) -> Option<usize> {
+        match &self.itself {
+            Some((itself, arity)) if itself == name => Some(*arity),
+            _ => (self.arity)(name).or_else(|| {
+                BUILTINS
+                    .iter()
+                    .find(|(builtin, _)| *builtin == name)
+                    .map(|(_, arity)| *arity)
+            }),
+        }
+    }
+}
+
+impl Visitor for Resolver<'_> {
+    fn visit_function(&mut self, function: &Function) {
+        if let Some(prototype) = &function.prototype {
+            self.itself = Some((prototype.name.clone(), prototype.args.len()));
+            self.scope = prototype
+                .args
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect();
+        }
+        walk_function(self, function);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.span = self.spans.get(self.next).copied();
+        self.next += 1;
+        walk_expr(self, expr);
+    }
+
+    fn visit_variable(&mut self, name: &str) {
+        if !self.is_bound(name) {
+            self.report(format!("could not find variable `{}`", name));
+        }
+    }
+
+    fn visit_call(&mut self, name: &str, args: &[Expr]) {
+        // a variable holding a function shadows the function of that name,
+        // what it takes is only known once it's typed
+        if !self.is_bound(name) {
+            match self.arity(name) {
+                None => self.report(format!("could not find function `{}`", name)),
+                Some(arity) if arity != args.len() => self.report(format!(
+                    "`{}` takes {} argument{} but was given {}",
+                    name,
+                    arity,
+                    if arity == 1 { "" } else { "s" },
+                    args.len()
+                )),
+                Some(_) => {}
+            }
+        }
+        walk_call(self, name, args);
+    }
+
+    fn visit_ref(&mut self, value: &Expr) {
+        match value {
+            // `&fib` makes a closure of the function
+            Expr::Variable(name) if !self.is_bound(name) => {
+                if self.arity(name).is_none() {
+                    self.report(format!("could not find function `{}`", name));
+                }
+                // the name isn't a variable to look up, skip past it
+                self.next += 1;
+            }
+            value => self.visit_expr(value),
+        }
+    }
+
+    fn visit_let(&mut self, name: &str, value: &Expr, body: &Expr) {
+        self.visit_expr(value);
+        self.scope.push(name.to_string());
+        self.visit_expr(body);
+        self.scope.pop();
+    }
+
+    fn visit_lambda(&mut self, args: &[(String, Option<Type>)], _ret: Option<&Type>, body: &Expr) {
+        let outer = self.scope.len();
+        self.scope.extend(args.iter().map(|(name, _)| name.clone()));
+        self.visit_expr(body);
+        self.scope.truncate(outer);
+    }
+}
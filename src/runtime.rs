@@ -0,0 +1,223 @@
+use std::ffi::{c_void, CStr, CString};
+use std::process;
+
+use libffi::middle as ffi;
+
+use crate::types::IntType;
+
+// Called by generated code when `a[i]` is out of range and bounds checks are on.
+pub const BOUNDS_FAIL: &str = "__ks_bounds_fail";
+
+extern "C" fn bounds_fail(index: u64, len: u64) {
+    eprintln!(
+        "runtime error: index out of bounds: the len is {} but the index is {}",
+        len, index
+    );
+    process::exit(101);
+}
+
+// Called by generated code for `**`, which LLVM has no instruction for.
+pub const POW_INT: &str = "__ks_pow_int";
+pub const POW_F64: &str = "__ks_pow_f64";
+
+/// `base ** exponent` on integers, wrapping like `*` does. Narrower types
+/// truncate the result: products modulo 2^64 agree with them modulo every
+/// smaller power of two, signed or not.
+pub fn int_pow(mut base: u64, mut exponent: u64) -> u64 {
+    let mut result: u64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    result
+}
+
+extern "C" fn pow_int(base: u64, exponent: u64) -> u64 {
+    int_pow(base, exponent)
+}
+
+extern "C" fn pow_f64(base: f64, exponent: f64) -> f64 {
+    base.powf(exponent)
+}
+
+// `extern name(x: f64) -> f64` declarations answered by the libm behind
+// Rust's float methods, so they work without the library being linked in.
+macro_rules! natives {
+    ( $( $name:ident => $method:ident ),* ) => {
+        $(
+            extern "C" fn $name(x: f64) -> f64 {
+                x.$method()
+            }
+        )*
+
+        const NATIVES: &[(&str, extern "C" fn(f64) -> f64)] =
+            &[ $( (stringify!($name), $name) ),* ];
+    };
+}
+
+natives!(
+    sin => sin,
+    cos => cos,
+    tan => tan,
+    sqrt => sqrt,
+    exp => exp,
+    log => ln,
+    floor => floor,
+    ceil => ceil,
+    fabs => abs
+);
+
+/// Finds `name` in the shared library `library`, loading it if needed.
+pub fn load_symbol(library: &str, name: &str) -> Result<usize, String> {
+    let library = CString::new(library).map_err(|_| "path contains a NUL byte")?;
+    let name = CString::new(name).map_err(|_| "name contains a NUL byte")?;
+    unsafe {
+        // never closed, compiled code may call into it at any time
+        let handle = libc::dlopen(library.as_ptr(), libc::RTLD_NOW);
+        if handle.is_null() {
+            return Err(last_dl_error());
+        }
+        let symbol = libc::dlsym(handle, name.as_ptr());
+        if symbol.is_null() {
+            return Err(last_dl_error());
+        }
+        Ok(symbol as usize)
+    }
+}
+
+/// Finds `name` among the symbols the process already has, the way the JIT
+/// resolves an `extern` that names no library.
+pub fn find_symbol(name: &str) -> Result<usize, String> {
+    let name = CString::new(name).map_err(|_| "name contains a NUL byte")?;
+    let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    if symbol.is_null() {
+        return Err(last_dl_error());
+    }
+    Ok(symbol as usize)
+}
+
+/// The Rust definition of `BOUNDS_FAIL`, `POW_INT` or `POW_F64`, which
+/// generated code declares and calls.
+pub fn helper(name: &str) -> Option<usize> {
+    match name {
+        BOUNDS_FAIL => Some(bounds_fail as *const () as usize),
+        POW_INT => Some(pow_int as *const () as usize),
+        POW_F64 => Some(pow_f64 as *const () as usize),
+        _ => None,
+    }
+}
+
+/// The Rust definition answering `extern name(x: f64) -> f64`, if any.
+pub fn native(name: &str) -> Option<usize> {
+    NATIVES
+        .iter()
+        .find(|(native, _)| *native == name)
+        .map(|(_, function)| *function as usize)
+}
+
+/// An argument to or the result of `call_c`: the bits of an int of the C
+/// type `IntType` names, or a `double`.
+pub enum CValue {
+    Int(u64, IntType),
+    Float(f64),
+}
+
+/// The C type `call_c` expects a function to return.
+pub enum CType {
+    Int(IntType),
+    Float,
+}
+
+// An argument converted to the type C reads it as, for libffi to point at.
+enum CArg {
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F64(f64),
+}
+
+fn ffi_int(int: IntType) -> ffi::Type {
+    match int {
+        IntType::I8 => ffi::Type::i8(),
+        IntType::I16 => ffi::Type::i16(),
+        IntType::I32 => ffi::Type::i32(),
+        IntType::I64 => ffi::Type::i64(),
+        IntType::U8 => ffi::Type::u8(),
+        IntType::U16 => ffi::Type::u16(),
+        IntType::U32 => ffi::Type::u32(),
+        IntType::U64 => ffi::Type::u64(),
+    }
+}
+
+/// Calls the C function at `address` through libffi, which passes `args`
+/// the way the platform's C calling convention does whatever their number
+/// and types.
+///
+/// # Safety
+///
+/// `address` has to be a function taking `args` and returning `ret`.
+pub unsafe fn call_c(address: usize, args: &[CValue], ret: CType) -> CValue {
+    let c_args: Vec<CArg> = args
+        .iter()
+        .map(|arg| match *arg {
+            CValue::Int(bits, IntType::I8) => CArg::I8(bits as i8),
+            CValue::Int(bits, IntType::I16) => CArg::I16(bits as i16),
+            CValue::Int(bits, IntType::I32) => CArg::I32(bits as i32),
+            CValue::Int(bits, IntType::I64) => CArg::I64(bits as i64),
+            CValue::Int(bits, IntType::U8) => CArg::U8(bits as u8),
+            CValue::Int(bits, IntType::U16) => CArg::U16(bits as u16),
+            CValue::Int(bits, IntType::U32) => CArg::U32(bits as u32),
+            CValue::Int(bits, IntType::U64) => CArg::U64(bits),
+            CValue::Float(value) => CArg::F64(value),
+        })
+        .collect();
+    let ffi_args: Vec<ffi::Arg> = c_args
+        .iter()
+        .map(|arg| match arg {
+            CArg::I8(value) => ffi::arg(value),
+            CArg::I16(value) => ffi::arg(value),
+            CArg::I32(value) => ffi::arg(value),
+            CArg::I64(value) => ffi::arg(value),
+            CArg::U8(value) => ffi::arg(value),
+            CArg::U16(value) => ffi::arg(value),
+            CArg::U32(value) => ffi::arg(value),
+            CArg::U64(value) => ffi::arg(value),
+            CArg::F64(value) => ffi::arg(value),
+        })
+        .collect();
+    let arg_types = args.iter().map(|arg| match arg {
+        CValue::Int(_, int) => ffi_int(*int),
+        CValue::Float(_) => ffi::Type::f64(),
+    });
+    let function = ffi::CodePtr::from_ptr(address as *const c_void);
+    match ret {
+        // libffi widens ints narrower than a register to a whole one
+        CType::Int(int) => {
+            let cif = ffi::Cif::new(arg_types, ffi_int(int));
+            CValue::Int(int.truncate(cif.call::<u64>(function, &ffi_args)), int)
+        }
+        CType::Float => {
+            let cif = ffi::Cif::new(arg_types, ffi::Type::f64());
+            CValue::Float(cif.call(function, &ffi_args))
+        }
+    }
+}
+
+fn last_dl_error() -> String {
+    unsafe {
+        let err = libc::dlerror();
+        if err.is_null() {
+            String::from("unknown error")
+        } else {
+            CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::operator::Operator;
+use crate::types::IntType;
 
 #[derive(Debug)]
 pub enum Token {
@@ -6,12 +7,31 @@ pub enum Token {
     Eof,
     Def,
     Extern,
+    Struct,
     If,
     Then,
     Else,
+    Import,
+    Use,
+    Let,
+    In,
     LeftParenthesis,
     RightParenthesis,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
     Comma,
-    Identifier(Vec<u8>),
-    Number(usize),
+    Semicolon,
+    Dot,
+    Colon,
+    PathSep,
+    Arrow,
+    Ampersand,
+    Pipe,
+    Identifier(String),
+    Str(String),
+    Number(u64, Option<IntType>),
+    Float(f64),
+    Comment(String),
 }
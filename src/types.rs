@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int(IntType),
+    F64,
+    Struct(String),
+    Pointer(Box<Type>),
+    // `fn(u64, u64) -> u64`, a pointer to a function with that signature
+    Function(Vec<Type>, Box<Type>),
+}
+
+impl Type {
+    /// What unannotated parameters, results and literals are.
+    pub const WORD: Type = Type::Int(IntType::U64);
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Type::Int(_) | Type::F64)
+    }
+
+    /// The type of a number literal, unsuffixed ones take the type they're
+    /// expected to have if they fit it.
+    pub fn literal(value: u64, suffix: Option<IntType>, expected: Option<&Type>) -> Type {
+        match (suffix, expected) {
+            (Some(ty), _) => Type::Int(ty),
+            (None, Some(Type::Int(int))) if value <= int.max_value() => Type::Int(*int),
+            (None, Some(Type::F64)) => Type::F64,
+            (None, _) => Type::WORD,
+        }
+    }
+
+    /// The type both sides of a binary operation or `if` are converted to:
+    /// `f64` wins over integers and the wider integer over the narrower.
+    pub fn unify(&self, other: &Type) -> Option<Type> {
+        match (self, other) {
+            (Type::F64, ty) | (ty, Type::F64) if ty.is_numeric() => Some(Type::F64),
+            (Type::Int(lhs), Type::Int(rhs)) if rhs.bits() > lhs.bits() => Some(other.clone()),
+            (Type::Int(_), Type::Int(_)) => Some(self.clone()),
+            _ if self == other => Some(self.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Argument and result types of a lambda, unannotated ones are taken from
+/// the function type it's `expected` to have, or are words.
+pub fn lambda_signature(
+    args: &[(String, Option<Type>)],
+    ret: &Option<Type>,
+    expected: Option<&Type>,
+) -> (Vec<Type>, Type) {
+    let (arg_hints, ret_hint) = match expected {
+        Some(Type::Function(hints, ret)) if hints.len() == args.len() => {
+            (hints.clone(), Some(*ret.clone()))
+        }
+        _ => (Vec::new(), None),
+    };
+    let arg_types = args
+        .iter()
+        .enumerate()
+        .map(|(i, (_, ty))| {
+            ty.clone()
+                .or_else(|| arg_hints.get(i).cloned())
+                .unwrap_or(Type::WORD)
+        })
+        .collect();
+    (arg_types, ret.clone().or(ret_hint).unwrap_or(Type::WORD))
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int(ty) => write!(f, "{}", ty),
+            Type::F64 => write!(f, "f64"),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Pointer(ty) => write!(f, "*{}", ty),
+            Type::Function(args, ret) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "fn({}) -> {}", args.join(", "), ret)
+            }
+        }
+    }
+}
+
+/// Declarations that outlive a single `CodeGen`, so a struct or function
+/// defined on one REPL line can be used on the next.
+#[derive(Default)]
+pub struct TypeTable {
+    pub structs: HashMap<String, Vec<(String, Type)>>,
+    pub functions: HashMap<String, (Vec<Type>, Type)>,
+    // files already compiled, so importing one twice is free
+    pub imported: HashSet<PathBuf>,
+    // files being compiled right now, innermost last, to catch cycles
+    pub importing: Vec<PathBuf>,
+    // addresses of functions bound by `extern "library"`
+    pub symbols: HashMap<String, usize>,
+}
+
+impl TypeTable {
+    /// Fails if one of `types` names a struct that isn't defined.
+    pub fn check_types<'t>(&self, types: impl IntoIterator<Item = &'t Type>) -> Result<(), String> {
+        match types
+            .into_iter()
+            .find_map(|ty| self.unknown_struct(ty, None))
+        {
+            Some(name) => Err(format!("could not find type `{}`", name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Like `check_types` for the fields of struct `name`, which can point
+    /// back at it but not contain it.
+    pub fn check_fields(&self, name: &str, fields: &[(String, Type)]) -> Result<(), String> {
+        for (_, ty) in fields {
+            if let Type::Struct(field) = ty {
+                if field == name {
+                    return Err(format!(
+                        "`{}` can't contain itself, only a pointer to it",
+                        name
+                    ));
+                }
+            }
+            if let Some(unknown) = self.unknown_struct(ty, Some(name)) {
+                return Err(format!("could not find type `{}`", unknown));
+            }
+        }
+        Ok(())
+    }
+
+    fn unknown_struct<'t>(&self, ty: &'t Type, itself: Option<&str>) -> Option<&'t str> {
+        match ty {
+            Type::Struct(name) if Some(name.as_str()) != itself => {
+                (!self.structs.contains_key(name)).then_some(name.as_str())
+            }
+            Type::Pointer(ty) => self.unknown_struct(ty, itself),
+            Type::Function(args, ret) => args
+                .iter()
+                .chain(Some(ret.as_ref()))
+                .find_map(|ty| self.unknown_struct(ty, itself)),
+            _ => None,
+        }
+    }
+
+    /// Fails if `name` is already declared with another signature; a
+    /// function can be defined again, but only as what callers expect.
+    pub fn check_signature(&self, name: &str, args: &[Type], ret: &Type) -> Result<(), String> {
+        match self.functions.get(name) {
+            Some((declared, declared_ret)) if declared != args || declared_ret != ret => {
+                Err(format!(
+                    "`{}` is already declared as `{}`",
+                    name,
+                    Type::Function(declared.clone(), Box::new(declared_ret.clone()))
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The key to import `path` under, or `None` if it already was imported.
+    /// Importing a file that is still being compiled is a cycle.
+    pub fn import_key(&self, path: &Path) -> Result<Option<PathBuf>, String> {
+        let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(start) = self.importing.iter().position(|p| *p == key) {
+            let cycle: Vec<String> = self.importing[start..]
+                .iter()
+                .chain(std::iter::once(&key))
+                .map(|p| {
+                    p.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect();
+            return Err(format!("import cycle: {}", cycle.join(" -> ")));
+        }
+        if self.imported.contains(&key) {
+            return Ok(None);
+        }
+        Ok(Some(key))
+    }
+}
+
+/// Width and signedness a literal was written with, e.g. the `u8` in `1u8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl IntType {
+    pub fn from_suffix(suffix: &str) -> Option<IntType> {
+        Some(match suffix {
+            "i8" => IntType::I8,
+            "i16" => IntType::I16,
+            "i32" => IntType::I32,
+            "i64" => IntType::I64,
+            "u8" => IntType::U8,
+            "u16" => IntType::U16,
+            "u32" => IntType::U32,
+            "u64" => IntType::U64,
+            _ => return None,
+        })
+    }
+
+    pub fn bits(&self) -> u32 {
+        match self {
+            IntType::I8 | IntType::U8 => 8,
+            IntType::I16 | IntType::U16 => 16,
+            IntType::I32 | IntType::U32 => 32,
+            IntType::I64 | IntType::U64 => 64,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            IntType::I8 | IntType::I16 | IntType::I32 | IntType::I64
+        )
+    }
+
+    /// Largest value a literal of this type may spell out.
+    pub fn max_value(&self) -> u64 {
+        let bits = self.bits() - self.is_signed() as u32;
+        u64::MAX >> (64 - bits)
+    }
+
+    /// Wraps `bits` around to this width, zero-extended back to 64 bits.
+    pub fn truncate(&self, bits: u64) -> u64 {
+        bits & (u64::MAX >> (64 - self.bits()))
+    }
+
+    /// The value of `bits` truncated to this width, read as signed.
+    pub fn sign_extend(&self, bits: u64) -> i64 {
+        let shift = 64 - self.bits();
+        ((bits << shift) as i64) >> shift
+    }
+}
+
+impl fmt::Display for IntType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.is_signed() { 'i' } else { 'u' };
+        write!(f, "{}{}", sign, self.bits())
+    }
+}
@@ -1,21 +1,17 @@
-use std::str;
+use unicode_xid::UnicodeXID;
 
-pub fn is_space(c: &u8) -> bool {
-    [10, 13, 32].contains(c)
+pub fn is_space(c: char) -> bool {
+    c.is_whitespace()
 }
 
-pub fn is_digit(c: &u8) -> bool {
-    (48..58).contains(c)
+pub fn is_digit(c: char) -> bool {
+    c.is_ascii_digit()
 }
 
-pub fn is_alpha(c: &u8) -> bool {
-    (65..91).contains(c) || (97..123).contains(c)
+pub fn is_ident_start(c: char) -> bool {
+    c == '_' || c.is_xid_start()
 }
 
-pub fn is_alnum(c: &u8) -> bool {
-    is_digit(c) || is_alpha(c)
-}
-
-pub fn str_from_u8(v: &Vec<u8>) -> &str {
-    str::from_utf8(v).unwrap()
+pub fn is_ident_continue(c: char) -> bool {
+    c.is_xid_continue()
 }
@@ -0,0 +1,429 @@
+use crate::bytecode::{Chunk, Num, Op};
+use crate::diagnostic::Diagnostic;
+use crate::runtime::{self, CType, CValue};
+use crate::types::{Type, TypeTable};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::replace;
+use std::rc::Rc;
+
+// deep enough for any sensible recursion, shallow enough to stop runaway
+// recursion long before memory runs out
+const MAX_FRAMES: usize = 1 << 20;
+
+/// A value on the stack. The compiler already checked every type, so unlike
+/// the interpreter's values these don't carry one.
+#[derive(Clone, Debug)]
+pub enum Slot {
+    // zero-extended from the width the compiler gave it
+    Int(u64),
+    F64(f64),
+    // fields in declaration order, copied before a write through a pointer
+    Struct(Rc<Vec<Slot>>),
+    Pointer(Rc<RefCell<Slot>>),
+    Closure(Rc<Closure>),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: usize,
+    // passed after the arguments, see `Compiler::lambda`
+    pub captures: Vec<Slot>,
+}
+
+pub enum Body {
+    Chunk(Rc<Chunk>),
+    // an `extern`, with its address once it's known
+    Foreign(Option<usize>),
+}
+
+pub struct Function {
+    pub name: String,
+    pub args: Vec<Type>,
+    pub ret: Type,
+    pub body: Body,
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    // where the frame's locals start on the stack, arguments first
+    base: usize,
+}
+
+/// Every function compiled so far and the machine running them. Like the
+/// preload module of the JIT it outlives a single `Compiler`.
+#[derive(Default)]
+pub struct Vm {
+    pub types: TypeTable,
+    pub functions: Vec<Function>,
+    // the function of each name, which a redefinition changes the body of
+    pub names: HashMap<String, usize>,
+    // arrays are words like in the generated code, the word indexes this
+    arrays: Vec<Vec<u64>>,
+    stack: Vec<Slot>,
+    frames: Vec<Frame>,
+}
+
+fn word(slot: Slot) -> u64 {
+    match slot {
+        Slot::Int(bits) => bits,
+        _ => unreachable!("the compiler only passes words here"),
+    }
+}
+
+fn truth(slot: &Slot) -> bool {
+    match slot {
+        Slot::Int(bits) => *bits != 0,
+        // ordered, so NaN is false like `fcmp one`
+        Slot::F64(value) => *value != 0.0 && !value.is_nan(),
+        _ => unreachable!("the compiler only allows numbers as conditions"),
+    }
+}
+
+fn arithmetic(op: Op, lhs: Slot, rhs: Slot) -> Slot {
+    match (op, lhs, rhs) {
+        (Op::Add(Num::F64), Slot::F64(lhs), Slot::F64(rhs)) => Slot::F64(lhs + rhs),
+        (Op::Sub(Num::F64), Slot::F64(lhs), Slot::F64(rhs)) => Slot::F64(lhs - rhs),
+        (Op::Mul(Num::F64), Slot::F64(lhs), Slot::F64(rhs)) => Slot::F64(lhs * rhs),
+        (Op::Pow(Num::F64), Slot::F64(lhs), Slot::F64(rhs)) => Slot::F64(lhs.powf(rhs)),
+        (Op::Less(Num::F64), Slot::F64(lhs), Slot::F64(rhs)) => Slot::Int((lhs < rhs) as u64),
+        (Op::Add(Num::Int(int)), Slot::Int(lhs), Slot::Int(rhs)) => {
+            Slot::Int(int.truncate(lhs.wrapping_add(rhs)))
+        }
+        (Op::Sub(Num::Int(int)), Slot::Int(lhs), Slot::Int(rhs)) => {
+            Slot::Int(int.truncate(lhs.wrapping_sub(rhs)))
+        }
+        (Op::Mul(Num::Int(int)), Slot::Int(lhs), Slot::Int(rhs)) => {
+            Slot::Int(int.truncate(lhs.wrapping_mul(rhs)))
+        }
+        (Op::Pow(Num::Int(int)), Slot::Int(lhs), Slot::Int(rhs)) => {
+            Slot::Int(int.truncate(runtime::int_pow(lhs, rhs)))
+        }
+        (Op::Less(Num::Int(int)), Slot::Int(lhs), Slot::Int(rhs)) if int.is_signed() => {
+            Slot::Int((int.sign_extend(lhs) < int.sign_extend(rhs)) as u64)
+        }
+        (Op::Less(Num::Int(_)), Slot::Int(lhs), Slot::Int(rhs)) => Slot::Int((lhs < rhs) as u64),
+        _ => unreachable!("the compiler checked the operand types"),
+    }
+}
+
+fn cast(slot: Slot, from: Num, to: Num) -> Slot {
+    match (slot, from, to) {
+        (Slot::Int(bits), Num::Int(from), Num::Int(to)) if from.is_signed() => {
+            Slot::Int(to.truncate(from.sign_extend(bits) as u64))
+        }
+        (Slot::Int(bits), Num::Int(_), Num::Int(to)) => Slot::Int(to.truncate(bits)),
+        (Slot::Int(bits), Num::Int(from), Num::F64) if from.is_signed() => {
+            Slot::F64(from.sign_extend(bits) as f64)
+        }
+        (Slot::Int(bits), Num::Int(_), Num::F64) => Slot::F64(bits as f64),
+        (Slot::F64(value), Num::F64, Num::Int(to)) if to.is_signed() => {
+            Slot::Int(to.truncate(value as i64 as u64))
+        }
+        (Slot::F64(value), Num::F64, Num::Int(to)) => Slot::Int(to.truncate(value as u64)),
+        (slot, _, _) => slot,
+    }
+}
+
+impl Vm {
+    /// Runs `chunk` until it returns. Arrays are always bounds checked,
+    /// there's no memory to read past.
+    pub fn run(&mut self, chunk: Rc<Chunk>) -> Result<Slot, Diagnostic> {
+        let result = self.execute(chunk);
+        // an error leaves behind frames that will never return
+        self.stack.clear();
+        self.frames.clear();
+        result
+    }
+
+    fn pop(&mut self) -> Slot {
+        self.stack.pop().unwrap()
+    }
+
+    fn execute(&mut self, chunk: Rc<Chunk>) -> Result<Slot, Diagnostic> {
+        let mut frame = Frame {
+            base: self.stack.len(),
+            ip: 0,
+            chunk,
+        };
+        self.stack
+            .resize(frame.base + frame.chunk.locals(), Slot::Int(0));
+        loop {
+            let op = frame.chunk.code[frame.ip];
+            frame.ip += 1;
+            match op {
+                Op::Nop => {}
+                Op::Const(index) => {
+                    let value = frame.chunk.constants[index as usize].clone();
+                    self.stack.push(value);
+                }
+                Op::Load(slot) => {
+                    let value = self.stack[frame.base + slot as usize].clone();
+                    self.stack.push(value);
+                }
+                Op::Store(slot) => {
+                    let value = self.pop();
+                    self.stack[frame.base + slot as usize] = value;
+                }
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Add(_) | Op::Sub(_) | Op::Mul(_) | Op::Pow(_) | Op::Less(_) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    self.stack.push(arithmetic(op, lhs, rhs));
+                }
+                Op::Cast(from, to) => {
+                    let value = self.pop();
+                    self.stack.push(cast(value, from, to));
+                }
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpUnless(target) => {
+                    if !truth(&self.pop()) {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::Call(function, argc) => {
+                    self.call(&mut frame, function as usize, argc as usize, false)?
+                }
+                Op::TailCall(function, argc) => {
+                    self.call(&mut frame, function as usize, argc as usize, true)?
+                }
+                Op::CallClosure(argc) | Op::TailCallClosure(argc) => {
+                    let at = self.stack.len() - argc as usize - 1;
+                    let closure = match self.stack.remove(at) {
+                        Slot::Closure(closure) => closure,
+                        _ => unreachable!("the compiler only calls closures"),
+                    };
+                    self.stack.extend(closure.captures.iter().cloned());
+                    let argc = argc as usize + closure.captures.len();
+                    let tail = matches!(op, Op::TailCallClosure(_));
+                    self.call(&mut frame, closure.function, argc, tail)?;
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    self.stack.truncate(frame.base);
+                    match self.frames.pop() {
+                        Some(caller) => {
+                            frame = caller;
+                            self.stack.push(value);
+                        }
+                        None => return Ok(value),
+                    }
+                }
+                Op::Array(len) => {
+                    let start = self.stack.len() - len as usize;
+                    let words = self.stack.drain(start..).map(word).collect();
+                    let array = self.alloc(words);
+                    self.stack.push(array);
+                }
+                Op::Alloc => {
+                    let len = word(self.pop());
+                    let mut words = Vec::new();
+                    words.try_reserve_exact(len as usize).map_err(|_| {
+                        Diagnostic::error(format!("could not allocate {} words", len))
+                    })?;
+                    words.resize(len as usize, 0);
+                    let array = self.alloc(words);
+                    self.stack.push(array);
+                }
+                Op::Len => {
+                    let array = word(self.pop());
+                    let len = self.array(array)?.len() as u64;
+                    self.stack.push(Slot::Int(len));
+                }
+                Op::Index => {
+                    let index = word(self.pop());
+                    let array = word(self.pop());
+                    let index = self.element_index(array, index)?;
+                    let value = self.arrays[array as usize][index];
+                    self.stack.push(Slot::Int(value));
+                }
+                Op::SetIndex => {
+                    let value = word(self.pop());
+                    let index = word(self.pop());
+                    let array = word(self.pop());
+                    let index = self.element_index(array, index)?;
+                    self.arrays[array as usize][index] = value;
+                    self.stack.push(Slot::Int(value));
+                }
+                Op::Struct(len) => {
+                    let start = self.stack.len() - len as usize;
+                    let fields = self.stack.drain(start..).collect();
+                    self.stack.push(Slot::Struct(Rc::new(fields)));
+                }
+                Op::Field(index) => match self.pop() {
+                    Slot::Struct(fields) => self.stack.push(fields[index as usize].clone()),
+                    _ => unreachable!("the compiler only reads fields of structs"),
+                },
+                Op::Deref => match self.pop() {
+                    Slot::Pointer(target) => {
+                        let value = target.borrow().clone();
+                        self.stack.push(value);
+                    }
+                    _ => unreachable!("the compiler only dereferences pointers"),
+                },
+                Op::SetField(index) => {
+                    let value = self.pop();
+                    if let Slot::Pointer(target) = self.pop() {
+                        if let Slot::Struct(fields) = &mut *target.borrow_mut() {
+                            Rc::make_mut(fields)[index as usize] = value.clone();
+                        }
+                    }
+                    self.stack.push(value);
+                }
+                Op::Ref => {
+                    let value = self.pop();
+                    self.stack.push(Slot::Pointer(Rc::new(RefCell::new(value))));
+                }
+                Op::Closure(function, count) => {
+                    let start = self.stack.len() - count as usize;
+                    let captures = self.stack.drain(start..).collect();
+                    self.stack.push(Slot::Closure(Rc::new(Closure {
+                        function: function as usize,
+                        captures,
+                    })));
+                }
+            }
+        }
+    }
+
+    // Makes `function` the running frame, with its `argc` arguments on top of
+    // the stack. The caller is saved to return to, unless this is a tail call
+    // and the callee takes over its frame.
+    fn call(
+        &mut self,
+        frame: &mut Frame,
+        function: usize,
+        argc: usize,
+        tail: bool,
+    ) -> Result<(), Diagnostic> {
+        let chunk = match self.functions[function].body {
+            Body::Chunk(ref chunk) => chunk.clone(),
+            // the `return` after a tail call hands the result on as usual
+            Body::Foreign(_) => {
+                let value = self.call_foreign(function, argc)?;
+                self.stack.push(value);
+                return Ok(());
+            }
+        };
+        let args = self.stack.len() - argc;
+        let base = if tail {
+            self.stack.drain(frame.base..args);
+            frame.base
+        } else {
+            if self.frames.len() == MAX_FRAMES {
+                return Err(Diagnostic::error(format!(
+                    "stack overflow in `{}`",
+                    self.functions[function].name
+                )));
+            }
+            args
+        };
+        let callee = Frame { chunk, ip: 0, base };
+        self.stack
+            .resize(base + callee.chunk.locals(), Slot::Int(0));
+        let caller = replace(frame, callee);
+        if !tail {
+            self.frames.push(caller);
+        }
+        Ok(())
+    }
+
+    fn call_foreign(&mut self, index: usize, argc: usize) -> Result<Slot, Diagnostic> {
+        let start = self.stack.len() - argc;
+        let args: Vec<Slot> = self.stack.drain(start..).collect();
+        let function = &mut self.functions[index];
+        let address = match function.body {
+            Body::Foreign(Some(address)) => address,
+            _ => {
+                let address = match runtime::native(&function.name) {
+                    Some(address) => address,
+                    None => runtime::find_symbol(&function.name).map_err(|err| {
+                        Diagnostic::error(format!(
+                            "could not find function `{}`: {}",
+                            function.name, err
+                        ))
+                    })?,
+                };
+                function.body = Body::Foreign(Some(address));
+                address
+            }
+        };
+
+        let mut c_args = Vec::with_capacity(argc);
+        for (arg, ty) in args.into_iter().zip(&function.args) {
+            c_args.push(match (arg, ty) {
+                (Slot::Int(bits), Type::Int(int)) => CValue::Int(bits, *int),
+                (Slot::F64(value), _) => CValue::Float(value),
+                (_, ty) => {
+                    return Err(Diagnostic::error(format!(
+                        "`{}` can't be passed to `{}` by the vm",
+                        ty, function.name
+                    )))
+                }
+            });
+        }
+        // `extern` declarations only allow scalars, see `Compiler::declare_extern`
+        let ret = match &function.ret {
+            Type::F64 => CType::Float,
+            Type::Int(int) => CType::Int(*int),
+            ty => {
+                return Err(Diagnostic::error(format!(
+                    "`{}` can't be returned from `{}` to the vm",
+                    ty, function.name
+                )))
+            }
+        };
+        Ok(match unsafe { runtime::call_c(address, &c_args, ret) } {
+            CValue::Int(bits, _) => Slot::Int(bits),
+            CValue::Float(value) => Slot::F64(value),
+        })
+    }
+
+    fn alloc(&mut self, words: Vec<u64>) -> Slot {
+        self.arrays.push(words);
+        Slot::Int(self.arrays.len() as u64 - 1)
+    }
+
+    fn array(&self, array: u64) -> Result<&Vec<u64>, Diagnostic> {
+        self.arrays
+            .get(array as usize)
+            .ok_or_else(|| Diagnostic::error(format!("`{}` is not an array", array)))
+    }
+
+    fn element_index(&self, array: u64, index: u64) -> Result<usize, Diagnostic> {
+        let len = self.array(array)?.len() as u64;
+        if index >= len {
+            return Err(Diagnostic::error(format!(
+                "index out of bounds: the len is {} but the index is {}",
+                len, index
+            )));
+        }
+        Ok(index as usize)
+    }
+
+    /// Shows `slot` the way the JIT shows a value of type `ty`.
+    pub fn format(&self, slot: &Slot, ty: &Type) -> String {
+        match (slot, ty) {
+            (Slot::Int(bits), Type::Int(int)) if int.is_signed() => {
+                int.sign_extend(*bits).to_string()
+            }
+            (Slot::Int(bits), _) => bits.to_string(),
+            (Slot::F64(value), _) => value.to_string(),
+            (Slot::Struct(values), Type::Struct(name)) => {
+                let fields: Vec<String> = self.types.structs[name]
+                    .iter()
+                    .zip(values.iter())
+                    .map(|((field, ty), value)| format!("{}: {}", field, self.format(value, ty)))
+                    .collect();
+                format!("{} {{ {} }}", name, fields.join(", "))
+            }
+            (Slot::Struct(values), _) => format!("{:#x}", Rc::as_ptr(values) as usize),
+            (Slot::Pointer(target), _) => format!("{:#x}", Rc::as_ptr(target) as usize),
+            (Slot::Closure(closure), _) => format!("{:#x}", Rc::as_ptr(closure) as usize),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Helpers shared by the integration tests, each of which only uses some.
+#![allow(dead_code)]
+
+use std::env;
+
+/// The lines where `actual` differs from `expected`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let (want, got) = (expected.get(i), actual.get(i));
+        if want != got {
+            out.push_str(&format!("  - {}\n", want.unwrap_or(&"")));
+            out.push_str(&format!("  + {}\n", got.unwrap_or(&"")));
+        }
+    }
+    out
+}
+
+pub fn env_number(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} should be a number, not `{}`", name, value)),
+        Err(_) => default,
+    }
+}
+
+// xorshift64*, good enough to pick productions without a dependency
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // never zero, which xorshift would stay at
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+}
@@ -0,0 +1,369 @@
+//! Runs the same scripts on every backend and reports wherever they print
+//! different results: first the example scripts, then random programs over
+//! the expression grammar.
+//!
+//! `KS_SEED=n` replays a run of random programs, `KS_CASES=n` changes how
+//! many are generated.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+mod common;
+
+use common::{diff, env_number, Rng};
+
+// the first one is what the others are compared against
+const BACKENDS: &[&str] = if cfg!(feature = "jit") {
+    &["jit", "interp", "vm"]
+} else {
+    &["interp", "vm"]
+};
+
+// each entry is one session, scripts that need another one preloaded list
+// it first
+const CORPUS: &[&[&str]] = &[
+    &["Fib"],
+    &["Fib", "Input"],
+    &["Functional"],
+    &["tests/golden/externs.ks"],
+];
+
+struct Output {
+    stdout: String,
+    stderr: String,
+    success: bool,
+}
+
+fn run(backend: &str, paths: &[PathBuf]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_llvm-tutorial"))
+        .arg(format!("--backend={}", backend))
+        .args(paths)
+        .stdin(Stdio::null())
+        .output()
+        .expect("could not run llvm-tutorial");
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        success: output.status.success(),
+    }
+}
+
+// Runs `paths` on every backend and describes each one that disagrees with
+// the first, or returns `None` if they all agree.
+fn compare(paths: &[PathBuf]) -> Option<String> {
+    let outputs: Vec<(&str, Output)> = BACKENDS
+        .iter()
+        .map(|backend| (*backend, run(backend, paths)))
+        .collect();
+    let (reference, expected) = &outputs[0];
+    let mut report = String::new();
+    for (backend, actual) in &outputs[1..] {
+        if actual.stdout == expected.stdout && actual.success == expected.success {
+            continue;
+        }
+        report.push_str(&format!(
+            "{} (-) and {} (+) differ:\n{}",
+            reference,
+            backend,
+            diff(&expected.stdout, &actual.stdout)
+        ));
+        for (backend, output) in [(reference, expected), (backend, actual)] {
+            if !output.success || !output.stderr.is_empty() {
+                report.push_str(&format!(
+                    "{} stderr ({}):\n{}\n",
+                    backend,
+                    if output.success { "ok" } else { "failed" },
+                    output.stderr.trim_end()
+                ));
+            }
+        }
+    }
+    if report.is_empty() {
+        None
+    } else {
+        Some(report)
+    }
+}
+
+// Backends agreeing says nothing if one of them doesn't run at all, the JIT
+// when LLVM can't be loaded say, and the others fail the same way.
+#[test]
+fn every_backend_runs() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("runs.ks");
+    fs::write(&path, "1 + 2\n").unwrap();
+    for backend in BACKENDS {
+        let output = run(backend, std::slice::from_ref(&path));
+        assert!(
+            output.success && output.stdout.contains("1+2 => 3"),
+            "{} didn't run `1 + 2`:\n{}{}",
+            backend,
+            output.stdout,
+            output.stderr
+        );
+    }
+}
+
+#[test]
+fn corpus_agrees_across_backends() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut failures = Vec::new();
+    for session in CORPUS {
+        let paths: Vec<PathBuf> = session.iter().map(|path| root.join(path)).collect();
+        if let Some(report) = compare(&paths) {
+            failures.push(format!("{}:\n{}", session.join(" "), report));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn random_programs_agree_across_backends() {
+    let seed = env_number("KS_SEED", 1);
+    let cases = env_number("KS_CASES", 32);
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let mut failures = Vec::new();
+    for case in 0..cases {
+        // every case has its own seed, so a failing one can be replayed alone
+        let case_seed = seed.wrapping_add(case);
+        let program = Generator::new(case_seed).program();
+        let path = dir.join(format!("random-{}.ks", case_seed));
+        fs::write(&path, &program).unwrap();
+        if let Some(report) = compare(std::slice::from_ref(&path)) {
+            failures.push(format!(
+                "{} (replay with KS_SEED={} KS_CASES=1):\n{}\n{}",
+                path.display(),
+                case_seed,
+                program,
+                report
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// What an expression is generated as. Words may really be narrower integers
+// and turn into `f64`s where one is expected, but nothing generated ever
+// converts an `f64` to an integer, where the backends are allowed to differ
+// on values out of range.
+#[derive(Clone, Copy, PartialEq)]
+enum Ty {
+    Word,
+    F64,
+}
+
+impl Ty {
+    fn name(self) -> &'static str {
+        match self {
+            Ty::Word => "u64",
+            Ty::F64 => "f64",
+        }
+    }
+}
+
+/// Random well-typed programs: a few functions, each only calling the ones
+/// before it so everything terminates, then top-level expressions using them.
+/// Array indices are always in bounds, the JIT doesn't check them by default.
+///
+/// Bodies and top-level expressions are parenthesized, newlines don't end an
+/// expression and the next line could otherwise continue it as a call or an
+/// index.
+struct Generator {
+    rng: Rng,
+    // name, argument types and result of every function defined so far
+    functions: Vec<(String, Vec<Ty>, Ty)>,
+    // variables in scope, innermost last
+    scope: Vec<(String, Ty)>,
+    fresh: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        Generator {
+            rng: Rng::new(seed),
+            functions: Vec::new(),
+            scope: Vec::new(),
+            fresh: 0,
+        }
+    }
+
+    fn program(&mut self) -> String {
+        let mut out = String::from("struct Pair { a: u64, b: f64 }\n");
+        for i in 0..self.rng.below(5) {
+            let name = format!("f{}", i);
+            // `f()` doesn't parse, every function takes something
+            let args: Vec<Ty> = (0..1 + self.rng.below(2)).map(|_| self.ty()).collect();
+            let ret = self.ty();
+            self.scope = args
+                .iter()
+                .enumerate()
+                .map(|(i, ty)| (format!("a{}", i), *ty))
+                .collect();
+            let params: Vec<String> = self
+                .scope
+                .iter()
+                .map(|(name, ty)| format!("{}: {}", name, ty.name()))
+                .collect();
+            let body = self.expr(ret, 4);
+            out.push_str(&format!(
+                "def {}({}) -> {}\n  ({})\n",
+                name,
+                params.join(", "),
+                ret.name(),
+                body
+            ));
+            self.functions.push((name, args, ret));
+        }
+        self.scope.clear();
+        for _ in 0..1 + self.rng.below(8) {
+            let ty = self.ty();
+            let expr = self.expr(ty, 4);
+            out.push_str(&format!("({})\n", expr));
+        }
+        out
+    }
+
+    fn ty(&mut self) -> Ty {
+        if self.rng.chance(70) {
+            Ty::Word
+        } else {
+            Ty::F64
+        }
+    }
+
+    fn fresh(&mut self) -> String {
+        self.fresh += 1;
+        format!("v{}", self.fresh)
+    }
+
+    fn literal(&mut self, ty: Ty) -> String {
+        match ty {
+            Ty::F64 => format!("{:?}", self.rng.below(100) as f64 / 4.0),
+            Ty::Word => match self.rng.below(10) {
+                0 => u64::MAX.to_string(),
+                1 => self.rng.next().to_string(),
+                2 => {
+                    let suffix = ["u8", "i8", "u16", "i32", "i64"][self.rng.below(5) as usize];
+                    format!("{}{}", self.rng.below(100), suffix)
+                }
+                _ => self.rng.below(10).to_string(),
+            },
+        }
+    }
+
+    fn leaf(&mut self, ty: Ty) -> String {
+        // words widen to floats, never the other way around
+        let vars: Vec<&String> = self
+            .scope
+            .iter()
+            .filter(|(_, var)| *var == ty || *var == Ty::Word)
+            .map(|(name, _)| name)
+            .collect();
+        if !vars.is_empty() && self.rng.chance(60) {
+            return vars[self.rng.below(vars.len() as u64) as usize].clone();
+        }
+        self.literal(ty)
+    }
+
+    fn args(&mut self, args: &[Ty], depth: u32) -> String {
+        let args: Vec<String> = args.iter().map(|ty| self.expr(*ty, depth)).collect();
+        args.join(", ")
+    }
+
+    fn expr(&mut self, ty: Ty, depth: u32) -> String {
+        if depth == 0 || self.rng.chance(15) {
+            return self.leaf(ty);
+        }
+        let d = depth - 1;
+        match (ty, self.rng.below(13)) {
+            (_, 0) => format!("({} + {})", self.expr(ty, d), self.expr(ty, d)),
+            (_, 1) => format!("({} - {})", self.expr(ty, d), self.expr(ty, d)),
+            (_, 2) => format!("({} * {})", self.expr(ty, d), self.expr(ty, d)),
+            (Ty::Word, 3) => {
+                let operands = self.ty();
+                format!("({} < {})", self.expr(operands, d), self.expr(operands, d))
+            }
+            (Ty::F64, 3) => format!("({} * {})", self.expr(ty, d), self.expr(Ty::Word, d)),
+            (_, 4) => {
+                let predicate = self.ty();
+                format!(
+                    "(if {} then {} else {})",
+                    self.expr(predicate, d),
+                    self.expr(ty, d),
+                    self.expr(ty, d)
+                )
+            }
+            (Ty::Word, 5) => {
+                let (predicate, then) = (self.ty(), self.ty());
+                format!(
+                    "(if {} then {})",
+                    self.expr(predicate, d),
+                    self.expr(then, d)
+                )
+            }
+            (Ty::F64, 5) => match self.rng.below(3) {
+                0 => format!("sqrt({})", self.expr(ty, d)),
+                _ => format!("fmax({}, {})", self.expr(ty, d), self.expr(ty, d)),
+            },
+            (_, 6) => {
+                let name = self.fresh();
+                let value_ty = self.ty();
+                let value = self.expr(value_ty, d);
+                self.scope.push((name.clone(), value_ty));
+                let body = self.expr(ty, d);
+                self.scope.pop();
+                format!("(let {} = {} in {})", name, value, body)
+            }
+            (_, 7) => {
+                let first = self.ty();
+                format!("{{ {}; {} }}", self.expr(first, d), self.expr(ty, d))
+            }
+            (_, 8) => {
+                let callable: Vec<usize> = (0..self.functions.len())
+                    .filter(|i| self.functions[*i].2 == ty || self.functions[*i].2 == Ty::Word)
+                    .collect();
+                if callable.is_empty() {
+                    return match ty {
+                        Ty::Word => format!("max({}, {})", self.expr(ty, d), self.expr(ty, d)),
+                        Ty::F64 => format!("fmin({}, {})", self.expr(ty, d), self.expr(ty, d)),
+                    };
+                }
+                let (name, args, _) = self.functions
+                    [callable[self.rng.below(callable.len() as u64) as usize]]
+                    .clone();
+                format!("{}({})", name, self.args(&args, d))
+            }
+            (Ty::Word, 9) => {
+                let len = 1 + self.rng.below(3);
+                let elements: Vec<String> = (0..len).map(|_| self.expr(Ty::Word, d)).collect();
+                match self.rng.below(3) {
+                    0 => format!("len([{}])", elements.join(", ")),
+                    _ => format!("[{}][{}]", elements.join(", "), self.rng.below(len)),
+                }
+            }
+            (Ty::F64, 9) => format!(
+                "Pair {{ b: {}, a: {} }}.b",
+                self.expr(Ty::F64, d),
+                self.expr(Ty::Word, d)
+            ),
+            (_, 10) => {
+                let (name, arg) = (self.fresh(), self.fresh());
+                self.scope.push((arg.clone(), Ty::Word));
+                let body = self.expr(ty, d);
+                self.scope.pop();
+                format!(
+                    "(let {} = |{}| -> {} {} in {}({}))",
+                    name,
+                    arg,
+                    ty.name(),
+                    body,
+                    name,
+                    self.expr(Ty::Word, d)
+                )
+            }
+            (_, 11) => format!("({} ** {})", self.expr(ty, d), self.expr(ty, d)),
+            (Ty::Word, _) => format!("abs({})", self.expr(ty, d)),
+            (Ty::F64, _) => self.leaf(ty),
+        }
+    }
+}
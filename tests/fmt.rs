@@ -0,0 +1,159 @@
+//! The formatter over every script in the repository: the result has to
+//! parse to the same items, keep every comment and come out of a second run
+//! unchanged.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::parser::Parser;
+use llvm_tutorial::pretty::format_source;
+use llvm_tutorial::token::Token;
+
+mod common;
+
+use common::diff;
+
+fn scripts() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths: Vec<PathBuf> = ["Fib", "Input", "Functional", "src/prelude.ks"]
+        .iter()
+        .map(|path| root.join(path))
+        .collect();
+    let mut golden: Vec<PathBuf> = fs::read_dir(root.join("tests/golden"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        // the one that doesn't parse is checked on its own
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("ks"))
+        .filter(|path| !path.ends_with("errors.ks"))
+        .collect();
+    golden.sort();
+    paths.extend(golden);
+    paths
+}
+
+fn items(source: &str) -> Vec<String> {
+    let mut lex = Lexer::new(source);
+    Parser::new(&mut lex)
+        .map(|result| format!("{:?}", result.unwrap().0))
+        .collect()
+}
+
+fn comments(source: &str) -> Vec<String> {
+    let mut lex = Lexer::with_trivia(source);
+    let mut comments = Vec::new();
+    loop {
+        match lex.emit_token().unwrap() {
+            (Token::Eof, _) => return comments,
+            (Token::Comment(text), _) => comments.push(text),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn scripts_format_to_the_same_program() {
+    for path in scripts() {
+        let source = fs::read_to_string(&path).unwrap();
+        let formatted = match format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => panic!("{}", err.render(&path.display().to_string(), &source)),
+        };
+        assert_eq!(items(&formatted), items(&source), "{}", path.display());
+        assert_eq!(
+            comments(&formatted),
+            comments(&source),
+            "{} lost comments",
+            path.display()
+        );
+        let again = format_source(&formatted).unwrap();
+        assert!(
+            again == formatted,
+            "formatting {} twice changes it:\n{}",
+            path.display(),
+            diff(&formatted, &again)
+        );
+    }
+}
+
+#[test]
+fn fib_is_formatted_already() {
+    let source = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("Fib")).unwrap();
+    let formatted = format_source(&source).unwrap();
+    assert!(formatted == source, "\n{}", diff(&source, &formatted));
+}
+
+#[test]
+fn layout() {
+    for (source, expected) in [
+        (
+            "def f(x) if x<3 then 1 else { g(x); x*2 }",
+            "def f(x)\n  if x < 3 then\n    1\n  else {\n    g(x);\n    x * 2\n  }\n",
+        ),
+        (
+            "def sign(x) if x < 0 then 0 - 1 else if x < 1 then 0 else 1",
+            "def sign(x)\n  if x < 0 then\n    0 - 1\n  else if x < 1 then\n    0\n  else\n    1\n",
+        ),
+        (
+            "# first\n\n\n\ndef f(x) x # after\n/* before */ f(1)",
+            "# first\n\ndef f(x)\n  x # after\n/* before */\nf(1)\n",
+        ),
+        // comments before a body or a statement, or after a statement
+        (
+            "def f(x)\n  # why\n  x+1\nf(2)",
+            "def f(x)\n  # why\n  x + 1\nf(2)\n",
+        ),
+        (
+            "def f(x) {\n# first\ng(x); # call\n  /* last */ x*2 # done\n}",
+            "def f(x) {\n  # first\n  g(x); # call\n  /* last */\n  x * 2 # done\n}\n",
+        ),
+        (
+            "def f(x) let y = x+1 in y; # keep\n x",
+            "def f(x)\n  let y = x + 1 in {\n    y; # keep\n    x\n  }\n",
+        ),
+        // anywhere else the tree has nowhere to keep it
+        ("f(1, # one\n  2+3)", "f(1, # one\n  2+3)\n"),
+        // literals are printed as they were written
+        ("0xff+1_000u32", "0xff + 1_000u32\n"),
+        ("(if a then b) + c", "(if a then b) + c\n"),
+        (
+            "(a - b) - (c - d) * e ** (f ** g)",
+            "a - b - (c - d) * e ** f ** g\n",
+        ),
+    ] {
+        assert_eq!(format_source(source).unwrap(), expected, "`{}`", source);
+    }
+}
+
+#[test]
+fn files_that_dont_parse_are_left_alone() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/errors.ks");
+    assert!(format_source(&fs::read_to_string(path).unwrap()).is_err());
+}
+
+#[test]
+fn fmt_rewrites_files_and_check_lists_them() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("fmt.ks");
+    fs::write(&path, "def f(x) x+1\n").unwrap();
+    let fmt = |check: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_llvm-tutorial"));
+        command.arg("fmt");
+        if check {
+            command.arg("--check");
+        }
+        command
+            .arg(&path)
+            .output()
+            .expect("could not run llvm-tutorial")
+    };
+
+    let output = fmt(true);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("fmt.ks"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "def f(x) x+1\n");
+
+    assert!(fmt(false).status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "def f(x)\n  x + 1\n");
+    assert!(fmt(true).status.success());
+}
@@ -0,0 +1,112 @@
+//! Golden tests: every `tests/golden/*.ks` goes through the lexer, the
+//! parser, code generation and a run of the binary, and what each stage
+//! prints is compared with the file next to the program named after the
+//! stage, e.g. `precedence.ast`.
+//!
+//! `BLESS=1 cargo test --test golden` writes what the binary prints now as
+//! the new expectations instead; review the diff before committing it. A
+//! stage without an expectation file fails until it's blessed.
+//!
+//! A run has to print the same on every backend, so they all share the one
+//! `.out` file, which the first blesses and the others are checked against;
+//! the JIT's run and the IR are only checked when it's built.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+mod common;
+
+use common::diff;
+
+// the extension of each stage's expectation, the flags that print it and
+// whether it needs the JIT
+const STAGES: &[(&str, &[&str], bool)] = &[
+    ("tokens", &["--dump=tokens"], false),
+    ("ast", &["--dump=ast"], false),
+    ("ir", &["--dump=ir"], true),
+    ("out", &["--backend=interp"], false),
+    ("out", &["--backend=vm"], false),
+    ("out", &["--backend=jit"], true),
+];
+
+// What `args` print for `file`, diagnostics last after a marker line.
+fn run(dir: &Path, args: &[&str], file: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_llvm-tutorial"))
+        .args(args)
+        .arg(file)
+        // so diagnostics name the file the same way wherever the tests run
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .expect("could not run llvm-tutorial");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // the REPL prompt that finds stdin closed after the file ran
+    let mut text = stdout.strip_suffix("> \n").unwrap_or(&stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.is_empty() {
+        text.push_str("--- stderr\n");
+        text.push_str(&stderr);
+    }
+    if !output.status.success() {
+        text.push_str(&format!("--- {}\n", output.status));
+    }
+    text
+}
+
+#[test]
+fn golden_files_match() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let bless = env::var_os("BLESS").is_some();
+    let mut programs: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".ks"))
+        .collect();
+    programs.sort();
+
+    let mut failures = Vec::new();
+    let mut blessed = HashSet::new();
+    for program in &programs {
+        for (stage, args, jit) in STAGES {
+            if *jit && !cfg!(feature = "jit") {
+                continue;
+            }
+            let actual = run(&dir, args, program);
+            let path = dir.join(program).with_extension(stage);
+            if bless && blessed.insert(path.clone()) {
+                fs::write(&path, &actual).unwrap();
+                continue;
+            }
+            match fs::read_to_string(&path) {
+                Ok(expected) if expected == actual => {}
+                Ok(expected) => failures.push(format!(
+                    "{} differs for `{}` (-expected +actual):\n{}",
+                    path.display(),
+                    args.join(" "),
+                    diff(&expected, &actual)
+                )),
+                Err(_) => failures.push(format!("{} is missing", path.display())),
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "\n{}\nrun with BLESS=1 if the changes are intended",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn missing_files_are_reported() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let output = run(&dir, &[], "missing.ks");
+    assert!(
+        output.contains("--- stderr\nerror: could not read `missing.ks`"),
+        "{}",
+        output
+    );
+    assert!(!output.contains("panicked"), "{}", output);
+}
@@ -0,0 +1,150 @@
+//! Lints: what each one warns about and what it leaves alone, and that `-W`
+//! and `-A` turn them on and off by name.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use llvm_tutorial::lint::{Lint, Linter, Lints};
+
+const PRELUDE: &str = include_str!("../src/prelude.ks");
+
+// The lint that found each warning for `source` and the text it points at,
+// with the prelude's externs declared like in a session.
+fn warnings(source: &str, lints: Lints) -> Vec<(String, String)> {
+    let mut linter = Linter::new(lints);
+    linter.lint_file("<prelude>", PRELUDE);
+    linter
+        .lint_file("test.ks", source)
+        .into_iter()
+        .map(|warning| {
+            let span = warning.span.expect("a warning without a span");
+            (
+                warning.code.unwrap().to_string(),
+                source[span.start..span.end].to_string(),
+            )
+        })
+        .collect()
+}
+
+fn expect(source: &str, expected: &[(&str, &str)]) {
+    let expected: Vec<(String, String)> = expected
+        .iter()
+        .map(|(lint, text)| (lint.to_string(), text.to_string()))
+        .collect();
+    assert_eq!(warnings(source, Lints::default()), expected, "`{}`", source);
+}
+
+#[test]
+fn suspicious_code_is_warned_about() {
+    expect(
+        "def f(a, b) a\n|x, y| y",
+        &[("unused_parameters", "b"), ("unused_parameters", "x")],
+    );
+    expect(
+        "def f(x) let y = x in let z = (let w = 1 in 2) in z",
+        &[("unused_bindings", "y"), ("unused_bindings", "w")],
+    );
+    expect(
+        "def f(x) if 2 * 3 then x else 0",
+        &[("unreachable_else", "0")],
+    );
+    expect("def cos(x: f64) -> f64 x", &[("shadowed_extern", "cos")]);
+    expect(
+        "extern \"libm.so.6\" cbrt(x: f64) -> f64\ndef cbrt(x: f64) -> f64 x",
+        &[("shadowed_extern", "cbrt")],
+    );
+    expect(
+        "extern step(x)\ndef step(x: f64) -> f64 x",
+        &[("shadowed_extern", "step")],
+    );
+    expect("extern nothing(x)", &[("unused_extern", "nothing")]);
+    expect(
+        "def f(a, b) (a < b) + 1 - 2 ** (b < a)",
+        &[
+            ("comparison_arithmetic", "a < b"),
+            ("comparison_arithmetic", "b < a"),
+        ],
+    );
+}
+
+#[test]
+fn intended_code_is_left_alone() {
+    for source in [
+        // `_` says the parameter is unused on purpose
+        "def f(_unused, x) x",
+        // read by a lambda, shadowed by a `let` after it's read
+        "def f(a, b) |x| a + x + (let b = b in b)",
+        "let _x = 1 in 2",
+        // captured by a lambda, shadowed and read again inside its body
+        "let a = 1 in |x| a + (let a = x in a)",
+        // a parameter holding a function, called
+        "def apply(f: fn(u64) -> u64, x) f(x)",
+        "def f(x) if x < 2 then 1 else 2",
+        "def f(x) if 0 then 1 else x",
+        "def f(x) if 1 then x",
+        // declared ahead of its definition, for mutual recursion
+        "extern odd(n)\ndef even(n) if n < 1 then 1 else odd(n - 1)\ndef odd(n) if n < 1 then 0 else even(n - 1)",
+        "extern fib(x)\nfib(3)",
+        "extern fib(x)\n&fib",
+        "def f(a, b) if a < b then a else b",
+    ] {
+        expect(source, &[]);
+    }
+}
+
+#[test]
+fn lints_are_turned_off_and_on_by_name() {
+    let source = "extern nothing(x)\ndef f(a) (1 < 2) * 3";
+    let mut lints = Lints::default();
+    lints.set("unused_extern", false).unwrap();
+    assert_eq!(warnings(source, lints.clone()).len(), 2);
+    lints.set("all", false).unwrap();
+    assert!(warnings(source, lints.clone()).is_empty());
+    lints.set("unused_parameters", true).unwrap();
+    assert_eq!(
+        warnings(source, lints.clone()),
+        vec![("unused_parameters".to_string(), "a".to_string())]
+    );
+    assert!(lints.set("unused_params", false).is_err());
+
+    for lint in Lint::ALL {
+        let mut lints = Lints::default();
+        lints.set(lint.name(), false).unwrap();
+        assert!(!lints.is_enabled(*lint));
+    }
+}
+
+// A REPL line may declare an `extern` for a later line to use.
+#[test]
+fn lines_keep_externs_for_the_next() {
+    let mut linter = Linter::default();
+    assert!(linter.lint_line("<stdin>", "extern later(x)").is_empty());
+    assert!(linter.lint_line("<stdin>", "later(1)").is_empty());
+}
+
+#[test]
+fn flags_control_the_warnings_printed() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("lints.ks");
+    fs::write(&path, "def f(a, b) a\nlet unused = 1 in 2\n").unwrap();
+    let run = |flags: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_llvm-tutorial"))
+            .arg("--backend=interp")
+            .args(flags)
+            .arg(&path)
+            .output()
+            .expect("could not run llvm-tutorial")
+    };
+
+    let stderr = |flags: &[&str]| String::from_utf8_lossy(&run(flags).stderr).into_owned();
+    let all = stderr(&[]);
+    assert!(all.contains("warning[unused_parameters]: unused parameter `b`"));
+    assert!(all.contains("warning[unused_bindings]: unused binding `unused`"));
+    assert!(!stderr(&["-A", "unused_parameters"]).contains("`b`"));
+    assert_eq!(stderr(&["-Aall"]), "");
+    assert!(stderr(&["-A", "all", "-W", "unused_parameters"]).contains("`b`"));
+
+    let output = run(&["-A", "unused_params"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown lint `unused_params`"));
+}
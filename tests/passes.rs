@@ -0,0 +1,87 @@
+//! The default walks of `Visitor` and `Folder` have to reach every child of
+//! every variant, or passes built on them silently skip code.
+
+use llvm_tutorial::ast::*;
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::parser::Parser;
+
+// every kind of expression, with a differently named variable in each child
+const SOURCE: &str = "\
+struct P { x: u64 }
+def every(a0)
+  a1 + a2;
+  f(a3, a4);
+  if a5 then a6 else a7;
+  [a8, a9][a10];
+  P { x: a11 }.x;
+  &a12;
+  { a13; a14 };
+  let a15 = a16 in a17;
+  |a18| a19;
+  1;
+  2.5";
+
+fn items() -> Vec<Item> {
+    let mut lex = Lexer::new(SOURCE);
+    Parser::new(&mut lex)
+        .map(|result| result.unwrap().0)
+        .collect()
+}
+
+#[derive(Default)]
+struct Variables(Vec<String>);
+
+impl Visitor for Variables {
+    fn visit_variable(&mut self, name: &str) {
+        self.0.push(name.to_string());
+    }
+}
+
+fn variables(items: &[Item]) -> Vec<String> {
+    let mut pass = Variables::default();
+    for item in items {
+        pass.visit_item(item);
+    }
+    pass.0
+}
+
+#[test]
+fn visitor_reaches_every_variable() {
+    let expected: Vec<String> = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19]
+        .iter()
+        .map(|i| format!("a{}", i))
+        .collect();
+    assert_eq!(variables(&items()), expected);
+}
+
+// changes nothing, everything is left to the defaults
+struct Identity;
+
+impl Folder for Identity {}
+
+struct Rename;
+
+impl Folder for Rename {
+    fn fold_variable(&mut self, name: String) -> Expr {
+        Expr::Variable(name.replace('a', "b"))
+    }
+}
+
+#[test]
+fn folder_rebuilds_every_variable() {
+    let items: Vec<Item> = items()
+        .into_iter()
+        .map(|item| Identity.fold_item(item))
+        .collect();
+    assert_eq!(format!("{:?}", items), format!("{:?}", self::items()));
+
+    let renamed: Vec<Item> = items
+        .into_iter()
+        .map(|item| Rename.fold_item(item))
+        .collect();
+    let expected: Vec<String> = variables(&self::items())
+        .iter()
+        .map(|name| name.replace('a', "b"))
+        .collect();
+    assert_eq!(variables(&renamed), expected);
+}
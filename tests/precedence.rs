@@ -0,0 +1,173 @@
+//! The parser's precedence and associativity against a reference: random
+//! operator chains have to parse to the same tree as the reference's fully
+//! parenthesized version of them, which leaves the parser nothing to decide.
+//! Printing what they parse to has to give back the same tree as well.
+//!
+//! `KS_SEED=n` and `KS_CASES=n` work like in the differential test.
+
+use llvm_tutorial::ast::{Expr, Function, Item};
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::operator::{Assoc, Operator};
+use llvm_tutorial::parser::Parser;
+
+mod common;
+
+use common::{env_number, Rng};
+
+const OPERATORS: &[Operator] = &[
+    Operator::Assign,
+    Operator::Les,
+    Operator::Add,
+    Operator::Sub,
+    Operator::Mul,
+    Operator::Pow,
+];
+
+// `a op b op c ...`, where an operand may be a parenthesized chain itself
+enum Chain {
+    Operand(String),
+    Group(Vec<Chain>, Vec<Operator>),
+}
+
+impl Chain {
+    // as written in a program, leaving precedence to the parser
+    fn source(&self) -> String {
+        match self {
+            Chain::Operand(operand) => operand.clone(),
+            Chain::Group(operands, ops) => {
+                let mut out = operands[0].source();
+                for (op, operand) in ops.iter().zip(&operands[1..]) {
+                    out.push_str(&format!(" {} {}", op, operand.source()));
+                }
+                format!("({})", out)
+            }
+        }
+    }
+
+    // every operation in parentheses, grouped by the reference
+    fn parenthesized(&self) -> String {
+        match self {
+            Chain::Operand(operand) => operand.clone(),
+            Chain::Group(operands, ops) => reference(operands, ops),
+        }
+    }
+}
+
+// Splits the chain at the operator binding loosest, which is applied last:
+// the rightmost of them if they group to the left, else the leftmost.
+fn reference(operands: &[Chain], ops: &[Operator]) -> String {
+    if ops.is_empty() {
+        return operands[0].parenthesized();
+    }
+    let loosest = ops.iter().map(|op| op.precedence()).min().unwrap();
+    let is_loosest = |op: &&Operator| op.precedence() == loosest;
+    let assoc = ops.iter().find(is_loosest).unwrap().binding().1;
+    // the table has to agree with itself for the chain to mean anything
+    assert!(
+        ops.iter()
+            .filter(is_loosest)
+            .all(|op| op.binding().1 == assoc),
+        "operators of precedence {} differ in associativity",
+        loosest
+    );
+    let split = match assoc {
+        Assoc::Left => ops.iter().rposition(|op| is_loosest(&op)),
+        Assoc::Right => ops.iter().position(|op| is_loosest(&op)),
+    }
+    .unwrap();
+    format!(
+        "({} {} {})",
+        reference(&operands[..=split], &ops[..split]),
+        ops[split],
+        reference(&operands[split + 1..], &ops[split + 1..])
+    )
+}
+
+fn chain(rng: &mut Rng, depth: u32) -> Chain {
+    let len = 1 + rng.below(6) as usize;
+    let operands = (0..len)
+        .map(|i| {
+            if depth > 0 && rng.chance(20) {
+                chain(rng, depth - 1)
+            } else if rng.chance(50) {
+                Chain::Operand(rng.below(10).to_string())
+            } else {
+                Chain::Operand(format!("{}", (b'a' + i as u8) as char))
+            }
+        })
+        .collect();
+    let ops = (1..len)
+        .map(|_| OPERATORS[rng.below(OPERATORS.len() as u64) as usize])
+        .collect();
+    Chain::Group(operands, ops)
+}
+
+// The tree `source` parses to as a top-level expression.
+fn parse(source: &str) -> String {
+    format!("{:?}", expr(source))
+}
+
+fn expr(source: &str) -> Expr {
+    let mut lex = Lexer::new(source);
+    let mut par = Parser::new(&mut lex);
+    match par.next() {
+        Some(Ok((
+            Item::Function(Function {
+                prototype: None,
+                body: Some(body),
+            }),
+            _,
+            _,
+        ))) => body,
+        Some(Ok((item, _, _))) => panic!("`{}` is not an expression: {:?}", source, item),
+        Some(Err(err)) => panic!(
+            "`{}` doesn't parse:\n{}",
+            source,
+            err.render("<test>", source)
+        ),
+        None => panic!("`{}` is empty", source),
+    }
+}
+
+#[test]
+fn operators_group_like_the_table_says() {
+    for (source, parenthesized) in [
+        ("a - b - c", "((a - b) - c)"),
+        ("a * b + c * d", "((a * b) + (c * d))"),
+        ("a ** b ** c", "(a ** (b ** c))"),
+        ("a = b = c + d", "(a = (b = (c + d)))"),
+        ("a < b + c * d ** e", "(a < (b + (c * (d ** e))))"),
+        ("a ** b * c - d < e", "((((a ** b) * c) - d) < e)"),
+        ("a - b + c - d", "(((a - b) + c) - d)"),
+    ] {
+        assert_eq!(parse(source), parse(parenthesized), "`{}`", source);
+    }
+}
+
+#[test]
+fn random_chains_parse_like_the_reference() {
+    let seed = env_number("KS_SEED", 1);
+    let cases = env_number("KS_CASES", 512);
+    for case in 0..cases {
+        let case_seed = seed.wrapping_add(case);
+        let chain = chain(&mut Rng::new(case_seed), 3);
+        let (source, parenthesized) = (chain.source(), chain.parenthesized());
+        assert_eq!(
+            parse(&source),
+            parse(&parenthesized),
+            "`{}` should parse as `{}` (replay with KS_SEED={} KS_CASES=1)",
+            source,
+            parenthesized,
+            case_seed
+        );
+        let printed = expr(&source).to_string();
+        assert_eq!(
+            parse(&printed),
+            parse(&source),
+            "`{}` printed as `{}` (replay with KS_SEED={} KS_CASES=1)",
+            source,
+            printed,
+            case_seed
+        );
+    }
+}
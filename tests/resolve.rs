@@ -0,0 +1,143 @@
+//! Name resolution and the spans it reports problems at: every expression
+//! the parser notes a span for has to be the one written there, and the
+//! resolver has to find every problem in an item, not just the first.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use llvm_tutorial::ast::*;
+use llvm_tutorial::diagnostic::Span;
+use llvm_tutorial::lexer::Lexer;
+use llvm_tutorial::parser::Parser;
+use llvm_tutorial::resolve::resolve_item;
+
+fn parse(source: &str) -> Vec<(Item, Spans)> {
+    let mut lex = Lexer::new(source);
+    Parser::new(&mut lex)
+        .map(|result| {
+            let (item, _, spans) = result.unwrap();
+            (item, spans)
+        })
+        .collect()
+}
+
+// Every expression in the order a `Visitor` meets them.
+#[derive(Default)]
+struct Exprs(Vec<String>);
+
+impl Visitor for Exprs {
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.0.push(format!("{:?}", expr));
+        walk_expr(self, expr);
+    }
+}
+
+#[test]
+fn every_span_parses_back_to_its_expression() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let paths: Vec<PathBuf> = ["Fib", "Input", "Functional", "src/prelude.ks"]
+        .iter()
+        .map(|path| root.join(path))
+        .chain(
+            ["data", "functions", "names", "precedence", "types"]
+                .iter()
+                .map(|name| root.join("tests/golden").join(name).with_extension("ks")),
+        )
+        .collect();
+    for path in paths {
+        let source = fs::read_to_string(&path).unwrap();
+        for (item, spans) in parse(&source) {
+            let mut exprs = Exprs::default();
+            exprs.visit_item(&item);
+            assert_eq!(exprs.0.len(), spans.exprs.len(), "{}", path.display());
+            for (expr, span) in exprs.0.iter().zip(&spans.exprs) {
+                let text = &source[span.start..span.end];
+                let reparsed = match &parse(text)[..] {
+                    [(
+                        Item::Function(Function {
+                            body: Some(body), ..
+                        }),
+                        _,
+                    )] => format!("{:?}", body),
+                    _ => panic!("`{}` in {} isn't an expression", text, path.display()),
+                };
+                assert_eq!(&reparsed, expr, "`{}` in {}", text, path.display());
+            }
+        }
+    }
+}
+
+// What `resolve_item` reports for each item in `source`, as the text each
+// problem points at and its message.
+fn problems(source: &str, functions: &[(&str, usize)]) -> Vec<Vec<(String, String)>> {
+    let functions: HashMap<&str, usize> = functions.iter().copied().collect();
+    let arity = |name: &str| functions.get(name).copied();
+    parse(source)
+        .iter()
+        .map(|(item, spans)| match resolve_item(item, spans, &arity) {
+            Ok(()) => Vec::new(),
+            Err(err) => {
+                let mut problems = vec![(err.span, err.message.clone())];
+                problems.extend(
+                    err.related
+                        .iter()
+                        .map(|related| (related.span, related.message.clone())),
+                );
+                problems
+                    .into_iter()
+                    .map(|(span, message)| {
+                        let Span { start, end } = span.expect("a problem without a span");
+                        (source[start..end].to_string(), message)
+                    })
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn names_that_resolve() {
+    let source = "\
+def fact(n) if n < 2 then 1 else n * fact(n - 1)
+def apply(f: fn(u64) -> u64, x) f(x)
+let k = 3 in apply(|x| x * k, len([1, 2]))
+apply(&fact, 5)
+{ let a = 1 in a; alloc(4) }";
+    for problems in problems(source, &[("fact", 1), ("apply", 2)]) {
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+}
+
+#[test]
+fn every_problem_in_an_item_is_reported() {
+    let source = "\
+def f(x) g(x, 1) + h(x) + y
+let a = 1 in [a, b][len(a, a)]
+|x| x + &nope
+def shadow(len) len(1, 2)";
+    let expected = [
+        vec![
+            ("g(x, 1)", "`g` takes 1 argument but was given 2"),
+            ("h(x)", "could not find function `h`"),
+            ("y", "could not find variable `y`"),
+        ],
+        vec![
+            ("b", "could not find variable `b`"),
+            ("len(a, a)", "`len` takes 1 argument but was given 2"),
+        ],
+        vec![("&nope", "could not find function `nope`")],
+        // a variable holding a function can be called with anything, it's
+        // up to its type to say what it takes
+        vec![],
+    ];
+    let actual = problems(source, &[("g", 1)]);
+    for (actual, expected) in actual.iter().zip(&expected) {
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(text, message)| (text.to_string(), message.to_string()))
+            .collect();
+        assert_eq!(actual, &expected);
+    }
+    assert_eq!(actual.len(), expected.len());
+}